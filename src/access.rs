@@ -0,0 +1,252 @@
+//! logging each request that was served
+
+use crate::server::Outcome;
+use std::{
+    fs::File,
+    io::Write,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::{Mutex, OnceLock, PoisonError},
+    time::{Duration, Instant},
+};
+
+/// the access log, set once at startup
+static LOG: OnceLock<Log> = OnceLock::new();
+
+/// how often to check whether the log file has been rotated away
+const ROTATE_CHECK: Duration = Duration::from_secs(1);
+
+/// where to send access log entries
+#[derive(Debug)]
+pub enum Destination {
+    Stdout,
+    /// structured entries sent to the systemd journal
+    #[cfg(feature = "journald")]
+    Journald,
+    /// a file that is appended to, and reopened when it gets rotated
+    File(PathBuf),
+}
+
+impl std::fmt::Display for Destination {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Stdout => f.write_str("-"),
+            #[cfg(feature = "journald")]
+            Self::Journald => f.write_str("journald"),
+            Self::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+impl std::str::FromStr for Destination {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s {
+            "-" => Self::Stdout,
+            #[cfg(feature = "journald")]
+            "journald" => Self::Journald,
+            // rather than quietly logging to a file that was probably not meant
+            #[cfg(not(feature = "journald"))]
+            "journald" => {
+                return Err("built without the journald feature, use ./journald for a file");
+            }
+            path => Self::File(path.into()),
+        })
+    }
+}
+
+enum Log {
+    Stdout,
+    #[cfg(feature = "journald")]
+    Journald(journald::Journal),
+    File(Mutex<LogFile>),
+}
+
+/// start logging requests to a destination
+pub fn init(dest: Destination) -> std::io::Result<()> {
+    let log = match dest {
+        Destination::Stdout => Log::Stdout,
+        #[cfg(feature = "journald")]
+        Destination::Journald => Log::Journald(journald::Journal::default()),
+        Destination::File(path) => Log::File(Mutex::new(LogFile::open(path)?)),
+    };
+    _ = LOG.set(log);
+    Ok(())
+}
+
+struct LogFile {
+    path: PathBuf,
+    file: File,
+    checked: Instant,
+}
+
+impl LogFile {
+    /// open a log file, remembering where it is even if the current directory changes later,
+    /// such as with --chdir
+    fn open(path: PathBuf) -> std::io::Result<Self> {
+        let path = std::path::absolute(path)?;
+        let file = File::options().create(true).append(true).open(&path)?;
+        Ok(Self {
+            path,
+            file,
+            checked: Instant::now(),
+        })
+    }
+
+    /// whether the path no longer leads to the file we have open, such as after logrotate
+    /// renamed it
+    fn is_rotated(&self) -> bool {
+        let Ok(current) = std::fs::metadata(&self.path) else {
+            return true;
+        };
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+
+            self.file
+                .metadata()
+                .is_ok_and(|open| (open.dev(), open.ino()) != (current.dev(), current.ino()))
+        }
+        #[cfg(not(unix))]
+        {
+            _ = current;
+            false
+        }
+    }
+
+    fn write_line(&mut self, line: &str) {
+        if self.checked.elapsed() >= ROTATE_CHECK {
+            self.checked = Instant::now();
+            if self.is_rotated() {
+                match Self::open(self.path.clone()) {
+                    Ok(new) => *self = new,
+                    Err(e) => eprintln!("could not reopen access log: {e}"),
+                }
+            }
+        }
+        _ = writeln!(self.file, "{line}");
+    }
+}
+
+/// log a request to the access log, if there is one
+pub fn log(peer: Option<SocketAddr>, outcome: &Outcome) {
+    let Some(dest) = LOG.get() else {
+        return;
+    };
+    let peer = peer.map_or_else(|| "-".to_string(), |p| p.ip().to_string());
+    let url = outcome.url.as_deref().unwrap_or("-");
+    let line = format!("{peer} {} {} {url}", outcome.status, outcome.bytes);
+
+    match dest {
+        Log::Stdout => _ = writeln!(std::io::stdout().lock(), "{line}"),
+        Log::File(file) => file
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .write_line(&line),
+        #[cfg(feature = "journald")]
+        Log::Journald(journal) => journal.send(&[
+            ("MESSAGE", &line),
+            ("PRIORITY", "6"),
+            ("SYSLOG_IDENTIFIER", "redgem"),
+            ("PEER_ADDR", &peer),
+            ("URL", url),
+            ("STATUS", &outcome.status.to_string()),
+            ("BYTES", &outcome.bytes.to_string()),
+        ]),
+    }
+}
+
+/// systemd's native journal protocol, see `systemd.journal-fields(7)` and
+/// <https://systemd.io/JOURNAL_NATIVE_PROTOCOL/>
+#[cfg(feature = "journald")]
+mod journald {
+    use std::os::unix::net::UnixDatagram;
+
+    const SOCKET: &str = "/run/systemd/journal/socket";
+
+    #[derive(Debug)]
+    pub struct Journal(Option<UnixDatagram>);
+
+    impl Default for Journal {
+        fn default() -> Self {
+            Self(UnixDatagram::unbound().ok())
+        }
+    }
+
+    impl Journal {
+        /// send an entry made of fields, dropping it if the journal is not there
+        pub fn send(&self, fields: &[(&str, &str)]) {
+            let Some(sock) = &self.0 else {
+                return;
+            };
+            _ = sock.send_to(&encode(fields), SOCKET);
+        }
+    }
+
+    fn encode(fields: &[(&str, &str)]) -> Vec<u8> {
+        let mut entry = Vec::new();
+        for (name, value) in fields {
+            entry.extend_from_slice(name.as_bytes());
+            // values with newlines need their length spelled out instead
+            if value.contains('\n') {
+                entry.push(b'\n');
+                entry.extend_from_slice(&(value.len() as u64).to_le_bytes());
+            } else {
+                entry.push(b'=');
+            }
+            entry.extend_from_slice(value.as_bytes());
+            entry.push(b'\n');
+        }
+        entry
+    }
+
+    #[cfg(test)]
+    mod tests {
+        #[test]
+        fn encode() {
+            assert_eq!(
+                super::encode(&[("URL", "gemini://localhost/"), ("MESSAGE", "a\nb")]),
+                b"URL=gemini://localhost/\nMESSAGE\n\x03\0\0\0\0\0\0\0a\nb\n"
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::{Destination, LogFile, ROTATE_CHECK};
+    use std::time::Instant;
+
+    #[test]
+    fn destination() {
+        assert!(matches!("-".parse(), Ok(Destination::Stdout)));
+        assert!(matches!("./journald".parse(), Ok(Destination::File(_))));
+        #[cfg(feature = "journald")]
+        assert!(matches!("journald".parse(), Ok(Destination::Journald)));
+        #[cfg(not(feature = "journald"))]
+        assert!("journald".parse::<Destination>().is_err());
+    }
+
+    #[test]
+    fn rotate_after_chdir() {
+        let dir = std::env::temp_dir().join(format!("redgem-access-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let start = std::env::current_dir().unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        let log = LogFile::open("access.log".into());
+        std::env::set_current_dir(&start).unwrap();
+        let mut log = log.unwrap();
+
+        log.write_line("before");
+        std::fs::rename(dir.join("access.log"), dir.join("access.log.1")).unwrap();
+        log.checked = Instant::now().checked_sub(ROTATE_CHECK).unwrap();
+        log.write_line("after");
+
+        let read = |name| std::fs::read_to_string(dir.join(name)).unwrap();
+        assert_eq!(read("access.log.1"), "before\n");
+        assert_eq!(read("access.log"), "after\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,197 @@
+//! temporarily refusing connections from peers that keep failing handshakes or sending garbage
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+/// how many peers to remember strikes for before forgetting the ones that have calmed down
+const MAX_TRACKED: usize = 4096;
+
+#[derive(Debug, foxerror::FoxError)]
+pub enum NetworkError {
+    /// not an ip address
+    BadAddr,
+    /// prefix length is too long for the address
+    BadPrefix,
+}
+
+/// an address, or a range of them in cidr notation
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Network {
+    fn contains(&self, ip: IpAddr) -> bool {
+        let (net, ip, bits) = match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => (net.to_bits().into(), ip.to_bits().into(), 32),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => (net.to_bits(), ip.to_bits(), 128),
+            _ => return false,
+        };
+        let mask = u128::MAX
+            .checked_shl(bits - u32::from(self.prefix))
+            .unwrap_or(0);
+        net & mask == ip & mask
+    }
+}
+
+impl std::fmt::Display for Network {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix)
+    }
+}
+
+impl std::str::FromStr for Network {
+    type Err = NetworkError;
+
+    /// parse an address, optionally followed by `/PREFIX`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = s.split_once('/').map_or((s, None), |(a, p)| (a, Some(p)));
+        let addr: IpAddr = addr.parse().map_err(|_| NetworkError::BadAddr)?;
+        let addr = addr.to_canonical();
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse().map_err(|_| NetworkError::BadPrefix)?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(NetworkError::BadPrefix);
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+#[derive(Debug)]
+struct Peer {
+    strikes: u32,
+    last: Instant,
+    banned_until: Option<Instant>,
+}
+
+/// strikes against peers, and which of them are banned
+#[derive(Debug)]
+pub struct Bans {
+    /// strikes before a peer gets banned
+    after: u32,
+    /// how long bans last, and how long strikes are remembered for
+    duration: Duration,
+    /// peers that are never banned
+    allow: Vec<Network>,
+    peers: Mutex<HashMap<IpAddr, Peer>>,
+}
+
+impl Bans {
+    pub fn new(after: u32, duration: Duration, allow: Vec<Network>) -> Self {
+        Self {
+            after,
+            duration,
+            allow,
+            peers: Mutex::default(),
+        }
+    }
+
+    /// whether connections from an address should be refused right now
+    pub fn is_banned(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        let peers = self.peers.lock().unwrap_or_else(PoisonError::into_inner);
+        peers
+            .get(&ip)
+            .and_then(|peer| peer.banned_until)
+            .is_some_and(|until| Instant::now() < until)
+    }
+
+    /// count a failed handshake or malformed request against an address, returning how long it
+    /// got banned for if this was one too many
+    pub fn strike(&self, ip: IpAddr) -> Option<Duration> {
+        let ip = ip.to_canonical();
+        if self.allow.iter().any(|net| net.contains(ip)) {
+            return None;
+        }
+
+        let now = Instant::now();
+        let mut peers = self.peers.lock().unwrap_or_else(PoisonError::into_inner);
+        if peers.len() >= MAX_TRACKED && !peers.contains_key(&ip) {
+            peers.retain(|_, peer| {
+                now.duration_since(peer.last) < self.duration
+                    || peer.banned_until.is_some_and(|until| now < until)
+            });
+        }
+
+        let peer = peers.entry(ip).or_insert(Peer {
+            strikes: 0,
+            last: now,
+            banned_until: None,
+        });
+        if now.duration_since(peer.last) >= self.duration {
+            peer.strikes = 0;
+        }
+        peer.strikes += 1;
+        peer.last = now;
+        let banned = peer.strikes >= self.after;
+        if banned {
+            peer.strikes = 0;
+            peer.banned_until = Some(now + self.duration);
+        }
+        drop(peers);
+        banned.then_some(self.duration)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::{Bans, Network};
+    use std::time::Duration;
+
+    #[test]
+    fn network() {
+        let net: Network = "192.0.2.0/24".parse().unwrap();
+        assert!(net.contains("192.0.2.99".parse().unwrap()));
+        assert!(!net.contains("192.0.3.1".parse().unwrap()));
+        assert!(!net.contains("2001:db8::1".parse().unwrap()));
+
+        let net: Network = "2001:db8::/32".parse().unwrap();
+        assert!(net.contains("2001:db8:1::1".parse().unwrap()));
+        assert!(!net.contains("2001:db9::1".parse().unwrap()));
+
+        let net: Network = "::ffff:192.0.2.1".parse().unwrap();
+        assert!(net.contains("192.0.2.1".parse().unwrap()));
+        assert!(!net.contains("192.0.2.2".parse().unwrap()));
+
+        assert!(
+            "0.0.0.0/0"
+                .parse::<Network>()
+                .unwrap()
+                .contains("1.2.3.4".parse().unwrap())
+        );
+        assert!("192.0.2.0/33".parse::<Network>().is_err());
+        assert!("example.com".parse::<Network>().is_err());
+    }
+
+    #[test]
+    fn strikes() {
+        let bans = Bans::new(
+            3,
+            Duration::from_mins(1),
+            vec!["192.0.2.0/24".parse().unwrap()],
+        );
+        let bad = "198.51.100.7".parse().unwrap();
+        assert_eq!(bans.strike(bad), None);
+        assert_eq!(bans.strike(bad), None);
+        assert!(!bans.is_banned(bad));
+        assert_eq!(bans.strike(bad), Some(Duration::from_mins(1)));
+        assert!(bans.is_banned(bad));
+        assert!(bans.is_banned("::ffff:198.51.100.7".parse().unwrap()));
+        assert!(!bans.is_banned("198.51.100.8".parse().unwrap()));
+
+        let allowed = "192.0.2.1".parse().unwrap();
+        for _ in 0..5 {
+            assert_eq!(bans.strike(allowed), None);
+        }
+        assert!(!bans.is_banned(allowed));
+    }
+}
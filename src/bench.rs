@@ -0,0 +1,191 @@
+//! load testing a server, for finding out how many clients a host can keep up with
+
+use crate::fetch;
+use argh::{ArgsInfo, FromArgs};
+use fluent_uri::Iri;
+use std::{
+    collections::BTreeMap,
+    path::PathBuf,
+    process::ExitCode,
+    sync::{
+        Arc,
+        atomic::{AtomicUsize, Ordering},
+    },
+    time::{Duration, Instant},
+};
+use tokio::{io::copy, task::JoinSet, time::timeout};
+use tokio_rustls::TlsConnector;
+
+/// make many requests to a url at once, and report how long they took and which failed
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "bench")]
+pub struct Opt {
+    /// requests to have in flight at once
+    #[argh(option, short = 'c', default = "10")]
+    concurrency: usize,
+    /// requests to make in total
+    #[argh(option, short = 'n', default = "1000")]
+    requests: usize,
+    /// seconds to wait for each response
+    #[argh(option, default = "10")]
+    timeout: u64,
+    /// file of certificate fingerprints to trust hosts on first use with, refusing them if their
+    /// certificate changes later
+    #[argh(option)]
+    known_hosts: Option<PathBuf>,
+    /// url to request
+    #[argh(positional)]
+    url: String,
+}
+
+/// what happened to the requests one connection made
+#[derive(Debug, Default)]
+struct Results {
+    /// how long each response took to arrive in full
+    latencies: Vec<Duration>,
+    /// responses for each status
+    statuses: BTreeMap<String, usize>,
+    /// requests that did not get a response, for each reason
+    errors: BTreeMap<String, usize>,
+}
+
+impl Results {
+    fn merge(&mut self, other: Self) {
+        self.latencies.extend(other.latencies);
+        for (status, count) in other.statuses {
+            *self.statuses.entry(status).or_default() += count;
+        }
+        for (error, count) in other.errors {
+            *self.errors.entry(error).or_default() += count;
+        }
+    }
+}
+
+/// the latency that a percentage of requests were at least as fast as, by nearest rank
+fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+    let rank = (sorted.len() * percent).div_ceil(100).max(1);
+    sorted.get(rank - 1).copied().unwrap_or_default()
+}
+
+/// keep making requests until all of them have been started
+async fn worker(
+    connector: TlsConnector,
+    url: Arc<str>,
+    remaining: Arc<AtomicUsize>,
+    limit: Duration,
+) -> Results {
+    let mut results = Results::default();
+    let Ok(iri) = Iri::parse(&*url) else {
+        return results;
+    };
+    let url = iri.to_uri();
+    while remaining
+        .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+        .is_ok()
+    {
+        let start = Instant::now();
+        let response = async {
+            let (header, mut body) = fetch::request(&connector, &url).await?;
+            copy(&mut body, &mut tokio::io::sink()).await?;
+            Ok::<_, fetch::Error>(header)
+        };
+        match timeout(limit, response).await {
+            Ok(Ok(header)) => {
+                results.latencies.push(start.elapsed());
+                let status = header.get(..2).unwrap_or_default().to_string();
+                *results.statuses.entry(status).or_default() += 1;
+            }
+            Ok(Err(e)) => *results.errors.entry(e.to_string()).or_default() += 1,
+            Err(_) => *results.errors.entry("timed out".to_string()).or_default() += 1,
+        }
+    }
+    results
+}
+
+pub fn run(opt: &Opt) -> ExitCode {
+    if opt.concurrency == 0 || opt.requests == 0 {
+        eprintln!("--concurrency and --requests must be at least 1");
+        return ExitCode::from(1);
+    }
+    if Iri::parse(opt.url.as_str()).is_err() {
+        eprintln!("could not bench {}: {}", opt.url, fetch::Error::BadUrl);
+        return ExitCode::from(1);
+    }
+    let Some(connector) = fetch::connector(opt.known_hosts.as_deref()) else {
+        return ExitCode::from(1);
+    };
+    let runtime = match tokio::runtime::Runtime::new() {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("could not start tokio runtime: {e}");
+            return ExitCode::from(2);
+        }
+    };
+
+    let url: Arc<str> = opt.url.as_str().into();
+    let remaining = Arc::new(AtomicUsize::new(opt.requests));
+    let limit = Duration::from_secs(opt.timeout);
+    let start = Instant::now();
+    let results = runtime.block_on(async {
+        let mut workers = JoinSet::new();
+        for _ in 0..opt.concurrency.min(opt.requests) {
+            workers.spawn(worker(
+                connector.clone(),
+                url.clone(),
+                remaining.clone(),
+                limit,
+            ));
+        }
+        let mut results = Results::default();
+        while let Some(worker) = workers.join_next().await {
+            if let Ok(worker) = worker {
+                results.merge(worker);
+            }
+        }
+        results
+    });
+    let elapsed = start.elapsed();
+
+    let done = results.latencies.len();
+    let rate = f64::from(u32::try_from(done).unwrap_or(u32::MAX)) / elapsed.as_secs_f64();
+    println!("{done} responses in {elapsed:.2?}, {rate:.1} per second");
+    for (status, count) in &results.statuses {
+        println!("status {status}: {count}");
+    }
+    for (error, count) in &results.errors {
+        println!("failed, {error}: {count}");
+    }
+
+    let mut latencies = results.latencies;
+    latencies.sort_unstable();
+    if let (Some(min), Some(max)) = (latencies.first(), latencies.last()) {
+        println!(
+            "latency min {min:.1?}, p50 {:.1?}, p90 {:.1?}, p99 {:.1?}, max {max:.1?}",
+            percentile(&latencies, 50),
+            percentile(&latencies, 90),
+            percentile(&latencies, 99),
+        );
+    }
+
+    if results.errors.is_empty() {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::percentile;
+    use std::time::Duration;
+
+    #[test]
+    fn percentiles() {
+        let sorted: Vec<_> = (1..=10).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&sorted, 50), Duration::from_millis(5));
+        assert_eq!(percentile(&sorted, 90), Duration::from_millis(9));
+        assert_eq!(percentile(&sorted, 99), Duration::from_millis(10));
+        assert_eq!(percentile(&sorted, 0), Duration::from_millis(1));
+        assert_eq!(percentile(&[], 50), Duration::ZERO);
+    }
+}
@@ -0,0 +1,131 @@
+//! removing the socket files of unix listeners when we exit, instead of leaving it behind until
+//! the next start replaces it
+
+use std::{
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+};
+use tokio_util::sync::CancellationToken;
+
+/// the socket file we are listening on
+pub struct Socket {
+    path: PathBuf,
+    dev: u64,
+    ino: u64,
+    /// cancelled once a new process has taken over the listener, and with it the file
+    stop: CancellationToken,
+}
+
+impl Socket {
+    /// remember which file is our socket, so that one put in its place is never removed
+    pub fn new(path: &Path, stop: CancellationToken) -> std::io::Result<Self> {
+        // --chdir would otherwise change what a relative path points to
+        let path = std::path::absolute(path)?;
+        let meta = std::fs::symlink_metadata(&path)?;
+        Ok(Self {
+            path,
+            dev: meta.dev(),
+            ino: meta.ino(),
+            stop,
+        })
+    }
+
+    /// remove the socket file, if we still own it
+    pub fn remove(&self) {
+        if self.stop.is_cancelled() {
+            return;
+        }
+        if let Ok(meta) = std::fs::symlink_metadata(&self.path)
+            && (meta.dev(), meta.ino()) == (self.dev, self.ino)
+        {
+            _ = std::fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// watch for SIGTERM and SIGINT in a background thread, removing the sockets before letting the
+/// signal kill us as it normally would
+///
+/// like `upgrade::watch`, this must be called before any other threads are started, including
+/// the thread of `upgrade::watch`
+#[cfg(any(
+    feature = "daemon",
+    feature = "recvfd",
+    feature = "workers",
+    feature = "upgrade"
+))]
+pub fn watch(sockets: std::sync::Arc<Vec<Socket>>) -> std::io::Result<()> {
+    // SAFETY: empty sigset_ts are valid places for sigemptyset to initialize
+    let [mut set, mut all, mut old] = unsafe { std::mem::zeroed::<[libc::sigset_t; 3]>() };
+    // SAFETY: the sets are valid for writes
+    let ret = unsafe {
+        libc::sigemptyset(&raw mut set);
+        libc::sigaddset(&raw mut set, libc::SIGTERM);
+        libc::sigaddset(&raw mut set, libc::SIGINT);
+        libc::sigfillset(&raw mut all);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &raw const all, &raw mut old)
+    };
+    if ret != 0 {
+        return Err(std::io::Error::from_raw_os_error(ret));
+    }
+
+    // with everything blocked, so that it never takes a signal meant for another thread
+    std::thread::spawn(move || {
+        let mut sig = 0;
+        // SAFETY: set was initialized above and sig is valid for writes
+        while unsafe { libc::sigwait(&raw const set, &raw mut sig) } != 0 {}
+        for socket in sockets.iter() {
+            socket.remove();
+        }
+        // SAFETY: set is still initialized, and the signal has its default action of exiting
+        unsafe {
+            libc::pthread_sigmask(libc::SIG_UNBLOCK, &raw const set, std::ptr::null_mut());
+            libc::raise(sig);
+        }
+    });
+
+    // SAFETY: old was filled in by pthread_sigmask above
+    let ret = unsafe {
+        libc::sigaddset(&raw mut old, libc::SIGTERM);
+        libc::sigaddset(&raw mut old, libc::SIGINT);
+        libc::pthread_sigmask(libc::SIG_SETMASK, &raw const old, std::ptr::null_mut())
+    };
+    if ret != 0 {
+        return Err(std::io::Error::from_raw_os_error(ret));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::Socket;
+    use std::os::unix::net::UnixListener;
+    use tokio_util::sync::CancellationToken;
+
+    #[test]
+    fn remove() {
+        let dir = std::env::temp_dir().join(format!("redgem-cleanup-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("sock");
+
+        let _listener = UnixListener::bind(&path).unwrap();
+        let socket = Socket::new(&path, CancellationToken::new()).unwrap();
+        // something else took its place, such as another redgem
+        std::fs::remove_file(&path).unwrap();
+        let _other = UnixListener::bind(&path).unwrap();
+        socket.remove();
+        assert!(path.exists());
+
+        let socket = Socket::new(&path, CancellationToken::new()).unwrap();
+        let stop = CancellationToken::new();
+        let handed_over = Socket::new(&path, stop.clone()).unwrap();
+        stop.cancel();
+        handed_over.remove();
+        assert!(path.exists());
+        socket.remove();
+        assert!(!path.exists());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,244 @@
+//! shell completion scripts, generated from the option definitions so that they keep up with
+//! new options
+
+use argh::{ArgsInfo, CommandInfoWithArgs, FlagInfo, FlagInfoKind, Optionality};
+use std::fmt::Write;
+
+/// the names redgem is usually run as
+const NAMES: [&str; 2] = ["redgem", "redgem.zip"];
+
+#[derive(Debug, foxerror::FoxError)]
+pub enum ShellError {
+    /// expected bash, zsh, or fish
+    Unknown,
+}
+
+/// the completion script for a shell, covering the options of `T` and its subcommands
+pub fn generate<T: ArgsInfo>(shell: &str) -> Result<String, ShellError> {
+    let info = T::get_args_info();
+    match shell {
+        "bash" => Ok(bash(&info)),
+        "zsh" => Ok(zsh(&info)),
+        "fish" => Ok(fish(&info)),
+        _ => Err(ShellError::Unknown),
+    }
+}
+
+/// the first paragraph of a description, on a single line
+fn summary(description: &str) -> String {
+    let para = description.split("\n\n").next().unwrap_or_default();
+    para.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn visible(info: &CommandInfoWithArgs) -> impl Iterator<Item = &FlagInfo<'static>> {
+    info.flags.iter().filter(|flag| !flag.hidden)
+}
+
+const fn takes_value(flag: &FlagInfo) -> bool {
+    matches!(flag.kind, FlagInfoKind::Option { .. })
+}
+
+fn bash(info: &CommandInfoWithArgs) -> String {
+    let commands: Vec<_> = info.commands.iter().map(|c| c.name).collect();
+    let flag_names =
+        |info: &CommandInfoWithArgs| visible(info).map(|f| f.long).collect::<Vec<_>>().join(" ");
+    let mut values: Vec<_> = std::iter::once(info)
+        .chain(info.commands.iter().map(|c| &c.command))
+        .flat_map(visible)
+        .filter(|f| takes_value(f))
+        .map(|f| f.long)
+        .collect();
+    values.sort_unstable();
+    values.dedup();
+
+    let mut out = String::new();
+    out.push_str("_redgem() {\n");
+    out.push_str(
+        "    local cur=\"${COMP_WORDS[COMP_CWORD]}\" prev=\"${COMP_WORDS[COMP_CWORD-1]}\"\n",
+    );
+    out.push_str("    local command=\"\" word flags\n");
+    out.push_str("    for word in \"${COMP_WORDS[@]:1:COMP_CWORD-1}\"; do\n");
+    _ = writeln!(
+        out,
+        "        case \"$word\" in {}) command=\"$word\"; break ;; esac",
+        commands.join("|")
+    );
+    out.push_str("    done\n");
+    if !values.is_empty() {
+        _ = writeln!(
+            out,
+            "    case \"$prev\" in {}) COMPREPLY=($(compgen -f -- \"$cur\")); return ;; esac",
+            values.join("|")
+        );
+    }
+    out.push_str("    case \"$command\" in\n");
+    for command in &info.commands {
+        _ = writeln!(
+            out,
+            "        {}) flags=\"{}\" ;;",
+            command.name,
+            flag_names(&command.command)
+        );
+    }
+    _ = writeln!(out, "        *) flags=\"{}\" ;;", flag_names(info));
+    out.push_str("    esac\n");
+    out.push_str("    if [[ $cur == -* ]]; then\n");
+    out.push_str("        COMPREPLY=($(compgen -W \"$flags\" -- \"$cur\"))\n");
+    out.push_str("    elif [[ -z $command ]]; then\n");
+    _ = writeln!(
+        out,
+        "        COMPREPLY=($(compgen -W \"{}\" -- \"$cur\") $(compgen -f -- \"$cur\"))",
+        commands.join(" ")
+    );
+    out.push_str("    else\n");
+    out.push_str("        COMPREPLY=($(compgen -f -- \"$cur\"))\n");
+    out.push_str("    fi\n");
+    out.push_str("}\n");
+    _ = writeln!(out, "complete -F _redgem {}", NAMES.join(" "));
+    out
+}
+
+/// escape text for a description inside brackets, in a single quoted zsh string
+fn zsh_escape(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\'' => out.push_str("'\\''"),
+            '[' | ']' | ':' | '\\' => {
+                out.push('\\');
+                out.push(c);
+            }
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+fn zsh_arguments(out: &mut String, info: &CommandInfoWithArgs, rest: &str) {
+    out.push_str("            _arguments -s");
+    for flag in visible(info) {
+        let repeat = if flag.optionality == Optionality::Repeating {
+            "*"
+        } else {
+            ""
+        };
+        let desc = zsh_escape(&summary(flag.description));
+        _ = write!(out, " \\\n                '{repeat}{}[{desc}]", flag.long);
+        if let FlagInfoKind::Option { arg_name } = flag.kind {
+            _ = write!(out, ":{}:_files", zsh_escape(arg_name));
+        }
+        out.push('\'');
+    }
+    _ = writeln!(out, " \\\n                '{rest}'");
+}
+
+fn zsh(info: &CommandInfoWithArgs) -> String {
+    let commands: Vec<_> = info.commands.iter().map(|c| c.name).collect();
+
+    let mut out = String::new();
+    _ = writeln!(out, "#compdef {}\n", NAMES.join(" "));
+    out.push_str("_redgem() {\n");
+    out.push_str("    local -a commands=(\n");
+    for command in &info.commands {
+        let desc = summary(command.command.description).replace(':', "\\:");
+        _ = writeln!(
+            out,
+            "        '{}:{}'",
+            command.name,
+            desc.replace('\'', "'\\''")
+        );
+    }
+    out.push_str("    )\n");
+    _ = writeln!(out, "    case ${{words[(r)({})]}} in", commands.join("|"));
+    for command in &info.commands {
+        _ = writeln!(out, "        {})", command.name);
+        zsh_arguments(&mut out, &command.command, "*:file:_files");
+        out.push_str("            ;;\n");
+    }
+    out.push_str("        *)\n");
+    zsh_arguments(
+        &mut out,
+        info,
+        "*: :_alternative \"commands: :_describe command commands\" \"files:file:_files\"",
+    );
+    out.push_str("            ;;\n");
+    out.push_str("    esac\n");
+    out.push_str("}\n\n");
+    out.push_str("_redgem \"$@\"\n");
+    out
+}
+
+/// quote text for fish
+fn fish_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+fn fish_flags(out: &mut String, info: &CommandInfoWithArgs, condition: &str) {
+    for flag in visible(info) {
+        let long = flag.long.trim_start_matches('-');
+        _ = write!(
+            out,
+            "complete -c redgem -n {} -l {long}",
+            fish_quote(condition)
+        );
+        if takes_value(flag) {
+            out.push_str(" -r");
+        }
+        _ = writeln!(out, " -d {}", fish_quote(&summary(flag.description)));
+    }
+}
+
+fn fish(info: &CommandInfoWithArgs) -> String {
+    let commands: Vec<_> = info.commands.iter().map(|c| c.name).collect();
+
+    let mut out = String::new();
+    for name in &NAMES[1..] {
+        _ = writeln!(out, "complete -c {name} --wraps redgem");
+    }
+    for command in &info.commands {
+        _ = writeln!(
+            out,
+            "complete -c redgem -n __fish_use_subcommand -a {} -d {}",
+            command.name,
+            fish_quote(&summary(command.command.description))
+        );
+    }
+    let main = format!("not __fish_seen_subcommand_from {}", commands.join(" "));
+    fish_flags(&mut out, info, &main);
+    for command in &info.commands {
+        let condition = format!("__fish_seen_subcommand_from {}", command.name);
+        fish_flags(&mut out, &command.command, &condition);
+    }
+    out
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::{fish_quote, generate, summary, zsh_escape};
+
+    #[test]
+    fn scripts() {
+        let bash = generate::<crate::Opt>("bash").unwrap();
+        assert!(bash.contains("--bind"));
+        assert!(bash.contains("        fetch) flags=\""));
+        assert!(bash.ends_with("complete -F _redgem redgem redgem.zip\n"));
+
+        let zsh = generate::<crate::Opt>("zsh").unwrap();
+        assert!(zsh.starts_with("#compdef redgem redgem.zip\n"));
+        assert!(zsh.contains("'*--site["));
+
+        let fish = generate::<crate::Opt>("fish").unwrap();
+        assert!(fish.contains(" -l bind -r -d "));
+        assert!(fish.contains("-a pack -d "));
+
+        assert!(generate::<crate::Opt>("tcsh").is_err());
+    }
+
+    #[test]
+    fn escaping() {
+        assert_eq!(summary("one\ntwo.\n\nthree"), "one two.");
+        assert_eq!(zsh_escape("a [b]: it's"), "a \\[b\\]\\: it'\\''s");
+        assert_eq!(fish_quote("it's \\"), "'it\\'s \\\\'");
+    }
+}
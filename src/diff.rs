@@ -0,0 +1,258 @@
+//! comparing two zips, for reviewing what swapping in a new one would change
+
+use crate::server::Zip;
+use argh::{ArgsInfo, FromArgs};
+use async_zip::{error::ZipError, tokio::read::fs::ZipFileReader};
+use std::{fmt::Write, path::PathBuf, process::ExitCode};
+
+/// lines of unchanged context to show around changes
+const CONTEXT: usize = 3;
+
+/// the largest table of lines to compare against each other, past which a file is only reported
+/// as changed
+const MAX_CELLS: usize = 1 << 22;
+
+/// compare two zips, listing the files that were added, removed, or changed, with a line diff
+/// for changed text files.
+///
+/// exits with 0 if they have the same files, 1 if they differ, and 2 if one could not be read
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "diff")]
+pub struct Opt {
+    /// only list which files changed
+    #[argh(switch, short = 'q')]
+    brief: bool,
+    /// the zip being served now
+    #[argh(positional)]
+    old: PathBuf,
+    /// the zip that would replace it
+    #[argh(positional)]
+    new: PathBuf,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    Same,
+    Removed,
+    Added,
+}
+
+/// how to turn one list of lines into the other, with as few changes as possible
+fn ops(old: &[&str], new: &[&str]) -> Option<Vec<Op>> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (old_mid, new_mid) = (
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    );
+    let width = new_mid.len() + 1;
+    if (old_mid.len() + 1) * width > MAX_CELLS {
+        return None;
+    }
+
+    // the length of the longest common subsequence of every pair of suffixes
+    let mut lcs = vec![0_u32; (old_mid.len() + 1) * width];
+    for i in (0..old_mid.len()).rev() {
+        for j in (0..new_mid.len()).rev() {
+            lcs[i * width + j] = if old_mid[i] == new_mid[j] {
+                lcs[(i + 1) * width + j + 1] + 1
+            } else {
+                lcs[(i + 1) * width + j].max(lcs[i * width + j + 1])
+            };
+        }
+    }
+
+    let mut ops = vec![Op::Same; prefix];
+    let (mut i, mut j) = (0, 0);
+    while i < old_mid.len() && j < new_mid.len() {
+        if old_mid[i] == new_mid[j] {
+            ops.push(Op::Same);
+            (i, j) = (i + 1, j + 1);
+        } else if lcs[(i + 1) * width + j] >= lcs[i * width + j + 1] {
+            ops.push(Op::Removed);
+            i += 1;
+        } else {
+            ops.push(Op::Added);
+            j += 1;
+        }
+    }
+    ops.extend(std::iter::repeat_n(Op::Removed, old_mid.len() - i));
+    ops.extend(std::iter::repeat_n(Op::Added, new_mid.len() - j));
+    ops.extend(std::iter::repeat_n(Op::Same, suffix));
+    Some(ops)
+}
+
+/// the start and length of a hunk's lines, as written in its header
+fn range(start: usize, count: usize) -> String {
+    // an empty range names the line before it
+    if count == 0 {
+        format!("{start},0")
+    } else {
+        format!("{},{count}", start + 1)
+    }
+}
+
+/// a unified diff of two texts, without the file headers
+fn unified(old: &str, new: &str) -> Option<String> {
+    let (old, new): (Vec<_>, Vec<_>) = (old.lines().collect(), new.lines().collect());
+    let ops = ops(&old, &new)?;
+    let changes: Vec<_> = (0..ops.len()).filter(|&i| ops[i] != Op::Same).collect();
+
+    let mut out = String::new();
+    let (mut old_line, mut new_line, mut at) = (0, 0, 0);
+    let mut rest = changes.as_slice();
+    while let Some(&first) = rest.first() {
+        // hunks whose context would touch are merged into one
+        let mut last = first;
+        while let Some(&next) = rest.get(1)
+            && next - last <= 2 * CONTEXT
+        {
+            last = next;
+            rest = &rest[1..];
+        }
+        rest = &rest[1..];
+        let (start, end) = (
+            first.saturating_sub(CONTEXT),
+            (last + CONTEXT + 1).min(ops.len()),
+        );
+
+        for &op in &ops[at..start] {
+            old_line += usize::from(op != Op::Added);
+            new_line += usize::from(op != Op::Removed);
+        }
+        let hunk = &ops[start..end];
+        let old_count = hunk.iter().filter(|&&op| op != Op::Added).count();
+        let new_count = hunk.iter().filter(|&&op| op != Op::Removed).count();
+        _ = writeln!(
+            out,
+            "@@ -{} +{} @@",
+            range(old_line, old_count),
+            range(new_line, new_count)
+        );
+        for &op in hunk {
+            match op {
+                Op::Same => _ = writeln!(out, " {}", old[old_line]),
+                Op::Removed => _ = writeln!(out, "-{}", old[old_line]),
+                Op::Added => _ = writeln!(out, "+{}", new[new_line]),
+            }
+            old_line += usize::from(op != Op::Added);
+            new_line += usize::from(op != Op::Removed);
+        }
+        at = end;
+    }
+    Some(out)
+}
+
+/// text that a line diff makes sense for
+fn as_text(data: &[u8]) -> Option<&str> {
+    str::from_utf8(data)
+        .ok()
+        .filter(|text| !text.contains('\0'))
+}
+
+async fn compare(opt: &Opt, out: &mut String) -> Result<bool, ZipError> {
+    let old: Zip = ZipFileReader::new(&opt.old).await?.into();
+    let new: Zip = ZipFileReader::new(&opt.new).await?.into();
+    let (old_files, new_files) = (old.files(), new.files());
+    let mut differ = false;
+
+    for name in old_files.keys().filter(|n| !new_files.contains_key(*n)) {
+        _ = writeln!(out, "removed {name}");
+        differ = true;
+    }
+    for name in new_files.keys().filter(|n| !old_files.contains_key(*n)) {
+        _ = writeln!(out, "added {name}");
+        differ = true;
+    }
+    for (name, &(old_id, old_crc, old_size)) in &old_files {
+        let Some(&(new_id, new_crc, new_size)) = new_files.get(name) else {
+            continue;
+        };
+        if (old_crc, old_size) == (new_crc, new_size) {
+            continue;
+        }
+        differ = true;
+        if opt.brief {
+            _ = writeln!(out, "changed {name}");
+            continue;
+        }
+        let (old_data, new_data) = (
+            old.read_checked(old_id).await?,
+            new.read_checked(new_id).await?,
+        );
+        match (as_text(&old_data), as_text(&new_data)) {
+            (Some(old_text), Some(new_text)) => match unified(old_text, new_text) {
+                Some(diff) => {
+                    _ = write!(out, "changed {name}\n--- a/{name}\n+++ b/{name}\n{diff}");
+                }
+                None => _ = writeln!(out, "changed {name} (too large to diff)"),
+            },
+            _ => _ = writeln!(out, "changed {name} (binary)"),
+        }
+    }
+    Ok(differ)
+}
+
+pub fn run(opt: &Opt) -> ExitCode {
+    let runtime = match tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("could not start tokio runtime: {e}");
+            return ExitCode::from(2);
+        }
+    };
+
+    let mut out = String::new();
+    match runtime.block_on(compare(opt, &mut out)) {
+        Ok(differ) => {
+            print!("{out}");
+            ExitCode::from(u8::from(differ))
+        }
+        Err(e) => {
+            eprintln!(
+                "could not compare {} and {}: {e}",
+                opt.old.display(),
+                opt.new.display()
+            );
+            ExitCode::from(2)
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::unified;
+
+    #[test]
+    fn hunks() {
+        assert_eq!(unified("a\nb\n", "a\nb\n").unwrap(), "");
+        assert_eq!(
+            unified("a\nb\nc\n", "a\nB\nc\nd\n").unwrap(),
+            "@@ -1,3 +1,4 @@\n a\n-b\n+B\n c\n+d\n"
+        );
+        assert_eq!(unified("", "new\n").unwrap(), "@@ -0,0 +1,1 @@\n+new\n");
+
+        // changes far enough apart get their own hunks
+        let lines = |two: &str, nineteen: &str| {
+            let mut lines: Vec<_> = (1..=20).map(|n| n.to_string()).collect();
+            lines[1] = two.to_string();
+            lines[18] = nineteen.to_string();
+            lines.join("\n")
+        };
+        let (old, new) = (lines("2", "19"), lines("two", "nineteen"));
+        assert_eq!(
+            unified(&old, &new).unwrap(),
+            "@@ -1,5 +1,5 @@\n 1\n-2\n+two\n 3\n 4\n 5\n\
+             @@ -16,5 +16,5 @@\n 16\n 17\n 18\n-19\n+nineteen\n 20\n"
+        );
+    }
+}
@@ -0,0 +1,251 @@
+//! a tiny gemini client, for checking on a capsule without installing anything else
+
+use crate::{
+    server::{auth, known_hosts::KnownHosts},
+    tls::{AnyServerCert, TofuServerCert},
+};
+use argh::{ArgsInfo, FromArgs};
+use fluent_uri::{Iri, Uri, UriRef, component::Scheme};
+use std::{
+    net::IpAddr,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, copy},
+    net::TcpStream,
+    time::timeout,
+};
+use tokio_rustls::{
+    TlsConnector,
+    client::TlsStream,
+    rustls::{ClientConfig, client::danger::ServerCertVerifier, pki_types::ServerName},
+};
+
+/// how many redirects to follow before giving up
+const MAX_REDIRECTS: usize = 5;
+
+/// fetch a gemini url, printing the response header to stderr and the body to stdout.
+///
+/// without --known-hosts any server certificate is accepted, its fingerprint is printed so it
+/// can be checked by hand
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "fetch")]
+pub struct Opt {
+    /// follow redirects
+    #[argh(switch, short = 'L')]
+    follow: bool,
+    /// file of certificate fingerprints to trust hosts on first use with, refusing them if their
+    /// certificate changes later
+    #[argh(option)]
+    known_hosts: Option<PathBuf>,
+    /// url to fetch
+    #[argh(positional)]
+    url: String,
+}
+
+/// check that a running server responds successfully, for container health checks and
+/// monitoring scripts
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "healthcheck")]
+pub struct HealthcheckOpt {
+    /// path to request
+    #[argh(option, default = "\"/\".to_string()")]
+    path: String,
+    /// seconds to wait for a response
+    #[argh(option, default = "10")]
+    timeout: u64,
+    /// file of certificate fingerprints to trust hosts on first use with, refusing them if their
+    /// certificate changes later
+    #[argh(option)]
+    known_hosts: Option<PathBuf>,
+    /// host and port of the server
+    #[argh(positional, default = "\"localhost:1965\".to_string()")]
+    addr: String,
+}
+
+#[derive(Debug, foxerror::FoxError)]
+pub enum Error {
+    #[err(from)]
+    Io(std::io::Error),
+    /// not a gemini url
+    BadUrl,
+    /// malformed response header
+    BadHeader,
+    /// too many redirects
+    TooManyRedirects,
+    /// timed out
+    Timeout,
+}
+
+fn runtime() -> Option<tokio::runtime::Runtime> {
+    match tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => Some(runtime),
+        Err(e) => {
+            eprintln!("could not start tokio runtime: {e}");
+            None
+        }
+    }
+}
+
+pub fn run(opt: &Opt) -> ExitCode {
+    let Some(runtime) = runtime() else {
+        return ExitCode::from(2);
+    };
+    let Some(connector) = connector(opt.known_hosts.as_deref()) else {
+        return ExitCode::from(1);
+    };
+
+    let mut stdout = tokio::io::stdout();
+    match runtime.block_on(fetch(&connector, &opt.url, opt.follow, &mut stdout)) {
+        Ok(header) if header.starts_with('2') => ExitCode::SUCCESS,
+        Ok(_) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("could not fetch {}: {e}", opt.url);
+            ExitCode::from(2)
+        }
+    }
+}
+
+pub fn healthcheck(opt: &HealthcheckOpt) -> ExitCode {
+    let Some(runtime) = runtime() else {
+        return ExitCode::FAILURE;
+    };
+    let Some(connector) = connector(opt.known_hosts.as_deref()) else {
+        return ExitCode::FAILURE;
+    };
+
+    let url = format!("gemini://{}{}", opt.addr, opt.path);
+    let check = async {
+        let mut sink = tokio::io::sink();
+        timeout(
+            Duration::from_secs(opt.timeout),
+            fetch(&connector, &url, false, &mut sink),
+        )
+        .await
+        .unwrap_or(Err(Error::Timeout))
+    };
+    match runtime.block_on(check) {
+        Ok(header) if header.starts_with('2') => ExitCode::SUCCESS,
+        Ok(_) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("could not fetch {url}: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// a connector that trusts hosts on first use with a known hosts file, or accepts any server
+/// certificate without one
+pub fn connector(known_hosts: Option<&Path>) -> Option<TlsConnector> {
+    let builder = ClientConfig::builder();
+    let provider = builder.crypto_provider().clone();
+    let verifier: Arc<dyn ServerCertVerifier> = match known_hosts {
+        Some(path) => match KnownHosts::load(Some(path.to_path_buf()), false) {
+            Ok(known) => Arc::new(TofuServerCert::new(provider, Arc::new(known))),
+            Err(e) => {
+                eprintln!("could not use known hosts {}: {e}", path.display());
+                return None;
+            }
+        },
+        None => Arc::new(AnyServerCert::new(provider)),
+    };
+    let config = builder
+        .dangerous()
+        .with_custom_certificate_verifier(verifier)
+        .with_no_client_auth();
+    Some(TlsConnector::from(Arc::new(config)))
+}
+
+/// fetch a url and copy the body into `out`, returning the final response header
+pub async fn fetch<W>(
+    connector: &TlsConnector,
+    url: &str,
+    follow: bool,
+    out: &mut W,
+) -> Result<String, Error>
+where
+    W: AsyncWrite + Unpin,
+{
+    let mut url = Iri::parse(url).map_err(|_| Error::BadUrl)?.to_uri();
+    for _ in 0..=MAX_REDIRECTS {
+        let (header, mut body) = request(connector, &url).await?;
+        if let Some(cert) = body
+            .get_ref()
+            .get_ref()
+            .1
+            .peer_certificates()
+            .and_then(<[_]>::first)
+        {
+            eprintln!("certificate fingerprint: {}", auth::fingerprint(cert));
+        }
+        eprintln!("{header}");
+
+        if follow && header.starts_with('3') {
+            let target = header.get(3..).ok_or(Error::BadHeader)?;
+            url = UriRef::parse(target)
+                .map_err(|_| Error::BadHeader)?
+                .resolve_against(&url)
+                .map_err(|_| Error::BadHeader)?;
+            continue;
+        }
+
+        copy(&mut body, out).await?;
+        return Ok(header);
+    }
+
+    Err(Error::TooManyRedirects)
+}
+
+/// make a single request, returning the response header and the stream positioned at the body
+pub async fn request(
+    connector: &TlsConnector,
+    url: &Uri<String>,
+) -> Result<(String, BufReader<TlsStream<TcpStream>>), Error> {
+    if url.scheme() != const { Scheme::new_or_panic("gemini") } {
+        return Err(Error::BadUrl);
+    }
+    let authority = url.authority().ok_or(Error::BadUrl)?;
+    let port = authority
+        .port_to_u16()
+        .map_err(|_| Error::BadUrl)?
+        .unwrap_or(1965);
+    let host = authority.host();
+    let host = host
+        .strip_prefix('[')
+        .and_then(|h| h.strip_suffix(']'))
+        .unwrap_or(host);
+    let name = match host.parse::<IpAddr>() {
+        Ok(ip) => ServerName::from(ip),
+        Err(_) => ServerName::try_from(host.to_string()).map_err(|_| Error::BadUrl)?,
+    };
+
+    let sock = TcpStream::connect((host, port)).await?;
+    let mut stream = connector.connect(name, sock).await?;
+    stream.write_all(url.as_str().as_bytes()).await?;
+    stream.write_all(b"\r\n").await?;
+
+    let mut stream = BufReader::new(stream);
+    let mut header = Vec::new();
+    // two digit status, a space, up to 1024 bytes of meta, and the line ending
+    (&mut stream)
+        .take(1029)
+        .read_until(b'\n', &mut header)
+        .await?;
+    let header = header.strip_suffix(b"\r\n").ok_or(Error::BadHeader)?;
+    let header = String::from_utf8(header.to_vec()).map_err(|_| Error::BadHeader)?;
+    if !header
+        .as_bytes()
+        .get(..2)
+        .is_some_and(|status| status.iter().all(u8::is_ascii_digit))
+    {
+        return Err(Error::BadHeader);
+    }
+
+    Ok((header, stream))
+}
@@ -0,0 +1,86 @@
+//! capping how many connections a single peer may have open at once, so that one client cannot
+//! take up every connection with long downloads
+
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::{Arc, Mutex, PoisonError},
+};
+
+/// open connections for each peer that has any
+#[derive(Debug)]
+pub struct Conns {
+    /// connections a peer may have open at once
+    max: usize,
+    open: Mutex<HashMap<IpAddr, usize>>,
+}
+
+/// a connection that is counted against its peer until dropped
+#[derive(Debug)]
+pub struct Open {
+    conns: Arc<Conns>,
+    ip: IpAddr,
+}
+
+impl Conns {
+    pub fn new(max: usize) -> Self {
+        Self {
+            max,
+            open: Mutex::default(),
+        }
+    }
+
+    /// count a new connection from an address, unless it already has as many open as it may
+    pub fn open(self: &Arc<Self>, ip: IpAddr) -> Option<Open> {
+        let ip = ip.to_canonical();
+        let mut open = self.open.lock().unwrap_or_else(PoisonError::into_inner);
+        let count = open.entry(ip).or_default();
+        if *count >= self.max {
+            return None;
+        }
+        *count += 1;
+        drop(open);
+        Some(Open {
+            conns: self.clone(),
+            ip,
+        })
+    }
+}
+
+impl Drop for Open {
+    fn drop(&mut self) {
+        let mut open = self
+            .conns
+            .open
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(count) = open.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                open.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::Conns;
+    use std::sync::Arc;
+
+    #[test]
+    fn cap() {
+        let conns = Arc::new(Conns::new(2));
+        let ip = "198.51.100.7".parse().unwrap();
+        let first = conns.open(ip).unwrap();
+        let second = conns.open("::ffff:198.51.100.7".parse().unwrap()).unwrap();
+        assert!(conns.open(ip).is_none());
+        assert!(conns.open("198.51.100.8".parse().unwrap()).is_some());
+
+        drop(first);
+        let third = conns.open(ip).unwrap();
+        drop((second, third));
+        assert!(conns.open.lock().unwrap().is_empty());
+    }
+}
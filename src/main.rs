@@ -1,18 +1,26 @@
 #![deny(clippy::pedantic)]
 #![deny(clippy::nursery)]
 #![deny(clippy::unwrap_used)]
-#![cfg_attr(not(any(feature = "daemon", feature = "recvfd")), forbid(unsafe_code))]
+#![cfg_attr(
+    not(any(
+        feature = "daemon",
+        feature = "recvfd",
+        feature = "workers",
+        feature = "upgrade"
+    )),
+    forbid(unsafe_code)
+)]
 
-use argh::FromArgs;
-use async_zip::tokio::read::fs::ZipFileReader;
+use argh::{ArgsInfo, FromArgs};
+use async_zip::base::read::mem;
 use std::{
     net::{SocketAddr, TcpListener},
     path::PathBuf,
     process::ExitCode,
     sync::Arc,
-    time::Duration,
+    time::{Duration, Instant},
 };
-use tokio::time::timeout;
+use tokio::{sync::mpsc, time::timeout};
 use tokio_rustls::{
     TlsAcceptor,
     rustls::{
@@ -20,58 +28,441 @@ use tokio_rustls::{
         pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject},
     },
 };
+use tokio_util::sync::CancellationToken;
 
-#[cfg(feature = "recvfd")]
+#[cfg(unix)]
 use std::os::unix::net::UnixListener;
 
+mod access;
+mod ban;
+mod bench;
+#[cfg(unix)]
+mod cleanup;
+mod completions;
+mod diff;
+mod fetch;
+mod limit;
+mod man;
+mod metrics;
+mod mirror;
+mod pack;
+#[cfg(feature = "daemon")]
+mod pidfile;
+mod proxy;
 mod server;
+mod sign;
+mod stats;
+mod statsd;
+#[cfg(unix)]
+mod systemd;
 #[cfg(test)]
 mod tests;
+mod tls;
+#[cfg(feature = "upgrade")]
+mod upgrade;
 
 /// a gemini server served from a zip file
-#[derive(Debug, FromArgs)]
+#[derive(Debug, FromArgs, ArgsInfo)]
 #[argh(help_triggers("--help"))]
+#[allow(clippy::struct_excessive_bools, reason = "switches are bools")]
 struct Opt {
-    /// address to listen on
-    #[argh(
-        option,
-        default = "\"[::]:1965\".parse().expect(\"default bind address should be parseable\")"
-    )]
-    bind: SocketAddr,
-    /// unix socket to listen on and receive file descriptors from
+    /// address to listen on, [::]:1965 by default. with --unix or --unix-tls, tcp is only
+    /// listened on too if this is given
+    #[argh(option)]
+    bind: Option<SocketAddr>,
+    /// also serve another zip on another address, in the form of ADDR=ZIP or ADDR=ZIP=CERT.
+    ///
+    /// ADDR may be just a port, and CERT holds both the certificate and its private key. the
+    /// main certificate is used if there is none. may be repeated
+    #[argh(option)]
+    site: Vec<Site>,
+    /// expect a proxy protocol header before the tls handshake of each tcp connection
+    #[argh(switch)]
+    proxy_protocol: bool,
+    /// disable nagle's algorithm on tcp connections
+    #[argh(switch)]
+    tcp_nodelay: bool,
+    /// seconds a tcp connection may be idle before sending keepalive probes
+    #[argh(option)]
+    tcp_keepalive: Option<u64>,
+    /// comma separated tls cipher suites to allow, most preferred first, such as
+    /// `TLS13_AES_256_GCM_SHA384,TLS13_CHACHA20_POLY1305_SHA256`. all supported ones if unset
+    #[argh(option)]
+    ciphers: Option<String>,
+    /// comma separated tls key exchange groups to allow, most preferred first, such as `X25519`.
+    /// all supported ones if unset
+    #[argh(option)]
+    kx_groups: Option<String>,
+    /// maximum number of pending tcp connections
+    #[argh(option, default = "128")]
+    backlog: i32,
+    /// refuse connections from a tcp peer for a while after this many failed tls handshakes or
+    /// malformed requests
+    #[argh(option)]
+    ban_after: Option<u32>,
+    /// seconds to ban peers for, and to remember their failures for
+    #[argh(option, default = "600")]
+    ban_time: u64,
+    /// address or cidr range to never ban, may be repeated
+    #[argh(option)]
+    ban_allow: Vec<ban::Network>,
+    /// refuse new connections from a tcp peer while it already has this many open
+    #[argh(option)]
+    max_conns_per_ip: Option<usize>,
+    /// unix socket to listen on and receive file descriptors from, or @name for an abstract one
+    /// on linux. may be repeated
+    #[cfg(feature = "recvfd")]
+    #[argh(option)]
+    unix: Vec<PathBuf>,
+    /// octal permissions to give the --unix sockets, like 0660
+    #[cfg(feature = "recvfd")]
+    #[argh(option, from_str_fn(parse_mode))]
+    unix_mode: Option<u32>,
+    /// user:group to give the --unix sockets, as names or ids. either can be left out
     #[cfg(feature = "recvfd")]
+    #[argh(option, from_str_fn(parse_owner))]
+    unix_owner: Option<Owner>,
+    /// unix socket to serve gemini on directly, for a local reverse proxy to connect to. @name
+    /// is an abstract socket on linux
+    #[cfg(unix)]
     #[argh(option)]
-    unix: Option<PathBuf>,
-    /// fork into background after starting
+    unix_tls: Option<PathBuf>,
+    /// speak plaintext gemini without tls, for when a proxy in front terminates it.
+    ///
+    /// sni and client certificates are not available in this mode
+    #[argh(switch)]
+    no_tls: bool,
+    /// fork into background after starting, detached from the terminal in a new session.
+    ///
+    /// changes directory to / and sets the umask to 022, unless --chdir or --umask say otherwise
     #[cfg(feature = "daemon")]
     #[argh(switch)]
     daemon: bool,
-    /// zip file to serve files from.
+    /// file to append errors to once forked into background with --daemon, instead of
+    /// discarding them
+    #[cfg(feature = "daemon")]
+    #[argh(option)]
+    log_file: Option<PathBuf>,
+    /// directory to change to once started, so that the one redgem was started from is not kept
+    /// busy. other paths, including a --access-log file, are still relative to where it was
+    /// started
+    #[cfg(feature = "daemon")]
+    #[argh(option)]
+    chdir: Option<PathBuf>,
+    /// umask in octal for any files redgem creates, such as logs
+    #[cfg(feature = "daemon")]
+    #[argh(option, from_str_fn(parse_umask))]
+    umask: Option<libc::mode_t>,
+    /// file to write our pid to, locked while running so that a second copy refuses to start
+    #[cfg(feature = "daemon")]
+    #[argh(option)]
+    pidfile: Option<PathBuf>,
+    /// number of worker processes to fork after binding, each with their own runtime.
+    ///
+    /// workers that crash are restarted
+    #[cfg(feature = "workers")]
+    #[argh(option)]
+    workers: Option<std::num::NonZeroUsize>,
+    /// number of threads to handle connections on.
+    ///
+    /// defaults to one per cpu core, 0 handles everything on the main thread
+    #[argh(option)]
+    threads: Option<usize>,
+    /// hostname to redirect requests addressed to an ip address to
+    #[argh(option)]
+    canonical_host: Option<String>,
+    /// directory of dated gemlog posts to generate a subscribable index for, if it does not
+    /// already have one
+    #[argh(option)]
+    gemlog: Option<String>,
+    /// serve a generated /sitemap.gmi listing every file
+    #[argh(switch)]
+    sitemap: bool,
+    /// serve a generated /changes.gmi listing every file by when it was last modified, newest
+    /// first
+    #[argh(switch)]
+    changes: bool,
+    /// emoji to serve as /favicon.txt, if the zip does not have one
+    #[argh(option)]
+    favicon: Option<String>,
+    /// serve files and directories starting with a `.` instead of hiding them
+    #[argh(switch)]
+    serve_hidden: bool,
+    /// serve files whose unix mode in the zip is not world readable too, instead of treating
+    /// them as unpublished
+    #[argh(switch)]
+    ignore_permissions: bool,
+    /// which entry to serve when several in the zip would be served at the same path: first,
+    /// last, or error to refuse to load the zip
+    #[argh(option, default = "server::DuplicatePolicy::Last")]
+    duplicates: server::DuplicatePolicy,
+    /// glob pattern of paths to never serve, may be repeated.
+    ///
+    /// patterns without a / match any path component, `**` matches across directories
+    #[argh(option)]
+    deny: Vec<String>,
+    /// serve a generated /robots.txt asking archivers and indexers to stay out of --deny paths,
+    /// if the zip does not have one
+    #[argh(switch)]
+    robots: bool,
+    /// serve a generated /sha256sums.txt with the sha-256 of every file, so downloads can be
+    /// checked with sha256sum -c. it is worked out the first time it is asked for
+    #[argh(switch)]
+    checksums: bool,
+    /// refuse to serve files larger than this many bytes
+    #[argh(option)]
+    max_file_size: Option<u64>,
+    /// let clients ask for part of a file that is not text with a query like ?bytes=START-END,
+    /// so that interrupted downloads of big files can be resumed
+    #[argh(switch)]
+    byte_ranges: bool,
+    /// size in bytes of the buffer to read files and send responses through, 8192 by default.
+    /// larger buffers make fewer reads and writes for big files, at the cost of memory for each
+    /// connection
+    #[argh(option)]
+    buffer_size: Option<usize>,
+    /// seconds a client may take to finish the tls handshake. scanners can be cut off sooner,
+    /// or clients on high latency networks like tor given longer
+    #[argh(option, default = "10")]
+    handshake_timeout: u64,
+    /// seconds a client may take to send its request after connecting
+    #[argh(option, default = "30")]
+    header_timeout: u64,
+    /// seconds a response may take to send in total, or 0 for no limit
+    #[argh(option, default = "600")]
+    response_timeout: u64,
+    /// seconds a response may go without any progress before giving up on it, so that slow but
+    /// steady downloads of big files can be allowed a longer --response-timeout
+    #[argh(option)]
+    idle_timeout: Option<u64>,
+    /// look at the first bytes of files with unknown extensions to guess their type, instead of
+    /// sending them as application/octet-stream
+    #[argh(switch)]
+    sniff_types: bool,
+    /// serve gemtext files without their .gmi extension too, such as /about.gmi at /about
+    #[argh(switch)]
+    clean_urls: bool,
+    /// look at the first bytes of text files to add a charset to their type when they are in a
+    /// legacy encoding like iso-8859-1 instead of utf-8
+    #[argh(switch)]
+    detect_charset: bool,
+    /// serve files with an extension as a type, in the form of EXT=TYPE/SUBTYPE. may be
+    /// repeated
+    #[argh(option)]
+    mime: Vec<server::MimeOverride>,
+    /// gemtext file to append to every text/gemini response, such as a contact link
+    #[argh(option)]
+    footer: Option<PathBuf>,
+    /// text to send instead of the default for failures with a status code, in the form of
+    /// STATUS=TEXT. may be repeated
+    #[argh(option)]
+    error_meta: Vec<server::ErrorMeta>,
+    /// language of the capsule, to read error texts in from /.redgem/errors.LANG.toml in the zip
+    #[argh(option)]
+    lang: Option<String>,
+    /// serve a variant of a file in this language instead when there is one, such as
+    /// /page.fr.gmi for /page.gmi. may be repeated, in order of preference
+    #[argh(option)]
+    lang_pref: Vec<String>,
+    /// answer every request with a permanent redirect to the same path under this url, such as
+    /// `gemini://new.example/`, for moving a capsule to another domain
+    #[argh(option)]
+    redirect_all_to: Option<server::RedirectTarget>,
+    /// relay requests for this host, or host:port if it is not on 1965, to it and send back its
+    /// response, acting as a gemini proxy for clients that cannot reach it themselves. may be
+    /// repeated
+    #[argh(option)]
+    proxy_allow: Vec<String>,
+    /// file to remember the certificates of relayed hosts in, so that they are still trusted on
+    /// first use only after a restart
+    #[argh(option)]
+    known_hosts: Option<PathBuf>,
+    /// trust a relayed host's certificate when it changes, instead of refusing to relay to it
+    #[argh(switch)]
+    tofu_accept_new: bool,
+    /// only allow a client certificate with a sha-256 fingerprint to access a path prefix, in
+    /// the form of PREFIX=FINGERPRINT. may be repeated.
+    ///
+    /// rules are also read from /.redgem/authorized in the zip
+    #[argh(option)]
+    authorized: Vec<server::auth::Rule>,
+    /// only accept client certificates issued by a certificate authority in this file, instead of
+    /// any self-signed one. their validity period is always checked
+    #[argh(option)]
+    client_ca: Option<PathBuf>,
+    /// let expired client certificates access restricted paths, for clients that use long-lived
+    /// self-signed certificates as an identity
+    #[argh(switch)]
+    accept_expired_certs: bool,
+    /// refuse every request that does not come with a client certificate, for private capsules
+    #[argh(switch)]
+    require_client_cert: bool,
+    /// zip file to serve files from, or `-` to read it from stdin.
     ///
     /// defaults to the current binary, serving files from a zip concatenated with itself
     #[argh(option)]
     zip: Option<PathBuf>,
+    /// hex ed25519 public key that every zip must be signed with by `redgem sign`, refusing to
+    /// serve ones that are not. signed zips are read into memory, so that they cannot be changed
+    /// after being checked
+    #[argh(option)]
+    pubkey: Option<sign::PublicKey>,
+    /// reload the zip when it changes.
+    ///
+    /// replace it by renaming a new one over it, since requests that arrive while it is being
+    /// overwritten in place read whatever is there
+    #[argh(switch)]
+    watch: bool,
+    /// start accepting connections before the zip is indexed, answering them with a 41 until it
+    /// is, for huge zips that take a while. does not apply to a zip read from stdin
+    #[argh(switch)]
+    load_in_background: bool,
+    /// load everything like when serving, including the certificate and key, then print any
+    /// problems with the zip and exit without binding. for checking a new capsule before
+    /// restarting
+    #[argh(switch)]
+    check: bool,
+    /// print the settings that would be used, with defaults filled in, and exit
+    #[argh(switch)]
+    print_config: bool,
+    /// log each request to a file, `-` for stdout, or `journald` for the systemd journal with the
+    /// journald feature.
+    ///
+    /// the file is reopened when it gets renamed or removed, such as by logrotate
+    #[argh(option)]
+    access_log: Option<access::Destination>,
+    /// host:port of a statsd server to send request counts, status classes, and durations to
+    #[argh(option)]
+    statsd: Option<String>,
+    /// address to serve prometheus metrics on over http.
+    ///
+    /// with --workers, each worker counts separately and whichever accepts the scrape answers it
+    #[argh(option)]
+    metrics: Option<SocketAddr>,
     /// print version and exit
     #[expect(dead_code)]
     #[argh(switch)]
     version: bool,
-    /// path to your tls certificate
-    #[argh(positional)]
-    cert: PathBuf,
-    /// path to your tls private key.
+    /// path to your tls certificate, followed by the path to your tls private key if it is not
+    /// in the same file.
     ///
-    /// defaults to looking in the same file as your certificate
+    /// not needed with --no-tls
+    #[argh(positional, arg_name = "cert")]
+    tls_files: Vec<PathBuf>,
+    #[argh(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand)]
+enum Command {
+    Fetch(fetch::Opt),
+    Check(CheckOpt),
+    Pack(pack::Opt),
+    Zip(pack::ZipOpt),
+    List(ListOpt),
+    Healthcheck(fetch::HealthcheckOpt),
+    Man(man::Opt),
+    Bench(bench::Opt),
+    Mirror(mirror::Opt),
+    Export(ExportOpt),
+    Diff(diff::Opt),
+    Sign(sign::Opt),
+}
+
+/// look for problems in the zip, such as corrupted entries, paths that collide, and broken links.
+///
+/// uses the same options as serving, so that generated pages and hidden files are accounted for
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "check")]
+struct CheckOpt {}
+
+/// print every path that would be served, with its type, compressed and uncompressed sizes, and
+/// what it is an index for.
+///
+/// uses the same options as serving
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "list")]
+struct ListOpt {}
+
+/// extract every file in the zip into a directory, including ones that would not be served
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "export")]
+struct ExportOpt {
+    /// directory to extract into
     #[argh(positional)]
-    key: Option<PathBuf>,
+    dir: PathBuf,
 }
 
 #[cfg(feature = "daemon")]
+fn parse_umask(value: &str) -> Result<libc::mode_t, String> {
+    libc::mode_t::from_str_radix(value, 8)
+        .ok()
+        .filter(|&mask| mask <= 0o777)
+        .ok_or_else(|| "expected an octal umask like 027".to_string())
+}
+
+#[cfg(feature = "recvfd")]
+fn parse_mode(value: &str) -> Result<u32, String> {
+    u32::from_str_radix(value, 8)
+        .ok()
+        .filter(|&mode| mode <= 0o777)
+        .ok_or_else(|| "expected an octal mode like 0660".to_string())
+}
+
+/// the user and group to change a file to, leaving out the ones that should stay as they are
+#[cfg(feature = "recvfd")]
+type Owner = (Option<libc::uid_t>, Option<libc::gid_t>);
+
+#[cfg(feature = "recvfd")]
+fn parse_owner(value: &str) -> Result<Owner, String> {
+    use std::ffi::CString;
+
+    fn name(value: &str) -> Result<CString, String> {
+        CString::new(value).map_err(|_| format!("{value:?} is not a valid name"))
+    }
+
+    let (user, group) = value.split_once(':').unwrap_or((value, ""));
+    let uid = if user.is_empty() {
+        None
+    } else if let Ok(uid) = user.parse() {
+        Some(uid)
+    } else {
+        let name = name(user)?;
+        // SAFETY: name is nul terminated, and options are parsed before there are other
+        // threads that could be using getpwnam's static buffer
+        let entry = unsafe { libc::getpwnam(name.as_ptr()) };
+        if entry.is_null() {
+            return Err(format!("there is no user named {user:?}"));
+        }
+        // SAFETY: getpwnam returned an entry rather than null
+        Some(unsafe { (*entry).pw_uid })
+    };
+    let gid = if group.is_empty() {
+        None
+    } else if let Ok(gid) = group.parse() {
+        Some(gid)
+    } else {
+        let name = name(group)?;
+        // SAFETY: same as getpwnam above
+        let entry = unsafe { libc::getgrnam(name.as_ptr()) };
+        if entry.is_null() {
+            return Err(format!("there is no group named {group:?}"));
+        }
+        // SAFETY: getgrnam returned an entry rather than null
+        Some(unsafe { (*entry).gr_gid })
+    };
+    Ok((uid, gid))
+}
+
+#[cfg(any(feature = "daemon", feature = "workers"))]
 fn num_threads() -> Result<usize, std::io::Error> {
     let tasks = std::fs::read_dir("/proc/self/task")?;
     Ok(tasks.count())
 }
 
-/// fork into background
+/// fork into background, in a new session without a controlling terminal, sending errors to
+/// `log_file` if given
 ///
 /// # Safety
 /// must not be used when multiple threads exist
@@ -79,23 +470,54 @@ fn num_threads() -> Result<usize, std::io::Error> {
 /// forking also messes with quite a few little things that may break rust's safety guarantees,
 /// see `fork(2)` for an exhaustive list.
 #[cfg(feature = "daemon")]
-unsafe fn daemonize() -> std::io::Result<()> {
+unsafe fn daemonize(log_file: Option<&std::path::Path>) -> std::io::Result<()> {
     use std::{io::Error, os::fd::AsRawFd};
 
     // SAFETY: most safety concerns are alleviated by the parent exiting immediately,
     // but see above doc comment for issues not covered by that
     match unsafe { libc::fork() } {
         0 => {
+            // SAFETY: setsid only fails when we already lead a process group, which a child of
+            // fork does not
+            if unsafe { libc::setsid() } == -1 {
+                return Err(Error::last_os_error());
+            }
+            // fork again so that the session leader exits, and opening a terminal can never
+            // make it our controlling terminal
+            // SAFETY: same as the first fork
+            match unsafe { libc::fork() } {
+                0 => (),
+                1.. => std::process::exit(0),
+                -1 => return Err(Error::last_os_error()),
+                _ => unreachable!(),
+            }
+
+            // before replacing stderr, so that failing to open it can still be reported
+            let log = match log_file {
+                Some(path) => Some(
+                    std::fs::OpenOptions::new()
+                        .append(true)
+                        .create(true)
+                        .open(path)?,
+                ),
+                None => None,
+            };
             // SAFETY: opening a file should not have safety concerns
             if let nullfd @ 0.. = unsafe { libc::open(c"/dev/null".as_ptr().cast(), libc::O_RDWR) }
             {
-                eprintln!("forked into background, further errors will be eaten.");
+                match log_file {
+                    Some(path) => eprintln!(
+                        "forked into background, further errors will be written to {}.",
+                        path.display()
+                    ),
+                    None => eprintln!("forked into background, further errors will be eaten."),
+                }
 
-                macro_rules! nullify {
-                    ($($stdio:ident),*) => {$({
+                macro_rules! replace {
+                    ($($stdio:ident => $fd:expr),*) => {$({
                         let lock = std::io::$stdio().lock();
                         // SAFETY: dup2 is atomic, the borrowed fd is never in a closed state
-                        if unsafe { libc::dup2(nullfd, lock.as_raw_fd()) } != lock.as_raw_fd() {
+                        if unsafe { libc::dup2($fd, lock.as_raw_fd()) } != lock.as_raw_fd() {
                             let err = Error::last_os_error();
                             // SAFETY: we just opened it
                             _ = unsafe { libc::close(nullfd) };
@@ -104,7 +526,8 @@ unsafe fn daemonize() -> std::io::Result<()> {
                     })*};
                 }
 
-                nullify!(stdin, stdout, stderr);
+                let errfd = log.as_ref().map_or(nullfd, AsRawFd::as_raw_fd);
+                replace!(stdin => nullfd, stdout => nullfd, stderr => errfd);
 
                 // SAFETY: we just opened it
                 if unsafe { libc::close(nullfd) } != 0 {
@@ -121,6 +544,116 @@ unsafe fn daemonize() -> std::io::Result<()> {
     }
 }
 
+/// fork worker processes, restarting any that exit unsuccessfully
+///
+/// this only returns in the workers. the parent stays around to supervise them, and exits once
+/// every worker has exited successfully or it gets SIGTERM or SIGINT. since the workers share
+/// the unix sockets, it is the one to remove them when exiting
+///
+/// # Safety
+/// must not be used when multiple threads exist, see `fork(2)`
+#[cfg(feature = "workers")]
+unsafe fn spawn_workers(count: usize, sockets: &[cleanup::Socket]) -> std::io::Result<()> {
+    use std::{collections::HashSet, io::Error};
+
+    fn exit(sockets: &[cleanup::Socket]) -> ! {
+        for socket in sockets {
+            socket.remove();
+        }
+        std::process::exit(0)
+    }
+
+    // SAFETY: empty sigset_ts are valid places for sigemptyset to initialize
+    let [mut set, mut old] = unsafe { std::mem::zeroed::<[libc::sigset_t; 2]>() };
+    // SAFETY: the sets are valid for writes
+    let ret = unsafe {
+        libc::sigemptyset(&raw mut set);
+        libc::sigaddset(&raw mut set, libc::SIGCHLD);
+        libc::sigaddset(&raw mut set, libc::SIGTERM);
+        libc::sigaddset(&raw mut set, libc::SIGINT);
+        libc::sigprocmask(libc::SIG_BLOCK, &raw const set, &raw mut old)
+    };
+    if ret != 0 {
+        return Err(Error::last_os_error());
+    }
+
+    let mut workers = HashSet::new();
+    let mut wanted = count;
+    loop {
+        while workers.len() < wanted {
+            // SAFETY: the caller ensures we are the only thread, and the parent does nothing
+            // besides forking and waiting
+            match unsafe { libc::fork() } {
+                0 => {
+                    // do not leave workers behind if the supervisor gets killed
+                    #[cfg(target_os = "linux")]
+                    // SAFETY: PR_SET_PDEATHSIG only takes a signal number
+                    unsafe {
+                        libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM);
+                    }
+                    // SAFETY: old was filled in by sigprocmask above
+                    unsafe {
+                        libc::sigprocmask(libc::SIG_SETMASK, &raw const old, std::ptr::null_mut());
+                    }
+                    return Ok(());
+                }
+                pid @ 1.. => _ = workers.insert(pid),
+                _ => return Err(Error::last_os_error()),
+            }
+        }
+
+        let mut sig = 0;
+        // SAFETY: set was initialized above and sig is valid for writes
+        if unsafe { libc::sigwait(&raw const set, &raw mut sig) } != 0 {
+            continue;
+        }
+        if sig != libc::SIGCHLD {
+            for socket in sockets {
+                socket.remove();
+            }
+            // SAFETY: old is still initialized, and the signal has its default action of exiting
+            // unless it was changed before we started
+            unsafe {
+                libc::sigprocmask(libc::SIG_SETMASK, &raw const old, std::ptr::null_mut());
+                libc::raise(sig);
+            }
+            std::process::exit(0);
+        }
+
+        // one SIGCHLD can stand for several workers exiting
+        loop {
+            let mut status = 0;
+            // SAFETY: status is valid for writes
+            let pid = unsafe { libc::waitpid(-1, &raw mut status, libc::WNOHANG) };
+            if pid == 0 {
+                break;
+            }
+            if pid == -1 {
+                let err = Error::last_os_error();
+                match err.raw_os_error() {
+                    Some(libc::EINTR) => continue,
+                    Some(libc::ECHILD) => exit(sockets),
+                    _ => return Err(err),
+                }
+            }
+            if !workers.remove(&pid) {
+                continue;
+            }
+
+            if libc::WIFEXITED(status) && libc::WEXITSTATUS(status) == 0 {
+                wanted -= 1;
+                if wanted == 0 {
+                    exit(sockets);
+                }
+            } else {
+                eprintln!("worker {pid} died, restarting it");
+                // avoid spinning if workers die immediately, such as from a persistent error
+                std::thread::sleep(Duration::from_secs(1));
+            }
+        }
+    }
+}
+
 /// find the current executable
 ///
 /// this differs from [`std::env::current_exe`] in that symlinks are returned instead of the target
@@ -159,42 +692,221 @@ fn path_self() -> Option<PathBuf> {
     None
 }
 
+/// every path that is opened again after --chdir, and so has to be resolved before it
+#[cfg(feature = "daemon")]
+fn reopened_paths(opt: &mut Opt) -> impl Iterator<Item = &mut PathBuf> {
+    let zip = opt.zip.iter_mut().filter(|zip| zip.as_os_str() != "-");
+    let sites = opt.site.iter_mut().map(|site| &mut site.zip);
+    let access_log = opt.access_log.iter_mut().filter_map(|dest| {
+        if let access::Destination::File(path) = dest {
+            Some(path)
+        } else {
+            None
+        }
+    });
+    zip.chain(sites)
+        .chain(&mut opt.known_hosts)
+        .chain(access_log)
+}
+
+/// bind a tcp listener with a custom backlog, which std does not let us set
+fn bind_tcp(addr: SocketAddr, backlog: i32) -> std::io::Result<TcpListener> {
+    use socket2::{Domain, Socket, Type};
+
+    let sock = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    // std does this too, so that restarting does not fail while old connections linger
+    #[cfg(unix)]
+    sock.set_reuse_address(true)?;
+    sock.bind(&addr.into())?;
+    sock.listen(backlog)?;
+    Ok(sock.into())
+}
+
+/// the name of an abstract unix socket, written as `@name`, which has no file to clean up
+#[cfg(unix)]
+fn abstract_name(path: &std::path::Path) -> Option<&[u8]> {
+    #[cfg(target_os = "linux")]
+    return std::os::unix::ffi::OsStrExt::as_bytes(path.as_os_str()).strip_prefix(b"@");
+    #[cfg(not(target_os = "linux"))]
+    None
+}
+
+/// the unix sockets that are files to remove when exiting. abstract sockets go away by themselves
+#[cfg(unix)]
+fn socket_files(opt: &Opt) -> impl Iterator<Item = &PathBuf> {
+    #[cfg(feature = "recvfd")]
+    let paths = opt.unix.iter().chain(&opt.unix_tls);
+    #[cfg(not(feature = "recvfd"))]
+    let paths = opt.unix_tls.iter();
+    paths.filter(|path| abstract_name(path).is_none())
+}
+
+/// bind a unix socket, replacing a stale one left behind at the same path
+#[cfg(unix)]
+fn bind_unix(path: &std::path::Path) -> std::io::Result<UnixListener> {
+    use std::os::unix::fs::FileTypeExt;
+
+    #[cfg(target_os = "linux")]
+    if let Some(name) = abstract_name(path) {
+        use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+        return UnixListener::bind_addr(&SocketAddr::from_abstract_name(name)?);
+    }
+
+    // posix does not have a way to do this without being race condition-y :(
+    if let Ok(meta) = std::fs::metadata(path)
+        && meta.file_type().is_socket()
+    {
+        _ = std::fs::remove_file(path);
+    }
+
+    UnixListener::bind(path)
+}
+
+/// change who may connect to a unix socket. the owner goes first, so that the mode never
+/// applies to the group it had before
+#[cfg(feature = "recvfd")]
+fn restrict_unix(
+    path: &std::path::Path,
+    mode: Option<u32>,
+    owner: Option<Owner>,
+) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Some((uid, gid)) = owner {
+        std::os::unix::fs::chown(path, uid, gid)?;
+    }
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+/// bind the metrics listener, or take it over along with the main one
+fn bind_metrics(addr: SocketAddr) -> std::io::Result<TcpListener> {
+    #[cfg(feature = "upgrade")]
+    if let Some(fd) = upgrade::inherited_metrics() {
+        return Ok(fd.into());
+    }
+    TcpListener::bind(addr)
+}
+
+/// take over the listener for a --site from the process we are replacing, or bind a new one
+#[cfg_attr(not(feature = "upgrade"), allow(unused_variables))]
+fn bind_site(index: usize, addr: SocketAddr, backlog: i32) -> std::io::Result<TcpListener> {
+    #[cfg(feature = "upgrade")]
+    if let Some(fd) = upgrade::inherited_site(index) {
+        return Ok(fd.into());
+    }
+    bind_tcp(addr, backlog)
+}
+
+/// the tcp address to listen on, which is left out by default when there are unix sockets to
+/// listen on instead
+fn bind_addr(opt: &Opt) -> Option<SocketAddr> {
+    let default = SocketAddr::new(std::net::Ipv6Addr::UNSPECIFIED.into(), 1965);
+    #[cfg(feature = "recvfd")]
+    let unix = !opt.unix.is_empty();
+    #[cfg(not(feature = "recvfd"))]
+    let unix = false;
+    #[cfg(unix)]
+    let unix = unix || opt.unix_tls.is_some();
+    opt.bind.or_else(|| (!unix).then_some(default))
+}
+
+/// something to listen on
+#[derive(Debug, PartialEq, Eq)]
+enum Bind {
+    Tcp(SocketAddr),
+    #[cfg(feature = "recvfd")]
+    Unix(PathBuf),
+    #[cfg(unix)]
+    UnixTls(PathBuf),
+}
+
+/// everything to listen on, in the same order every time, since an upgrade hands the listeners
+/// over by index
+fn binds(opt: &Opt) -> Vec<Bind> {
+    let mut binds: Vec<_> = bind_addr(opt).map(Bind::Tcp).into_iter().collect();
+    #[cfg(feature = "recvfd")]
+    binds.extend(opt.unix.iter().cloned().map(Bind::Unix));
+    #[cfg(unix)]
+    binds.extend(opt.unix_tls.iter().cloned().map(Bind::UnixTls));
+    binds
+}
+
+/// take over the listener at an index from the process we are replacing, or bind a new one
+#[cfg(feature = "upgrade")]
+fn bind_or_inherit<T>(index: usize, bind: impl FnOnce() -> std::io::Result<T>) -> std::io::Result<T>
+where
+    T: From<std::os::fd::OwnedFd>,
+{
+    upgrade::inherited_listener(index).map_or_else(bind, |fd| Ok(fd.into()))
+}
+
+#[cfg(not(feature = "upgrade"))]
+fn bind_or_inherit<T>(
+    _index: usize,
+    bind: impl FnOnce() -> std::io::Result<T>,
+) -> std::io::Result<T> {
+    bind()
+}
+
+// kind of inelegant, but i could not think of an easier way to do this...
+// XXX: keep this up to date with the features in Cargo.toml
+const FEATURES: &[&str] = &[
+    #[cfg(feature = "bzip2")]
+    "bzip2",
+    #[cfg(feature = "deflate")]
+    "deflate",
+    #[cfg(feature = "xz")]
+    "xz",
+    #[cfg(feature = "zstd")]
+    "zstd",
+    #[cfg(feature = "tls12")]
+    "tls12",
+    #[cfg(feature = "daemon")]
+    "daemon",
+    #[cfg(feature = "recvfd")]
+    "recvfd",
+    #[cfg(feature = "workers")]
+    "workers",
+    #[cfg(feature = "upgrade")]
+    "upgrade",
+    #[cfg(feature = "journald")]
+    "journald",
+];
+
 struct VersionWrapper(Opt);
 
 impl argh::TopLevelCommand for VersionWrapper {}
 
 impl FromArgs for VersionWrapper {
     fn from_args(command_name: &[&str], args: &[&str]) -> Result<Self, argh::EarlyExit> {
+        // not a real subcommand, since argh cannot hide those from the help
+        if let ["completions", shell] = args {
+            return Err(match completions::generate::<Opt>(shell) {
+                Ok(output) => argh::EarlyExit {
+                    output,
+                    status: Ok(()),
+                },
+                Err(e) => argh::EarlyExit {
+                    output: e.to_string(),
+                    status: Err(()),
+                },
+            });
+        }
         if args
             .iter()
             .take_while(|&&s| s != "--")
             .any(|&s| s == "--version")
         {
-            // kind of inelegant, but i could not think of an easier way to do this...
-            // XXX: keep this up to date with the features in Cargo.toml
-            let features: &[&str] = &[
-                #[cfg(feature = "bzip2")]
-                "bzip2",
-                #[cfg(feature = "deflate")]
-                "deflate",
-                #[cfg(feature = "xz")]
-                "xz",
-                #[cfg(feature = "zstd")]
-                "zstd",
-                #[cfg(feature = "tls12")]
-                "tls12",
-                #[cfg(feature = "daemon")]
-                "daemon",
-                #[cfg(feature = "recvfd")]
-                "recvfd",
-            ];
             let mut output = format!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
             if let Some(info) = option_env!("REDGEM_VERSION_INFO") {
                 output.push('-');
                 output.push_str(info);
             }
             output.push_str("\nfeatures: ");
-            output.push_str(&features.join(", "));
+            output.push_str(&FEATURES.join(", "));
             return Err(argh::EarlyExit {
                 output,
                 status: Ok(()),
@@ -204,10 +916,140 @@ impl FromArgs for VersionWrapper {
     }
 }
 
+#[derive(Debug, foxerror::FoxError)]
+enum SiteError {
+    /// expected ADDR=ZIP or ADDR=ZIP=CERT
+    MissingZip,
+    /// expected an address or a port
+    BadAddr,
+}
+
+/// another zip to serve on its own address
+#[derive(Debug)]
+struct Site {
+    addr: SocketAddr,
+    zip: PathBuf,
+    cert: Option<PathBuf>,
+}
+
+impl std::str::FromStr for Site {
+    type Err = SiteError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.splitn(3, '=');
+        let addr = parts.next().unwrap_or_default();
+        let addr = match addr.parse() {
+            Ok(port) => SocketAddr::new(std::net::Ipv6Addr::UNSPECIFIED.into(), port),
+            Err(_) => addr.parse().map_err(|_| SiteError::BadAddr)?,
+        };
+        let zip = parts
+            .next()
+            .filter(|z| !z.is_empty())
+            .ok_or(SiteError::MissingZip)?;
+        Ok(Self {
+            addr,
+            zip: zip.into(),
+            cert: parts.next().map(PathBuf::from),
+        })
+    }
+}
+
+/// a site that has been loaded and bound, ready to serve
+struct Capsule {
+    srv: Arc<server::Server>,
+    acceptor: Option<TlsAcceptor>,
+    listener: TcpListener,
+    zip: PathBuf,
+}
+
+/// settings applied to each accepted tcp connection
+#[derive(Debug, Clone)]
+struct TcpOptions {
+    proxy_protocol: bool,
+    nodelay: bool,
+    keepalive: Option<Duration>,
+    bans: Option<Arc<ban::Bans>>,
+    conns: Option<Arc<limit::Conns>>,
+}
+
+impl TcpOptions {
+    fn is_banned(&self, peer: SocketAddr) -> bool {
+        self.bans
+            .as_ref()
+            .is_some_and(|bans| bans.is_banned(peer.ip()))
+    }
+
+    /// count a connection against its peer until the returned guard is dropped.
+    ///
+    /// gives `Err` if the peer already has as many open as it may, after logging it
+    fn open(&self, peer: SocketAddr) -> Result<Option<limit::Open>, ()> {
+        let Some(conns) = &self.conns else {
+            return Ok(None);
+        };
+        let open = conns.open(peer.ip()).ok_or(());
+        if open.is_err() {
+            log_rejected(Some(peer), "too_many_connections");
+        }
+        open.map(Some)
+    }
+
+    /// count misbehavior against a peer, banning it if it has done so too often
+    fn strike(&self, peer: SocketAddr) {
+        if let Some(time) = self.bans.as_ref().and_then(|bans| bans.strike(peer.ip())) {
+            eprintln!("banned {} {}", peer.ip(), time.as_secs());
+        }
+    }
+}
+
+/// log a peer we refused to serve, in the format documented in the readme so that tools like
+/// fail2ban can match on it
+fn log_rejected(peer: Option<SocketAddr>, reason: &str) {
+    match peer {
+        Some(peer) => eprintln!("rejected {} {reason}", peer.ip()),
+        None => eprintln!("rejected - {reason}"),
+    }
+}
+
 enum Listener {
     Tcp(TcpListener),
     #[cfg(feature = "recvfd")]
     Unix(UnixListener),
+    #[cfg(unix)]
+    UnixTls(UnixListener),
+}
+
+#[cfg(unix)]
+impl std::os::fd::AsRawFd for Listener {
+    fn as_raw_fd(&self) -> std::os::fd::RawFd {
+        match self {
+            Self::Tcp(listener) => listener.as_raw_fd(),
+            #[cfg(feature = "recvfd")]
+            Self::Unix(listener) => listener.as_raw_fd(),
+            Self::UnixTls(listener) => listener.as_raw_fd(),
+        }
+    }
+}
+
+/// connections being served, so that they can be finished before exiting
+#[derive(Clone)]
+struct Tasks {
+    /// cancelled once we should stop accepting connections
+    stop: CancellationToken,
+    /// held by every connection, so that the receiving end closes once they are all done
+    alive: mpsc::Sender<()>,
+}
+
+impl Tasks {
+    fn spawn<F>(&self, conn: F)
+    where
+        F: Future<Output = ()> + Send + 'static,
+    {
+        let alive = self.alive.clone();
+        tokio::spawn(async move {
+            conn.await;
+            drop(alive);
+        });
+    }
 }
 
 macro_rules! ear {
@@ -223,92 +1065,489 @@ macro_rules! ear {
     };
 }
 
+#[allow(clippy::too_many_lines)]
 fn main() -> ExitCode {
     let opt = argh::from_env::<VersionWrapper>().0;
-
-    let zip = {
-        let Some(zip_path) = opt.zip.or_else(path_self) else {
-            eprintln!("could not find path to myself. set it with the --zip option");
+    match &opt.command {
+        Some(Command::Fetch(fetch)) => return fetch::run(fetch),
+        Some(Command::Healthcheck(check)) => return fetch::healthcheck(check),
+        Some(Command::Bench(bench)) => return bench::run(bench),
+        Some(Command::Mirror(mirror)) => return mirror::run(mirror),
+        Some(Command::Diff(diff)) => return diff::run(diff),
+        Some(Command::Sign(sign)) => return sign::run(sign),
+        Some(Command::Pack(pack)) => {
+            let Some(binary) = path_self() else {
+                eprintln!("could not find path to myself");
+                return ExitCode::from(1);
+            };
+            return pack::pack(pack, &binary);
+        }
+        Some(Command::Zip(zip)) => return pack::zip(zip),
+        Some(Command::Man(_)) => {
+            print!("{}", man::page::<Opt>(FEATURES));
+            return ExitCode::SUCCESS;
+        }
+        Some(Command::Check(_) | Command::List(_) | Command::Export(_)) | None => (),
+    }
+    #[cfg(feature = "daemon")]
+    let mut opt = opt;
+    // so that a daemon does not keep the directory it was started from busy, or create files
+    // with whatever umask that shell had
+    #[cfg(feature = "daemon")]
+    if opt.daemon {
+        opt.chdir.get_or_insert_with(|| "/".into());
+        opt.umask.get_or_insert(0o022);
+    }
+    // these are opened again later, after --chdir would change what they are relative to
+    #[cfg(feature = "daemon")]
+    if opt.chdir.is_some() {
+        for path in reopened_paths(&mut opt) {
+            *path = ear!(
+                std::path::absolute(&*path),
+                "could not resolve {:?}",
+                1,
+                path
+            );
+        }
+    }
+    let Some(zip_path) = opt.zip.clone().or_else(path_self) else {
+        eprintln!("could not find path to myself. set it with the --zip option");
+        return ExitCode::from(1);
+    };
+    if opt.max_conns_per_ip == Some(0) {
+        eprintln!("--max-conns-per-ip must be at least 1");
+        return ExitCode::from(1);
+    }
+    if opt
+        .buffer_size
+        .is_some_and(|size| size < server::MIN_BUFFER_SIZE)
+    {
+        eprintln!(
+            "--buffer-size must be at least {} bytes",
+            server::MIN_BUFFER_SIZE
+        );
+        return ExitCode::from(1);
+    }
+    if let Some(lang) = opt
+        .lang_pref
+        .iter()
+        .find(|l| l.is_empty() || !l.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-'))
+    {
+        eprintln!("--lang-pref {lang:?} is not a language tag");
+        return ExitCode::from(1);
+    }
+    if let Some(host) = opt
+        .proxy_allow
+        .iter()
+        .find(|h| server::allowed_authority(h).is_none())
+    {
+        eprintln!("--proxy-allow {host:?} is not a host or host:port");
+        return ExitCode::from(1);
+    }
+    #[cfg(feature = "recvfd")]
+    if opt.unix_mode.is_some() || opt.unix_owner.is_some() {
+        if opt.unix.is_empty() {
+            eprintln!("--unix-mode and --unix-owner only apply with --unix");
             return ExitCode::from(1);
-        };
+        }
+        if opt.unix.iter().any(|path| abstract_name(path).is_some()) {
+            eprintln!("--unix-mode and --unix-owner do not apply to abstract sockets");
+            return ExitCode::from(1);
+        }
+    }
+    #[cfg(feature = "daemon")]
+    if opt.log_file.is_some() && !opt.daemon {
+        eprintln!("--log-file only applies with --daemon");
+        return ExitCode::from(1);
+    }
+    if opt
+        .favicon
+        .as_ref()
+        .is_some_and(|f| f.is_empty() || f.chars().any(|c| c.is_whitespace() || c.is_control()))
+    {
+        eprintln!("--favicon should be an emoji");
+        return ExitCode::from(1);
+    }
+    if opt.watch && zip_path.as_os_str() == "-" {
+        eprintln!("--watch needs a zip file to watch, not stdin");
+        return ExitCode::from(1);
+    }
+    let footer = match &opt.footer {
+        Some(path) => Some(ear!(
+            std::fs::read(path),
+            "could not read footer {path:?}",
+            1
+        )),
+        None => None,
+    };
+    // before the options move into the config
+    let printed = opt.print_config.then(|| describe(&opt, &zip_path));
+    let binds = binds(&opt);
+    #[cfg(unix)]
+    let socket_files: Vec<_> = socket_files(&opt).cloned().collect();
+    let config = server::Config {
+        canonical_host: opt.canonical_host,
+        gemlog: opt.gemlog,
+        sitemap: opt.sitemap,
+        changes: opt.changes,
+        favicon: opt.favicon,
+        serve_hidden: opt.serve_hidden,
+        ignore_permissions: opt.ignore_permissions,
+        duplicates: opt.duplicates,
+        deny: opt.deny,
+        robots: opt.robots,
+        checksums: opt.checksums,
+        authorized: opt.authorized,
+        max_file_size: opt.max_file_size,
+        byte_ranges: opt.byte_ranges,
+        buffer_size: opt.buffer_size,
+        handshake_timeout: Some(Duration::from_secs(opt.handshake_timeout)),
+        header_timeout: Some(Duration::from_secs(opt.header_timeout)),
+        response_timeout: Some(match opt.response_timeout {
+            0 => Duration::MAX,
+            secs => Duration::from_secs(secs),
+        }),
+        idle_timeout: opt.idle_timeout.map(Duration::from_secs),
+        error_meta: opt.error_meta,
+        lang: opt.lang,
+        lang_pref: opt.lang_pref,
+        accept_expired_certs: opt.accept_expired_certs,
+        require_client_cert: opt.require_client_cert,
+        redirect_all_to: opt.redirect_all_to,
+        sniff_types: opt.sniff_types,
+        clean_urls: opt.clean_urls,
+        detect_charset: opt.detect_charset,
+        mime: opt.mime,
+        footer: footer.map(Into::into),
+        proxy_allow: opt.proxy_allow,
+        known_hosts: opt.known_hosts,
+        tofu_accept_new: opt.tofu_accept_new,
+    };
+    if let Some(printed) = printed {
+        print!("{printed}{config}");
+        return ExitCode::SUCCESS;
+    }
+    let site_config = config.clone();
+
+    let background = opt.load_in_background
+        && !opt.check
+        && opt.command.is_none()
+        && zip_path.as_os_str() != "-";
+    let srv = if background {
+        ear!(server::Server::unloaded(config), "could not load zip", 2)
+    } else {
         let runtime = ear!(
             tokio::runtime::Runtime::new(),
             "could not start tokio runtime",
             2
         );
+        let zip: server::Zip = if zip_path.as_os_str() == "-" {
+            let mut data = vec![];
+            ear!(
+                std::io::Read::read_to_end(&mut std::io::stdin(), &mut data),
+                "could not read zip from stdin",
+                2
+            );
+            if let Some(key) = &opt.pubkey {
+                ear!(
+                    sign::verify(&data, key),
+                    "could not verify zip from stdin",
+                    2
+                );
+            }
+            ear!(
+                runtime.block_on(mem::ZipFileReader::new(data)),
+                "could not read zip from stdin",
+                2
+            )
+            .into()
+        } else {
+            ear!(
+                runtime.block_on(sign::open(&zip_path, opt.pubkey.as_ref())),
+                "could not open zip at {zip_path:?}",
+                2
+            )
+        };
+        // before loading, so that zips redgem would not serve can still be looked at
+        if let Some(Command::Export(export)) = &opt.command {
+            let (written, skipped) = ear!(
+                runtime.block_on(zip.export(&export.dir)),
+                "could not export to {:?}",
+                2,
+                export.dir
+            );
+            eprintln!("extracted {written} files to {}", export.dir.display());
+            if skipped > 0 {
+                eprintln!("left out {skipped} entries that would be outside of it");
+            }
+            return ExitCode::SUCCESS;
+        }
         ear!(
-            runtime.block_on(async { ZipFileReader::new(&zip_path).await }),
-            "could not open zip at {zip_path:?}",
+            runtime.block_on(server::Server::load(zip, config)),
+            "could not load zip",
             2
         )
     };
-    let cert = ear!(
-        ear!(
-            CertificateDer::pem_file_iter(&opt.cert),
-            "could not open certificate",
-            3
-        )
-        .collect::<Result<Vec<_>, _>>(),
-        "could not parse certificate",
-        3
-    );
-    let key = ear!(
-        PrivateKeyDer::from_pem_file(opt.key.as_ref().unwrap_or(&opt.cert)),
-        "could not open private key",
-        4
-    );
-    let config = rustls::ServerConfig::builder()
-        .with_no_client_auth()
-        .with_single_cert(cert, key)
-        .expect("creating rustls server config");
-    let acceptor = TlsAcceptor::from(Arc::new(config));
 
-    #[cfg(feature = "recvfd")]
-    let listener = if let Some(unix) = opt.unix {
-        use std::os::unix::fs::FileTypeExt;
+    match opt.command {
+        Some(Command::Check(_)) => return check(&srv, &[]),
+        Some(Command::List(_)) => {
+            for line in srv.list() {
+                println!("{line}");
+            }
+            return ExitCode::SUCCESS;
+        }
+        _ => (),
+    }
 
-        // posix does not have a way to do this without being race condition-y :(
-        if let Ok(meta) = std::fs::metadata(&unix)
-            && meta.file_type().is_socket()
-        {
-            _ = std::fs::remove_file(&unix);
+    let mut sites = vec![];
+    if !opt.site.is_empty() {
+        let runtime = ear!(
+            tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build(),
+            "could not start tokio runtime",
+            2
+        );
+        for site in &opt.site {
+            let path = &site.zip;
+            let zip = ear!(
+                runtime.block_on(sign::open(path, opt.pubkey.as_ref())),
+                "could not open zip at {path:?}",
+                2
+            );
+            let site_srv = srv.sibling(site_config.clone());
+            ear!(
+                runtime.block_on(site_srv.swap_zip(zip)),
+                "could not load zip {path:?}",
+                2
+            );
+            sites.push((site, site_srv));
         }
+    }
 
-        Listener::Unix(ear!(
-            UnixListener::bind(unix),
-            "could not bind unix socket",
-            5
-        ))
+    let (acceptor, site_acceptors) = if opt.no_tls {
+        (None, vec![])
     } else {
-        Listener::Tcp(ear!(
-            TcpListener::bind(opt.bind),
-            "could not bind tcp listener",
+        let (cert_path, key_path) = match opt.tls_files.as_slice() {
+            [cert] => (cert, cert),
+            [cert, key] => (cert, key),
+            [] => {
+                eprintln!("a certificate is required unless --no-tls is given");
+                return ExitCode::from(3);
+            }
+            [..] => {
+                eprintln!("too many positional arguments, expected a certificate and key");
+                return ExitCode::from(3);
+            }
+        };
+        let cert = ear!(
+            ear!(
+                CertificateDer::pem_file_iter(cert_path),
+                "could not open certificate {cert_path:?}",
+                3
+            )
+            .collect::<Result<Vec<_>, _>>(),
+            "could not parse certificate {cert_path:?}",
+            3
+        );
+        if cert.is_empty() {
+            eprintln!(
+                "no certificates in {}, it should be in pem format",
+                cert_path.display()
+            );
+            return ExitCode::from(3);
+        }
+        let key = ear!(
+            PrivateKeyDer::from_pem_file(key_path),
+            "could not open private key {key_path:?}",
+            4
+        );
+        let provider = ear!(
+            tls::restricted_provider(opt.ciphers.as_deref(), opt.kx_groups.as_deref()),
+            "could not restrict tls settings",
+            1
+        );
+        let builder = ear!(
+            rustls::ServerConfig::builder_with_provider(Arc::new(provider))
+                .with_safe_default_protocol_versions(),
+            "could not use the chosen cipher suites and key exchange groups",
+            3
+        );
+        // a reloaded zip may start restricting paths
+        let builder = if let Some(ca_path) = &opt.client_ca {
+            let verifier = ear!(
+                tls::ca_client_verifier(builder.crypto_provider().clone(), ca_path),
+                "could not use client ca {ca_path:?}",
+                3
+            );
+            builder.with_client_cert_verifier(verifier)
+        } else if !srv.has_authorized()
+            && !sites.iter().any(|(_, srv)| srv.has_authorized())
+            && !opt.watch
+            && !opt.require_client_cert
+        {
+            builder.with_no_client_auth()
+        } else {
+            let verifier = tls::AnyClientCert::new(builder.crypto_provider().clone());
+            builder.with_client_cert_verifier(Arc::new(verifier))
+        };
+        let mut site_acceptors = vec![];
+        for (site, _) in &sites {
+            let Some(path) = &site.cert else {
+                site_acceptors.push(None);
+                continue;
+            };
+            let cert = ear!(
+                ear!(
+                    CertificateDer::pem_file_iter(path),
+                    "could not open certificate {path:?}",
+                    3
+                )
+                .collect::<Result<Vec<_>, _>>(),
+                "could not parse certificate {path:?}",
+                3
+            );
+            let key = ear!(
+                PrivateKeyDer::from_pem_file(path),
+                "could not open private key {path:?}",
+                4
+            );
+            let config = ear!(
+                builder.clone().with_single_cert(cert, key),
+                "could not use certificate {path:?}",
+                4
+            );
+            site_acceptors.push(Some(TlsAcceptor::from(Arc::new(config))));
+        }
+        let config = ear!(
+            builder.with_single_cert(cert, key),
+            "could not use certificate {cert_path:?} with private key {key_path:?}",
+            4
+        );
+        let acceptor = TlsAcceptor::from(Arc::new(config));
+        let site_acceptors = site_acceptors
+            .into_iter()
+            .map(|site| Some(site.unwrap_or_else(|| acceptor.clone())))
+            .collect();
+        (Some(acceptor), site_acceptors)
+    };
+
+    if opt.check {
+        return check(&srv, &sites);
+    }
+
+    // before binding, which would replace the unix socket of a copy that is still running
+    #[cfg(feature = "daemon")]
+    let pidfile = match &opt.pidfile {
+        Some(path) => Some(ear!(
+            pidfile::lock(path),
+            "could not lock pidfile {path:?}",
+            1
+        )),
+        None => None,
+    };
+
+    let mut listeners = vec![];
+    for bind in binds {
+        let index = listeners.len();
+        listeners.push(match bind {
+            Bind::Tcp(addr) => Listener::Tcp(ear!(
+                bind_or_inherit(index, || bind_tcp(addr, opt.backlog)),
+                "could not bind tcp listener",
+                5
+            )),
+            #[cfg(feature = "recvfd")]
+            Bind::Unix(unix) => {
+                let listener = ear!(
+                    bind_or_inherit(index, || bind_unix(&unix)),
+                    "could not bind unix socket {unix:?}",
+                    5
+                );
+                ear!(
+                    restrict_unix(&unix, opt.unix_mode, opt.unix_owner),
+                    "could not set permissions of unix socket {unix:?}",
+                    5
+                );
+                Listener::Unix(listener)
+            }
+            #[cfg(unix)]
+            Bind::UnixTls(unix) => Listener::UnixTls(ear!(
+                bind_or_inherit(index, || bind_unix(&unix)),
+                "could not bind unix socket {unix:?}",
+                5
+            )),
+        });
+    }
+
+    let metrics = match opt.metrics {
+        Some(addr) => Some(ear!(
+            bind_metrics(addr),
+            "could not bind metrics listener",
             5
-        ))
+        )),
+        None => None,
     };
-    #[cfg(not(feature = "recvfd"))]
-    let listener = Listener::Tcp(ear!(
-        TcpListener::bind(opt.bind),
-        "could not bind tcp listener",
-        5
-    ));
 
-    match &listener {
-        Listener::Tcp(listener) => println!(
-            "listening on {}",
-            listener
-                .local_addr()
-                .expect("there should be a local addr, we just bound the listener to one")
-        ),
-        #[cfg(feature = "recvfd")]
-        Listener::Unix(listener) => println!(
-            "listening on {:?}",
+    for listener in &listeners {
+        match listener {
+            Listener::Tcp(listener) => println!(
+                "listening on {}",
+                listener
+                    .local_addr()
+                    .expect("there should be a local addr, we just bound the listener to one")
+            ),
+            #[cfg(feature = "recvfd")]
+            Listener::Unix(listener) => println!(
+                "listening on {:?}",
+                listener
+                    .local_addr()
+                    .expect("there should be a local addr, we just bound the listener to one")
+            ),
+            #[cfg(unix)]
+            Listener::UnixTls(listener) => println!(
+                "listening on {:?}",
+                listener
+                    .local_addr()
+                    .expect("there should be a local addr, we just bound the listener to one")
+            ),
+        }
+    }
+
+    let mut site_listeners = vec![];
+    for (i, (site, _)) in sites.iter().enumerate() {
+        let listener = ear!(
+            bind_site(i, site.addr, opt.backlog),
+            "could not bind tcp listener for {:?}",
+            5,
+            site.zip
+        );
+        println!(
+            "listening on {} for {}",
             listener
                 .local_addr()
-                .expect("there should be a local addr, we just bound the listener to one")
-        ),
+                .expect("there should be a local addr, we just bound the listener to one"),
+            site.zip.display()
+        );
+        site_listeners.push(listener);
     }
 
+    #[cfg(feature = "daemon")]
+    if let Some(mask) = opt.umask {
+        // SAFETY: umask always succeeds, and only affects files created after it
+        unsafe { libc::umask(mask) };
+    }
+
+    if let Some(dest) = opt.access_log {
+        ear!(access::init(dest), "could not open access log", 1);
+    }
+    if let Some(addr) = &opt.statsd {
+        ear!(statsd::init(addr), "could not set up statsd", 1);
+    }
+
+    #[cfg(unix)]
+    let watchdog = systemd::watchdog_interval();
+
     #[cfg(feature = "daemon")]
     if opt.daemon {
         if let Ok(threads) = num_threads() {
@@ -317,30 +1556,495 @@ fn main() -> ExitCode {
         ear!(
             // SAFETY: the first tokio runtime has already been dropped and the new tokio runtime has
             // not started yet, we should be the only thread
-            unsafe { daemonize() },
+            unsafe { daemonize(opt.log_file.as_deref()) },
             "failed to daemonize",
             5
         );
     }
+    #[cfg(feature = "daemon")]
+    if let Some(file) = &pidfile {
+        ear!(pidfile::write_pid(file), "could not write pidfile", 1);
+    }
+
+    let stop = CancellationToken::new();
+    #[cfg(unix)]
+    let sockets = {
+        let mut sockets = vec![];
+        for path in &socket_files {
+            sockets.push(ear!(
+                cleanup::Socket::new(path, stop.clone()),
+                "could not find unix socket {path:?}",
+                5
+            ));
+        }
+        sockets
+    };
+
+    #[cfg(feature = "workers")]
+    let forked = opt.workers.is_some();
+    #[cfg(not(feature = "workers"))]
+    let forked = false;
+    #[cfg(feature = "workers")]
+    if let Some(workers) = opt.workers {
+        if let Ok(threads) = num_threads() {
+            assert_eq!(threads, 1);
+        }
+        ear!(
+            // SAFETY: same as daemonizing, no runtime is running right now
+            unsafe { spawn_workers(workers.get(), &sockets) },
+            "failed to fork workers",
+            5
+        );
+    }
+
+    #[cfg(unix)]
+    let sockets = {
+        // workers share the sockets with each other, so one exiting must not remove them. the
+        // supervisor does instead
+        let sockets = Arc::new(if forked { vec![] } else { sockets });
+        #[cfg(any(
+            feature = "daemon",
+            feature = "recvfd",
+            feature = "workers",
+            feature = "upgrade"
+        ))]
+        if !sockets.is_empty() {
+            ear!(
+                cleanup::watch(sockets.clone()),
+                "could not watch for exit signals",
+                5
+            );
+        }
+        sockets
+    };
+    // before an upgrade of our own could hand it to another process
+    #[cfg(feature = "upgrade")]
+    let upgrading = upgrade::inherited_ready();
+    // before the runtime starts its threads, so that they block the signal too
+    #[cfg(feature = "upgrade")]
+    {
+        #[cfg(feature = "daemon")]
+        let pidfile_fd = pidfile.as_ref().map(std::os::fd::AsRawFd::as_raw_fd);
+        #[cfg(not(feature = "daemon"))]
+        let pidfile_fd = None;
+        // workers would each try to replace the whole server
+        if !forked {
+            ear!(
+                upgrade::watch(
+                    listeners
+                        .iter()
+                        .map(std::os::fd::AsRawFd::as_raw_fd)
+                        .collect(),
+                    metrics.as_ref().map(std::os::fd::AsRawFd::as_raw_fd),
+                    site_listeners
+                        .iter()
+                        .map(std::os::fd::AsRawFd::as_raw_fd)
+                        .collect(),
+                    pidfile_fd,
+                    stop.clone()
+                ),
+                "could not watch for upgrade signal",
+                5
+            );
+        }
+    }
+
+    let mut builder = match opt.threads {
+        Some(0) => tokio::runtime::Builder::new_current_thread(),
+        Some(threads) => {
+            let mut builder = tokio::runtime::Builder::new_multi_thread();
+            builder.worker_threads(threads);
+            builder
+        }
+        None => tokio::runtime::Builder::new_multi_thread(),
+    };
+    let runtime = ear!(
+        builder.enable_all().build(),
+        "could not start tokio runtime",
+        2
+    );
+    #[cfg(unix)]
+    if let Some(interval) = watchdog {
+        runtime.spawn(systemd::watchdog(interval));
+    }
+
+    let tcp = TcpOptions {
+        proxy_protocol: opt.proxy_protocol,
+        nodelay: opt.tcp_nodelay,
+        keepalive: opt.tcp_keepalive.map(Duration::from_secs),
+        bans: opt.ban_after.map(|after| {
+            Arc::new(ban::Bans::new(
+                after,
+                Duration::from_secs(opt.ban_time),
+                opt.ban_allow,
+            ))
+        }),
+        conns: opt
+            .max_conns_per_ip
+            .map(|max| Arc::new(limit::Conns::new(max))),
+    };
+
+    // everything is loaded and the listener is already queueing connections
+    #[cfg(unix)]
+    {
+        // systemd should follow us instead of the process we are replacing
+        #[cfg(feature = "upgrade")]
+        let ready = if upgrade::is_upgrading() {
+            format!("READY=1\nMAINPID={}", std::process::id())
+        } else {
+            "READY=1".to_string()
+        };
+        #[cfg(not(feature = "upgrade"))]
+        let ready = String::from("READY=1");
+        if let Err(e) = systemd::notify(&ready) {
+            eprintln!("could not notify systemd: {e}");
+        }
+    }
+    #[cfg(feature = "upgrade")]
+    if let Some(fd) = upgrading {
+        upgrade::ready(fd);
+    }
+
+    let mut site_acceptors = site_acceptors.into_iter();
+    let capsules = sites
+        .into_iter()
+        .zip(site_listeners)
+        .map(|((site, srv), listener)| Capsule {
+            srv: Arc::new(srv),
+            acceptor: site_acceptors.next().flatten(),
+            listener,
+            zip: site.zip.clone(),
+        })
+        .collect();
+
+    #[cfg(feature = "daemon")]
+    if let Some(dir) = &opt.chdir {
+        ear!(
+            std::env::set_current_dir(dir),
+            "could not change directory to {dir:?}",
+            1
+        );
+    }
+
+    let srv = Arc::new(srv);
+    if background {
+        runtime.spawn(load_zip(srv.clone(), zip_path.clone(), opt.pubkey));
+    }
+    let watch = opt.watch.then_some(zip_path);
+    let code = runtime.block_on(run(
+        srv, acceptor, listeners, capsules, tcp, stop, watch, opt.pubkey, metrics,
+    ));
+    #[cfg(unix)]
+    for socket in sockets.iter() {
+        socket.remove();
+    }
+    code
+}
+
+/// the settings that are not part of the [`server::Config`], as `name: value` lines
+fn describe(opt: &Opt, zip_path: &std::path::Path) -> String {
+    use std::fmt::Write;
+
+    fn or_none(value: Option<impl std::fmt::Display>) -> String {
+        value.map_or_else(|| "none".to_string(), |v| v.to_string())
+    }
+
+    let mut out = String::new();
+    let mut line = |name: &str, value: &dyn std::fmt::Display| {
+        _ = writeln!(out, "{name}: {value}");
+    };
+    line("features", &FEATURES.join(", "));
+    line("zip", &zip_path.display());
+    line("pubkey", &or_none(opt.pubkey));
+    line("watch", &opt.watch);
+    line("load-in-background", &opt.load_in_background);
+    line("bind", &or_none(bind_addr(opt)));
+    #[cfg(feature = "recvfd")]
+    for path in &opt.unix {
+        line("unix", &path.display());
+    }
+    #[cfg(unix)]
+    line(
+        "unix-tls",
+        &or_none(opt.unix_tls.as_ref().map(|p| p.display())),
+    );
+    #[cfg(feature = "recvfd")]
+    {
+        line(
+            "unix-mode",
+            &or_none(opt.unix_mode.map(|m| format!("{m:04o}"))),
+        );
+        let id = |id: Option<u32>| id.map(|id| id.to_string()).unwrap_or_default();
+        line(
+            "unix-owner",
+            &or_none(
+                opt.unix_owner
+                    .map(|(uid, gid)| format!("{}:{}", id(uid), id(gid))),
+            ),
+        );
+    }
+    for site in &opt.site {
+        let cert = site.cert.as_ref().map(|c| format!("={}", c.display()));
+        let site = format!(
+            "{}={}{}",
+            site.addr,
+            site.zip.display(),
+            cert.unwrap_or_default()
+        );
+        line("site", &site);
+    }
+    line("no-tls", &opt.no_tls);
+    for path in &opt.tls_files {
+        line("tls-file", &path.display());
+    }
+    line(
+        "client-ca",
+        &or_none(opt.client_ca.as_ref().map(|p| p.display())),
+    );
+    line("ciphers", &or_none(opt.ciphers.as_ref()));
+    line("kx-groups", &or_none(opt.kx_groups.as_ref()));
+    line("proxy-protocol", &opt.proxy_protocol);
+    line("tcp-nodelay", &opt.tcp_nodelay);
+    line(
+        "tcp-keepalive",
+        &or_none(opt.tcp_keepalive.map(|s| format!("{s}s"))),
+    );
+    line("backlog", &opt.backlog);
+    line("max-conns-per-ip", &or_none(opt.max_conns_per_ip));
+    line("ban-after", &or_none(opt.ban_after));
+    line("ban-time", &format!("{}s", opt.ban_time));
+    for net in &opt.ban_allow {
+        line("ban-allow", net);
+    }
+    line("threads", &or_none(opt.threads));
+    #[cfg(feature = "workers")]
+    line("workers", &or_none(opt.workers));
+    #[cfg(feature = "daemon")]
+    {
+        line("daemon", &opt.daemon);
+        line(
+            "log-file",
+            &or_none(opt.log_file.as_ref().map(|p| p.display())),
+        );
+        line("chdir", &or_none(opt.chdir.as_ref().map(|p| p.display())));
+        line("umask", &or_none(opt.umask.map(|m| format!("{m:03o}"))));
+        line(
+            "pidfile",
+            &or_none(opt.pidfile.as_ref().map(|p| p.display())),
+        );
+    }
+    line("access-log", &or_none(opt.access_log.as_ref()));
+    line("statsd", &or_none(opt.statsd.as_ref()));
+    line("metrics", &or_none(opt.metrics));
+    out
+}
+
+/// print any problems found in the zip, and in the zips for each site prefixed with their path
+fn check(srv: &server::Server, sites: &[(&Site, server::Server)]) -> ExitCode {
+    let runtime = ear!(
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build(),
+        "could not start tokio runtime",
+        2
+    );
+    let problems = runtime.block_on(srv.check());
+    for problem in &problems {
+        println!("{problem}");
+    }
+    let mut count = problems.len();
+    for (site, srv) in sites {
+        let problems = runtime.block_on(srv.check());
+        for problem in &problems {
+            println!("{}: {problem}", site.zip.display());
+        }
+        count += problems.len();
+    }
+
+    if count == 0 {
+        ExitCode::SUCCESS
+    } else {
+        eprintln!("found {count} problems");
+        ExitCode::FAILURE
+    }
+}
+
+#[allow(
+    clippy::too_many_arguments,
+    reason = "each listener needs its own setup"
+)]
+async fn run(
+    srv: Arc<server::Server>,
+    acceptor: Option<TlsAcceptor>,
+    listeners: Vec<Listener>,
+    capsules: Vec<Capsule>,
+    tcp: TcpOptions,
+    stop: CancellationToken,
+    watch: Option<PathBuf>,
+    pubkey: Option<sign::PublicKey>,
+    metrics: Option<TcpListener>,
+) -> ExitCode {
+    if watch.is_some() {
+        for capsule in &capsules {
+            tokio::spawn(watch_zip(capsule.srv.clone(), capsule.zip.clone(), pubkey));
+        }
+    }
+    if let Some(path) = watch {
+        tokio::spawn(watch_zip(srv.clone(), path, pubkey));
+    }
+    if let Some(listener) = metrics {
+        listener
+            .set_nonblocking(true)
+            .expect("making metrics listener nonblocking");
+        let listener = tokio::net::TcpListener::from_std(listener)
+            .expect("turning std listener into tokio listener");
+        tokio::spawn(stats::sample(srv.clone()));
+        tokio::spawn(metrics::serve(listener, srv.clone()));
+    }
+    let (alive, mut done) = mpsc::channel(1);
+    let tasks = Tasks { stop, alive };
+
+    let mut listening = tokio::task::JoinSet::new();
+    for capsule in capsules {
+        let (tcp, tasks) = (tcp.clone(), tasks.clone());
+        listening.spawn(async move {
+            let acceptor = capsule.acceptor.as_ref();
+            handle_tcp(capsule.srv, acceptor, capsule.listener, tcp, &tasks).await
+        });
+    }
+    // each feeding the same server
+    for listener in listeners {
+        let (srv, acceptor) = (srv.clone(), acceptor.clone());
+        let (tcp, tasks) = (tcp.clone(), tasks.clone());
+        listening.spawn(async move {
+            let acceptor = acceptor.as_ref();
+            match listener {
+                Listener::Tcp(listener) => handle_tcp(srv, acceptor, listener, tcp, &tasks).await,
+                #[cfg(feature = "recvfd")]
+                Listener::Unix(listener) => handle_unix(srv, acceptor, listener, tcp, &tasks).await,
+                #[cfg(unix)]
+                Listener::UnixTls(listener) => {
+                    handle_unix_tls(srv, acceptor, listener, &tasks).await
+                }
+            }
+        });
+    }
+    // the first listener to stop takes the rest down with it
+    let code = match listening.join_next().await {
+        Some(Ok(code)) => code,
+        _ => ExitCode::FAILURE,
+    };
+    drop(listening);
+
+    if tasks.stop.is_cancelled() {
+        drop(tasks);
+        // every sender is gone once the last connection finishes
+        _ = done.recv().await;
+    }
+    code
+}
 
-    run(zip, &acceptor, listener)
+/// load the zip at a path while already accepting connections, exiting if it cannot be
+async fn load_zip(srv: Arc<server::Server>, path: PathBuf, pubkey: Option<sign::PublicKey>) {
+    let zip = match sign::open(&path, pubkey.as_ref()).await {
+        Ok(zip) => zip,
+        Err(e) => {
+            eprintln!("could not open zip at {}: {e}", path.display());
+            std::process::exit(2);
+        }
+    };
+    if let Err(e) = srv.swap_zip(zip).await {
+        eprintln!("could not load zip: {e}");
+        std::process::exit(2);
+    }
+    println!("loaded {}", path.display());
 }
 
-#[tokio::main]
-async fn run(zip: ZipFileReader, acceptor: &TlsAcceptor, listener: Listener) -> ExitCode {
-    let srv = Arc::new(server::Server::from_zip(zip));
+/// swap in the zip at a path whenever it is modified, once it stops changing
+async fn watch_zip(srv: Arc<server::Server>, path: PathBuf, pubkey: Option<sign::PublicKey>) {
+    // the size too, since timestamps can be coarse
+    async fn modified(path: &std::path::Path) -> Option<(std::time::SystemTime, u64)> {
+        let meta = tokio::fs::metadata(path).await.ok()?;
+        Some((meta.modified().ok()?, meta.len()))
+    }
+
+    let mut loaded = modified(&path).await;
+    let mut last = loaded;
+    loop {
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        let current = modified(&path).await;
+        // wait for a copy in progress to finish
+        if current != last {
+            last = current;
+            continue;
+        }
+        if current.is_none() || current == loaded {
+            continue;
+        }
+        loaded = current;
 
-    match listener {
-        Listener::Tcp(listener) => handle_tcp(srv, acceptor, listener).await,
-        #[cfg(feature = "recvfd")]
-        Listener::Unix(listener) => handle_unix(srv, acceptor, listener).await,
+        match sign::open(&path, pubkey.as_ref()).await {
+            Ok(zip) => match srv.swap_zip(zip).await {
+                Ok(()) => println!("reloaded {}", path.display()),
+                Err(e) => eprintln!("could not load zip: {e}"),
+            },
+            Err(e) => eprintln!("could not open zip at {}: {e}", path.display()),
+        }
     }
 }
 
+/// serve a connection, doing the tls handshake first if needed.
+///
+/// returns whether the peer behaved, completing the handshake and sending a well-formed request
+async fn serve<S>(
+    srv: &server::Server,
+    acceptor: Option<&TlsAcceptor>,
+    sock: S,
+    peer: Option<SocketAddr>,
+) -> bool
+where
+    S: server::Connection,
+{
+    let Some(acceptor) = acceptor else {
+        return handle_connection(srv, sock, peer).await;
+    };
+    let stream = match timeout(srv.handshake_timeout(), acceptor.accept(sock)).await {
+        Ok(Ok(stream)) => stream,
+        failed => {
+            let why = match failed {
+                Ok(Err(e)) => tls::handshake_failure(&e),
+                _ => metrics::HandshakeFailure::Timeout,
+            };
+            srv.metrics().handshake_failed(why);
+            log_rejected(peer, &format!("handshake_{}", why.name()));
+            return false;
+        }
+    };
+
+    handle_connection(srv, stream, peer).await
+}
+
+async fn handle_connection<S>(srv: &server::Server, sock: S, peer: Option<SocketAddr>) -> bool
+where
+    S: server::Connection,
+{
+    let start = Instant::now();
+    let outcome = srv.handle_connection(sock).await;
+    access::log(peer, &outcome);
+    statsd::record(&outcome, start.elapsed());
+    let Some(why) = outcome.rejection else {
+        return true;
+    };
+    log_rejected(peer, why.name());
+    false
+}
+
 async fn handle_tcp(
     srv: Arc<server::Server>,
-    acceptor: &TlsAcceptor,
+    acceptor: Option<&TlsAcceptor>,
     listener: TcpListener,
+    tcp: TcpOptions,
+    tasks: &Tasks,
 ) -> ExitCode {
     listener
         .set_nonblocking(true)
@@ -349,17 +2053,73 @@ async fn handle_tcp(
         .expect("turning std listener into tokio listener");
 
     loop {
-        let (sock, _addr) = ear!(listener.accept().await, "failed to accept", 6);
-        let acceptor = acceptor.clone();
+        let (mut sock, addr) = tokio::select! {
+            sock = listener.accept() => ear!(sock, "failed to accept", 6),
+            () = tasks.stop.cancelled() => return ExitCode::SUCCESS,
+        };
+        if tcp.is_banned(addr) {
+            continue;
+        }
+        let acceptor = acceptor.cloned();
         let srv = srv.clone();
+        let tcp = tcp.clone();
 
-        tokio::spawn(async move {
-            let Ok(Ok(stream)) = timeout(Duration::from_secs(10), acceptor.accept(sock)).await
-            else {
+        if tcp.nodelay {
+            _ = sock.set_nodelay(true);
+        }
+        if let Some(time) = tcp.keepalive {
+            let keepalive = socket2::TcpKeepalive::new().with_time(time);
+            _ = socket2::SockRef::from(&sock).set_tcp_keepalive(&keepalive);
+        }
+
+        tasks.spawn(async move {
+            let peer = if tcp.proxy_protocol {
+                match timeout(Duration::from_secs(10), proxy::read_header(&mut sock)).await {
+                    Ok(Ok(peer)) => peer.unwrap_or(addr),
+                    _ => return,
+                }
+            } else {
+                addr
+            };
+            // behind a proxy, only now do we know who this is
+            if peer != addr && tcp.is_banned(peer) {
+                return;
+            }
+            let Ok(_open) = tcp.open(peer) else {
                 return;
             };
 
-            srv.handle_connection(stream).await;
+            if !serve(&srv, acceptor.as_ref(), sock, Some(peer)).await {
+                tcp.strike(peer);
+            }
+        });
+    }
+}
+
+/// serve connections made directly to a unix socket
+#[cfg(unix)]
+async fn handle_unix_tls(
+    srv: Arc<server::Server>,
+    acceptor: Option<&TlsAcceptor>,
+    listener: UnixListener,
+    tasks: &Tasks,
+) -> ExitCode {
+    listener
+        .set_nonblocking(true)
+        .expect("making listener nonblocking");
+    let listener = tokio::net::UnixListener::from_std(listener)
+        .expect("turning std listener into tokio listener");
+
+    loop {
+        let (sock, _addr) = tokio::select! {
+            sock = listener.accept() => ear!(sock, "failed to accept", 6),
+            () = tasks.stop.cancelled() => return ExitCode::SUCCESS,
+        };
+        let acceptor = acceptor.cloned();
+        let srv = srv.clone();
+
+        tasks.spawn(async move {
+            serve(&srv, acceptor.as_ref(), sock, None).await;
         });
     }
 }
@@ -367,8 +2127,10 @@ async fn handle_tcp(
 #[cfg(feature = "recvfd")]
 async fn handle_unix(
     srv: Arc<server::Server>,
-    acceptor: &TlsAcceptor,
+    acceptor: Option<&TlsAcceptor>,
     listener: UnixListener,
+    tcp: TcpOptions,
+    tasks: &Tasks,
 ) -> ExitCode {
     listener
         .set_nonblocking(true)
@@ -377,46 +2139,105 @@ async fn handle_unix(
         .expect("turning std listener into tokio listener");
 
     loop {
-        let (sock, _addr) = ear!(listener.accept().await, "failed to accept", 6);
-        let acceptor = acceptor.clone();
+        let (sock, _addr) = tokio::select! {
+            sock = listener.accept() => ear!(sock, "failed to accept", 6),
+            () = tasks.stop.cancelled() => return ExitCode::SUCCESS,
+        };
+        let acceptor = acceptor.cloned();
         let srv = srv.clone();
+        let tcp = tcp.clone();
 
-        tokio::spawn(async move {
+        let fd_tasks = tasks.clone();
+        tasks.spawn(async move {
             use asyncfd::UnixFdStream;
-            use std::os::fd::FromRawFd;
             use tokio::io::AsyncReadExt;
 
-            let Some(fd) = ({
-                let Ok(sock) = sock.into_std() else {
-                    return;
-                };
-                let Ok(mut sock) = UnixFdStream::new(sock, 1) else {
-                    return;
-                };
-                // do a throwaway read so that we can get the fd from ancillary data.
-                // calico just sends a null byte here
-                _ = sock.read_u8().await;
-                sock.pop_incoming_fd()
-            }) else {
-                return;
-            };
-            // SAFETY: we just received the fd so we should have exclusive access to it.
-            // notably, from_raw_fd has no safety requirement on what kind of fd to give it. this is
-            // good for us, since we could receive pretty much any kind of fd, and we do not have a
-            // convenient way to check that it actually corresponds to a tcp connection
-            let stream = unsafe { std::net::TcpStream::from_raw_fd(fd) };
-            if stream.set_nonblocking(true).is_err() {
-                return;
-            }
-            let Ok(stream) = tokio::net::TcpStream::from_std(stream) else {
+            let Ok(sock) = sock.into_std() else {
                 return;
             };
-            let Ok(Ok(stream)) = timeout(Duration::from_secs(10), acceptor.accept(stream)).await
-            else {
+            let Ok(mut sock) = UnixFdStream::new(sock, 16) else {
                 return;
             };
-
-            srv.handle_connection(stream).await;
+            // the sender may batch several fds into one message or spread them across several,
+            // so keep reading until it hangs up. calico just sends a null byte with each fd
+            let mut buf = [0; 16];
+            loop {
+                let done = !matches!(sock.read(&mut buf).await, Ok(1..));
+                while let Some(fd) = sock.pop_incoming_fd() {
+                    fd_tasks.spawn(handle_fd(
+                        srv.clone(),
+                        acceptor.clone(),
+                        fd,
+                        tcp.clone(),
+                        fd_tasks.clone(),
+                    ));
+                }
+                if done {
+                    break;
+                }
+            }
         });
     }
 }
+
+/// check if a socket is listening for connections, rather than being connected
+#[cfg(feature = "recvfd")]
+fn is_listening(sock: &impl std::os::fd::AsRawFd) -> bool {
+    let mut val: libc::c_int = 0;
+    let mut len = libc::socklen_t::try_from(size_of::<libc::c_int>())
+        .expect("size of c_int should fit in socklen_t");
+    // SAFETY: val and len are valid for writes, and len is the size of val
+    let ret = unsafe {
+        libc::getsockopt(
+            sock.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_ACCEPTCONN,
+            (&raw mut val).cast(),
+            &raw mut len,
+        )
+    };
+    ret == 0 && val != 0
+}
+
+/// serve a tcp connection received over a unix socket.
+///
+/// if the received socket is a listener instead, accept connections on it like normal
+#[cfg(feature = "recvfd")]
+async fn handle_fd(
+    srv: Arc<server::Server>,
+    acceptor: Option<TlsAcceptor>,
+    fd: std::os::fd::RawFd,
+    tcp: TcpOptions,
+    tasks: Tasks,
+) {
+    use std::os::fd::{FromRawFd, OwnedFd};
+
+    // SAFETY: we just received the fd so we should have exclusive access to it.
+    // notably, from_raw_fd has no safety requirement on what kind of fd to give it. this is
+    // good for us, since we could receive pretty much any kind of fd, and we do not have a
+    // convenient way to check that it actually corresponds to a tcp connection
+    let stream = unsafe { std::net::TcpStream::from_raw_fd(fd) };
+    if is_listening(&stream) {
+        let listener = TcpListener::from(OwnedFd::from(stream));
+        _ = handle_tcp(srv, acceptor.as_ref(), listener, tcp, &tasks).await;
+        return;
+    }
+    if stream.set_nonblocking(true).is_err() {
+        return;
+    }
+    let Ok(stream) = tokio::net::TcpStream::from_std(stream) else {
+        return;
+    };
+    let peer = stream.peer_addr().ok();
+    if peer.is_some_and(|peer| tcp.is_banned(peer)) {
+        return;
+    }
+    let Ok(_open) = peer.map(|peer| tcp.open(peer)).transpose() else {
+        return;
+    };
+    if !serve(&srv, acceptor.as_ref(), stream, peer).await
+        && let Some(peer) = peer
+    {
+        tcp.strike(peer);
+    }
+}
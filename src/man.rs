@@ -0,0 +1,111 @@
+//! a man page generated from the option definitions, documenting the features this binary was
+//! built with
+
+use argh::{ArgsInfo, CommandInfoWithArgs, FlagInfo, FlagInfoKind, FromArgs};
+use std::fmt::Write;
+
+/// print a man page for redgem in roff
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "man")]
+pub struct Opt {}
+
+/// exit statuses shared by serving and the subcommands that load the zip
+const EXIT_STATUSES: &[(u8, &str)] = &[
+    (1, "bad options or paths, or problems found by check"),
+    (2, "the zip could not be loaded"),
+    (3, "the certificate could not be used"),
+    (4, "the private key could not be used"),
+    (5, "a listener could not be bound"),
+    (6, "connections could not be accepted"),
+];
+
+/// escape text for roff, so that it is not taken as requests or escapes
+fn escape(text: &str) -> String {
+    let text = text.replace('\\', "\\e").replace('-', "\\-");
+    if text.starts_with(['.', '\'']) {
+        format!("\\&{text}")
+    } else {
+        text
+    }
+}
+
+fn flags(out: &mut String, flags: &[FlagInfo]) {
+    for flag in flags.iter().filter(|flag| !flag.hidden) {
+        out.push_str(".TP\n");
+        _ = write!(out, "\\fB{}\\fR", escape(flag.long));
+        if let FlagInfoKind::Option { arg_name } = flag.kind {
+            _ = write!(out, " \\fI{}\\fR", escape(arg_name));
+        }
+        out.push('\n');
+        _ = writeln!(out, "{}", escape(flag.description));
+    }
+}
+
+/// the man page for a command and its subcommands
+pub fn page<T: ArgsInfo>(features: &[&str]) -> String {
+    let info: CommandInfoWithArgs = T::get_args_info();
+    let name = env!("CARGO_PKG_NAME");
+    let version = env!("CARGO_PKG_VERSION");
+
+    let mut out = String::new();
+    _ = writeln!(
+        out,
+        ".TH {} 1 \"\" \"{name} {version}\"",
+        name.to_uppercase()
+    );
+    out.push_str(".SH NAME\n");
+    _ = writeln!(out, "{name} \\- {}", escape(info.description));
+
+    out.push_str(".SH SYNOPSIS\n");
+    _ = write!(out, ".B {name}\n[\\fIoptions\\fR]");
+    for positional in info.positionals.iter().filter(|p| !p.hidden) {
+        _ = write!(out, " [\\fI{}\\fR...]", escape(positional.name));
+    }
+    out.push('\n');
+    _ = writeln!(out, ".br\n.B {name}\n\\fIcommand\\fR [\\fIoptions\\fR]");
+
+    out.push_str(".SH OPTIONS\n");
+    for positional in info.positionals.iter().filter(|p| !p.hidden) {
+        _ = writeln!(out, ".TP\n\\fI{}\\fR", escape(positional.name));
+        _ = writeln!(out, "{}", escape(positional.description));
+    }
+    flags(&mut out, info.flags);
+
+    out.push_str(".SH COMMANDS\n");
+    for command in &info.commands {
+        _ = writeln!(out, ".SS {}", escape(command.name));
+        _ = writeln!(out, "{}", escape(command.command.description));
+        flags(&mut out, command.command.flags);
+    }
+
+    out.push_str(".SH EXIT STATUS\n");
+    for (code, meaning) in EXIT_STATUSES {
+        _ = writeln!(out, ".TP\n{code}\n{}", escape(meaning));
+    }
+
+    out.push_str(".SH FEATURES\n");
+    _ = writeln!(
+        out,
+        "this build includes the following features: {}",
+        escape(&features.join(", "))
+    );
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{escape, page};
+
+    #[test]
+    fn roff() {
+        assert_eq!(escape("--bind"), "\\-\\-bind");
+        assert_eq!(escape(".redgem\\"), "\\&.redgem\\e");
+
+        let page = page::<crate::Opt>(&["deflate"]);
+        assert!(page.starts_with(".TH REDGEM 1 "));
+        assert!(page.contains("\n.TP\n\\fB\\-\\-bind\\fR \\fIbind\\fR\n"));
+        assert!(page.contains("\n.SS fetch\n"));
+        assert!(page.contains("\n.SS man\n"));
+        assert!(page.ends_with("features: deflate\n"));
+    }
+}
@@ -0,0 +1,205 @@
+//! counters for keeping an eye on a running server, served over http in prometheus' text format
+
+use crate::{server::Server, stats::Stats};
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::{
+        Arc, Mutex, PoisonError,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+
+/// how many paths to count hits for, past which the least read one gets replaced
+const MAX_PATHS: usize = 256;
+
+/// why a tls handshake failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeFailure {
+    /// the client did not finish in time
+    Timeout,
+    /// the connection was closed or reset partway through
+    Closed,
+    /// the client sent something that is not tls, or garbled tls
+    BadRecord,
+    /// the client only speaks tls versions we do not
+    UnsupportedVersion,
+    /// the client has no cipher suites, groups, or signature schemes in common with us
+    Incompatible,
+    /// the client sent an alert, usually because it did not like our certificate
+    Rejected,
+    Other,
+}
+
+impl HandshakeFailure {
+    const ALL: [Self; 7] = [
+        Self::Timeout,
+        Self::Closed,
+        Self::BadRecord,
+        Self::UnsupportedVersion,
+        Self::Incompatible,
+        Self::Rejected,
+        Self::Other,
+    ];
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Timeout => "timeout",
+            Self::Closed => "closed",
+            Self::BadRecord => "bad_record",
+            Self::UnsupportedVersion => "unsupported_version",
+            Self::Incompatible => "incompatible",
+            Self::Rejected => "rejected",
+            Self::Other => "other",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    handshake_failures: [AtomicU64; HandshakeFailure::ALL.len()],
+    /// successful requests for the most read paths
+    hits: Mutex<HashMap<String, u64>>,
+    pub stats: Stats,
+}
+
+impl Metrics {
+    pub fn handshake_failed(&self, why: HandshakeFailure) {
+        self.handshake_failures[why as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// count a successful request for a path
+    pub fn hit(&self, path: &str) {
+        let mut hits = self.hits.lock().unwrap_or_else(PoisonError::into_inner);
+        if let Some(count) = hits.get_mut(path) {
+            *count += 1;
+            return;
+        }
+        // like the space-saving algorithm, a newcomer takes over the count of the path it
+        // replaces, so that a stream of rarely read paths cannot push out the popular ones
+        let mut count = 1;
+        if hits.len() >= MAX_PATHS
+            && let Some((least, &min)) = hits.iter().min_by_key(|&(_, &n)| n)
+        {
+            let least = least.clone();
+            hits.remove(&least);
+            count += min;
+        }
+        hits.insert(path.to_string(), count);
+    }
+
+    /// the most read paths and how often, most read first
+    pub fn top_hits(&self) -> Vec<(String, u64)> {
+        let hits = self.hits.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut top: Vec<_> = hits.iter().map(|(p, &n)| (p.clone(), n)).collect();
+        drop(hits);
+        top.sort_unstable_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+        top
+    }
+
+    /// every counter, in prometheus' text exposition format
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP redgem_handshake_failures_total failed tls handshakes\n");
+        out.push_str("# TYPE redgem_handshake_failures_total counter\n");
+        for why in HandshakeFailure::ALL {
+            _ = writeln!(
+                out,
+                "redgem_handshake_failures_total{{reason=\"{}\"}} {}",
+                why.name(),
+                self.handshake_failures[why as usize].load(Ordering::Relaxed)
+            );
+        }
+        out.push_str("# HELP redgem_hits_total successful requests for the most read paths\n");
+        out.push_str("# TYPE redgem_hits_total counter\n");
+        for (path, count) in self.top_hits() {
+            _ = writeln!(out, "redgem_hits_total{{path=\"{path}\"}} {count}");
+        }
+        self.stats.render(&mut out);
+        out
+    }
+}
+
+/// answer every http request on a listener with the server's metrics
+pub async fn serve(listener: TcpListener, srv: Arc<Server>) {
+    loop {
+        let sock = match listener.accept().await {
+            Ok((sock, _)) => sock,
+            Err(e) => {
+                // such as running out of fds, which would fail again right away
+                eprintln!("failed to accept metrics connection: {e}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let srv = srv.clone();
+        tokio::spawn(async move {
+            _ = timeout(Duration::from_secs(10), respond(sock, &srv)).await;
+        });
+    }
+}
+
+async fn respond(mut sock: TcpStream, srv: &Server) -> std::io::Result<()> {
+    // which path was requested does not matter, but wait for the whole request so that closing
+    // the connection does not reset it before the client reads the response
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.ends_with(b"\r\n\r\n") && request.len() < 8192 {
+        let count = sock.read(&mut buf).await?;
+        if count == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..count]);
+    }
+
+    let body = srv.metrics().render();
+    let response = format!(
+        "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{body}",
+        body.len()
+    );
+    sock.write_all(response.as_bytes()).await?;
+    sock.shutdown().await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{HandshakeFailure, Metrics};
+
+    #[test]
+    fn render() {
+        let metrics = Metrics::default();
+        metrics.handshake_failed(HandshakeFailure::BadRecord);
+        metrics.handshake_failed(HandshakeFailure::BadRecord);
+        metrics.handshake_failed(HandshakeFailure::Timeout);
+        let text = metrics.render();
+        assert!(text.contains("redgem_handshake_failures_total{reason=\"bad_record\"} 2\n"));
+        assert!(text.contains("redgem_handshake_failures_total{reason=\"timeout\"} 1\n"));
+        assert!(text.contains("redgem_handshake_failures_total{reason=\"other\"} 0\n"));
+    }
+
+    #[test]
+    fn hits() {
+        let metrics = Metrics::default();
+        for _ in 0..10 {
+            metrics.hit("/popular.gmi");
+        }
+        metrics.hit("/");
+        for i in 0..super::MAX_PATHS * 2 {
+            metrics.hit(&format!("/{i}.gmi"));
+        }
+        let top = metrics.top_hits();
+        assert_eq!(top.len(), super::MAX_PATHS);
+        assert_eq!(top[0], ("/popular.gmi".into(), 10));
+        assert!(
+            metrics
+                .render()
+                .contains("redgem_hits_total{path=\"/popular.gmi\"} 10\n")
+        );
+    }
+}
@@ -0,0 +1,379 @@
+//! crawling a capsule into a zip, so that it can be served with redgem elsewhere
+
+use crate::{fetch, pack};
+use argh::{ArgsInfo, FromArgs};
+use fluent_uri::{Iri, IriRef, Uri};
+use std::{
+    collections::{HashSet, VecDeque},
+    path::PathBuf,
+    process::ExitCode,
+    time::{Duration, SystemTime},
+};
+use tokio::{io::AsyncReadExt, time::timeout};
+use tokio_rustls::TlsConnector;
+use unicode_normalization::UnicodeNormalization;
+
+/// which crawler robots.txt rules apply to, see <gemini://geminiprotocol.net/docs/companion/robots.gmi>
+const AGENT: &str = "archiver";
+
+/// crawl a capsule, following links that stay on the same host, and write every page into a zip
+/// ready to serve.
+///
+/// respects the capsule's robots.txt, and skips links with queries since those usually ask for
+/// input
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "mirror")]
+pub struct Opt {
+    /// where to write the zip
+    #[argh(option, short = 'o')]
+    output: PathBuf,
+    /// most pages to fetch, not counting ones that could not be reached
+    #[argh(option, default = "10000")]
+    max_pages: usize,
+    /// seconds to wait between requests, to go easy on the capsule
+    #[argh(option, default = "1")]
+    delay: u64,
+    /// seconds to wait for each response
+    #[argh(option, default = "10")]
+    timeout: u64,
+    /// file of certificate fingerprints to trust hosts on first use with, refusing them if their
+    /// certificate changes later
+    #[argh(option)]
+    known_hosts: Option<PathBuf>,
+    /// url to start crawling from
+    #[argh(positional)]
+    url: String,
+}
+
+#[derive(Debug, foxerror::FoxError)]
+pub enum Error {
+    #[err(from)]
+    Fetch(fetch::Error),
+    #[err(from)]
+    Pack(pack::Error),
+    #[err(from)]
+    Io(std::io::Error),
+    /// robots.txt does not allow mirroring it
+    Disallowed,
+    /// nothing could be mirrored
+    Empty,
+}
+
+/// the paths a capsule's robots.txt does not want us to request
+#[derive(Debug, Default)]
+struct Robots {
+    disallow: Vec<String>,
+}
+
+impl Robots {
+    /// read the rules for everyone and for `agent`
+    fn parse(text: &str, agent: &str) -> Self {
+        let mut disallow = vec![];
+        // consecutive user-agent lines start a group that the following rules apply to
+        let (mut applies, mut in_agents) = (false, false);
+        for line in text.lines() {
+            let line = line.split('#').next().unwrap_or_default();
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            let (key, value) = (key.trim(), value.trim());
+            if key.eq_ignore_ascii_case("user-agent") {
+                if !in_agents {
+                    applies = false;
+                }
+                in_agents = true;
+                applies |= value == "*" || value.eq_ignore_ascii_case(agent);
+            } else {
+                in_agents = false;
+                // an empty disallow allows everything
+                if applies && key.eq_ignore_ascii_case("disallow") && !value.is_empty() {
+                    disallow.push(value.to_string());
+                }
+            }
+        }
+        Self { disallow }
+    }
+
+    fn allows(&self, path: &str) -> bool {
+        !self.disallow.iter().any(|prefix| path.starts_with(prefix))
+    }
+}
+
+/// the targets of every link line in gemtext, outside of preformatted blocks
+fn links(gemtext: &str) -> impl Iterator<Item = &str> {
+    let mut preformatted = false;
+    gemtext.lines().filter_map(move |line| {
+        if line.starts_with("```") {
+            preformatted = !preformatted;
+            return None;
+        }
+        if preformatted {
+            return None;
+        }
+        line.strip_prefix("=>")?.split_whitespace().next()
+    })
+}
+
+/// resolve a link, keeping it only if it is on the same capsule and has no query
+fn resolve(base: &Uri<String>, target: &str) -> Option<Uri<String>> {
+    let mut url = IriRef::parse(target)
+        .ok()?
+        .to_uri_ref()
+        .resolve_against(base)
+        .ok()?
+        .normalize();
+    url.set_fragment(None);
+    (url.scheme() == base.scheme()
+        && url.authority().map(|a| a.as_str()) == base.authority().map(|a| a.as_str())
+        && url.query().is_none())
+    .then_some(url)
+}
+
+/// the name to store a page under in the zip, with directories getting an index.gmi
+fn entry_name(url: &Uri<String>) -> String {
+    let path = url.path().decode().to_bytes();
+    let path = String::from_utf8_lossy(&path);
+    let mut name = path.trim_start_matches('/').nfc().collect::<String>();
+    if name.is_empty() || name.ends_with('/') {
+        name.push_str("index.gmi");
+    }
+    name
+}
+
+/// move pages that are also directories, like `/blog` next to `/blog/post.gmi`, to the
+/// directory's index.gmi since a zip cannot have both. if that index was mirrored too, the page
+/// is skipped instead
+fn nest_pages(files: Vec<(String, Vec<u8>)>) -> Vec<(String, Vec<u8>)> {
+    let names: HashSet<_> = files.iter().map(|(name, _)| name.clone()).collect();
+    let dirs: HashSet<_> = files
+        .iter()
+        .flat_map(|(name, _)| name.match_indices('/').map(|(i, _)| name[..i].to_string()))
+        .collect();
+    files
+        .into_iter()
+        .filter_map(|(name, body)| {
+            if !dirs.contains(&name) {
+                return Some((name, body));
+            }
+            let index = format!("{name}/index.gmi");
+            if names.contains(&index) {
+                eprintln!("skipping {name}, it is also a directory that has an index.gmi");
+                return None;
+            }
+            Some((index, body))
+        })
+        .collect()
+}
+
+/// fetch a url, returning the response header and body
+async fn get(
+    connector: &TlsConnector,
+    url: &Uri<String>,
+    limit: Duration,
+) -> Result<(String, Vec<u8>), fetch::Error> {
+    let response = async {
+        let (header, mut stream) = fetch::request(connector, url).await?;
+        let mut body = vec![];
+        stream.read_to_end(&mut body).await?;
+        Ok((header, body))
+    };
+    timeout(limit, response)
+        .await
+        .unwrap_or(Err(fetch::Error::Timeout))
+}
+
+async fn crawl(
+    opt: &Opt,
+    connector: &TlsConnector,
+    start: Uri<String>,
+) -> Result<Vec<(String, Vec<u8>)>, Error> {
+    let limit = Duration::from_secs(opt.timeout);
+    let delay = Duration::from_secs(opt.delay);
+
+    let robots = match resolve(&start, "/robots.txt") {
+        Some(url) => match get(connector, &url, limit).await {
+            Ok((header, body)) if header.starts_with('2') => {
+                Robots::parse(&String::from_utf8_lossy(&body), AGENT)
+            }
+            _ => Robots::default(),
+        },
+        None => Robots::default(),
+    };
+    if !robots.allows(start.path().as_str()) {
+        return Err(Error::Disallowed);
+    }
+
+    let mut files = vec![];
+    let mut seen = HashSet::from([start.as_str().to_string()]);
+    let mut queue = VecDeque::from([start]);
+    let mut fetched = 0;
+    while let Some(url) = queue.pop_front() {
+        if fetched >= opt.max_pages {
+            eprintln!("stopping after {fetched} pages");
+            break;
+        }
+        tokio::time::sleep(delay).await;
+
+        // only pages that answered count towards --max-pages
+        let (header, body) = match get(connector, &url, limit).await {
+            Ok(response) => response,
+            Err(e) => {
+                eprintln!("could not fetch {url}: {e}");
+                continue;
+            }
+        };
+        fetched += 1;
+        eprintln!("{url} {header}");
+
+        let found: Vec<_> = match header.as_bytes()[0] {
+            b'2' => {
+                let meta = header.get(3..).unwrap_or_default();
+                let found = if meta.is_empty() || meta.starts_with("text/gemini") {
+                    let gemtext = String::from_utf8_lossy(&body);
+                    links(&gemtext)
+                        .filter_map(|target| resolve(&url, target))
+                        .collect()
+                } else {
+                    vec![]
+                };
+                files.push((entry_name(&url), body));
+                found
+            }
+            b'3' => header
+                .get(3..)
+                .and_then(|target| resolve(&url, target))
+                .into_iter()
+                .collect(),
+            _ => vec![],
+        };
+        for link in found {
+            if robots.allows(link.path().as_str()) && seen.insert(link.as_str().to_string()) {
+                queue.push_back(link);
+            }
+        }
+    }
+
+    if files.is_empty() {
+        return Err(Error::Empty);
+    }
+    Ok(files)
+}
+
+async fn mirror(opt: &Opt, connector: &TlsConnector, start: Uri<String>) -> Result<usize, Error> {
+    let mut files = nest_pages(crawl(opt, connector, start).await?);
+    // the same order the index ends up in, like pack
+    files.sort_by(|a, b| unix_path::Path::new(&a.0).cmp(unix_path::Path::new(&b.0)));
+    files.dedup_by(|a, b| a.0 == b.0);
+    let zip = pack::build_zip_from(&files, SystemTime::now()).await?;
+    tokio::fs::write(&opt.output, zip).await?;
+    Ok(files.len())
+}
+
+pub fn run(opt: &Opt) -> ExitCode {
+    let Ok(start) = Iri::parse(opt.url.as_str()) else {
+        eprintln!("could not mirror {}: {}", opt.url, fetch::Error::BadUrl);
+        return ExitCode::from(1);
+    };
+    let Some(start) = resolve(&start.to_uri(), "") else {
+        eprintln!("could not mirror {}: {}", opt.url, fetch::Error::BadUrl);
+        return ExitCode::from(1);
+    };
+    let Some(connector) = fetch::connector(opt.known_hosts.as_deref()) else {
+        return ExitCode::from(1);
+    };
+    let runtime = match tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("could not start tokio runtime: {e}");
+            return ExitCode::from(2);
+        }
+    };
+
+    match runtime.block_on(mirror(opt, &connector, start)) {
+        Ok(count) => {
+            eprintln!("wrote {count} files to {}", opt.output.display());
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("could not mirror {}: {e}", opt.url);
+            ExitCode::from(2)
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::{Robots, entry_name, links, nest_pages, resolve};
+    use fluent_uri::Uri;
+
+    #[test]
+    fn robots() {
+        let robots = Robots::parse(
+            "User-agent: indexer\nDisallow: /\n\n\
+             User-agent: *\nUser-agent: archiver\nDisallow: /private # not public\nDisallow:\n",
+            "archiver",
+        );
+        assert!(robots.allows("/"));
+        assert!(robots.allows("/public.gmi"));
+        assert!(!robots.allows("/private/diary.gmi"));
+
+        assert!(!Robots::parse("user-agent: archiver\ndisallow: /", "archiver").allows("/"));
+        assert!(Robots::parse("User-agent: webproxy\nDisallow: /", "archiver").allows("/"));
+    }
+
+    #[test]
+    fn crawling() {
+        let gemtext = "# hi\n=> /about.gmi about\n```\n=> /not-a-link\n```\n=>\tposts/\n";
+        assert_eq!(links(gemtext).collect::<Vec<_>>(), ["/about.gmi", "posts/"]);
+
+        let base = Uri::parse("gemini://example.com/blog/").unwrap().to_owned();
+        let same = |target| resolve(&base, target).map(|u| u.as_str().to_string());
+        assert_eq!(
+            same("../café.gmi#top").as_deref(),
+            Some("gemini://example.com/caf%C3%A9.gmi")
+        );
+        assert_eq!(
+            same("gemini://EXAMPLE.com/").as_deref(),
+            Some("gemini://example.com/")
+        );
+        assert_eq!(same("gemini://example.org/"), None);
+        assert_eq!(same("https://example.com/"), None);
+        assert_eq!(same("search?q"), None);
+
+        let name = |url| entry_name(&Uri::parse(url).unwrap().to_owned());
+        assert_eq!(name("gemini://example.com"), "index.gmi");
+        assert_eq!(name("gemini://example.com/blog/"), "blog/index.gmi");
+        assert_eq!(name("gemini://example.com/caf%C3%A9.gmi"), "café.gmi");
+    }
+
+    #[test]
+    fn nesting() {
+        let page = |name: &str| (name.to_string(), name.as_bytes().to_vec());
+        let names = |files: Vec<(String, Vec<u8>)>| {
+            files
+                .into_iter()
+                .map(|(name, body)| (name, String::from_utf8(body).unwrap()))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            names(nest_pages(vec![
+                page("blog"),
+                page("blog/post.gmi"),
+                page("about.gmi"),
+            ])),
+            [
+                ("blog/index.gmi".into(), "blog".into()),
+                ("blog/post.gmi".into(), "blog/post.gmi".into()),
+                ("about.gmi".into(), "about.gmi".into()),
+            ]
+        );
+        assert_eq!(
+            names(nest_pages(vec![page("blog"), page("blog/index.gmi"),])),
+            [("blog/index.gmi".into(), "blog/index.gmi".into())]
+        );
+    }
+}
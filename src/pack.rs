@@ -0,0 +1,491 @@
+//! building zips and self-serving executables out of a directory
+
+use argh::{ArgsInfo, FromArgs};
+use async_zip::{
+    Compression, StoredZipEntry, ZipDateTime, ZipDateTimeBuilder, ZipEntryBuilder,
+    base::write::ZipFileWriter, tokio::read::fs::ZipFileReader,
+};
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufWriter},
+};
+use unicode_normalization::UnicodeNormalization;
+
+pub const EOCD_SIGNATURE: &[u8; 4] = b"PK\x05\x06";
+const CENTRAL_SIGNATURE: &[u8; 4] = b"PK\x01\x02";
+
+/// zip up a directory and append it to a copy of this executable, which then serves it
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "pack")]
+pub struct Opt {
+    /// directory to pack
+    #[argh(positional)]
+    dir: PathBuf,
+    /// where to write the executable
+    #[argh(option, short = 'o')]
+    output: PathBuf,
+    /// include files starting with a `.`
+    #[argh(switch)]
+    hidden: bool,
+}
+
+/// zip up a directory, arranged for serving with redgem.
+///
+/// file names are normalized to nfc, dotfiles other than /.redgem are left out, and files that
+/// are already compressed are stored as-is
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "zip")]
+pub struct ZipOpt {
+    /// directory to zip
+    #[argh(positional)]
+    dir: PathBuf,
+    /// where to write the zip
+    #[argh(option, short = 'o')]
+    output: PathBuf,
+    /// include files starting with a `.`
+    #[argh(switch)]
+    hidden: bool,
+}
+
+#[derive(Debug, foxerror::FoxError)]
+pub enum Error {
+    #[err(from)]
+    Io(std::io::Error),
+    #[err(from)]
+    Zip(async_zip::error::ZipError),
+    /// file name is not valid utf-8
+    NonUtf8Name(String),
+    /// several files have the same name after normalization
+    Collision(String),
+    /// directory links back to a directory it is in
+    Loop(String),
+    /// could not adjust zip offsets
+    Offsets,
+    /// `SOURCE_DATE_EPOCH` is not a number of seconds
+    BadSourceDate,
+}
+
+pub fn pack(opt: &Opt, binary: &Path) -> ExitCode {
+    run(&opt.dir, &opt.output, opt.hidden, Some(binary))
+}
+
+pub fn zip(opt: &ZipOpt) -> ExitCode {
+    run(&opt.dir, &opt.output, opt.hidden, None)
+}
+
+/// zip up a directory, attaching it to a copy of `binary` if given
+fn run(dir: &Path, output: &Path, hidden: bool, binary: Option<&Path>) -> ExitCode {
+    let runtime = match tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+    {
+        Ok(runtime) => runtime,
+        Err(e) => {
+            eprintln!("could not start tokio runtime: {e}");
+            return ExitCode::from(2);
+        }
+    };
+
+    let write = async { write(dir, output, hidden, binary, source_date_epoch()?).await };
+    match runtime.block_on(write) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("could not zip {}: {e}", dir.display());
+            ExitCode::from(2)
+        }
+    }
+}
+
+/// the latest date to give entries, so that zipping the same files gives the same zip no matter
+/// when they were checked out. see <https://reproducible-builds.org/specs/source-date-epoch/>
+fn source_date_epoch() -> Result<Option<SystemTime>, Error> {
+    let Ok(secs) = std::env::var("SOURCE_DATE_EPOCH") else {
+        return Ok(None);
+    };
+    let secs = secs.trim().parse().map_err(|_| Error::BadSourceDate)?;
+    Ok(Some(UNIX_EPOCH + Duration::from_secs(secs)))
+}
+
+async fn write(
+    dir: &Path,
+    output: &Path,
+    hidden: bool,
+    binary: Option<&Path>,
+    latest: Option<SystemTime>,
+) -> Result<(), Error> {
+    let mut files = vec![];
+    walk(dir, "", hidden, &mut vec![], &mut files)?;
+    // the same order the index ends up in, and reproducible
+    files.sort_by(|a, b| unix_path::Path::new(&a.0).cmp(unix_path::Path::new(&b.0)));
+    if let Some(pair) = files.windows(2).find(|pair| pair[0].0 == pair[1].0) {
+        return Err(Error::Collision(pair[0].0.clone()));
+    }
+
+    let mut out = File::options()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(output)
+        .await?;
+    let start = match binary {
+        Some(binary) => {
+            let len = executable_len(binary).await?;
+            tokio::io::copy(&mut File::open(binary).await?.take(len), &mut out).await?
+        }
+        None => 0,
+    };
+    let mut out = build_zip(&files, latest, BufWriter::new(out))
+        .await?
+        .into_inner();
+    if start > 0 {
+        shift_file_offsets(&mut out, start).await?;
+    }
+
+    #[cfg(unix)]
+    if binary.is_some() {
+        use std::os::unix::fs::PermissionsExt;
+        tokio::fs::set_permissions(output, std::fs::Permissions::from_mode(0o755)).await?;
+    }
+    Ok(())
+}
+
+/// how much of an executable to copy, leaving out a zip that is already attached instead of
+/// nesting them
+async fn executable_len(binary: &Path) -> Result<u64, Error> {
+    if let Ok(attached) = ZipFileReader::new(binary).await
+        && let Some(start) = attached
+            .file()
+            .entries()
+            .iter()
+            .map(StoredZipEntry::header_offset)
+            .min()
+    {
+        return Ok(start);
+    }
+    Ok(tokio::fs::metadata(binary).await?.len())
+}
+
+/// find every file in a directory, along with the nfc normalized name to store it as.
+///
+/// symlinks are followed, except to a directory we are already inside of, which would never end
+fn walk(
+    dir: &Path,
+    prefix: &str,
+    hidden: bool,
+    inside: &mut Vec<PathBuf>,
+    files: &mut Vec<(String, PathBuf)>,
+) -> Result<(), Error> {
+    let canonical = std::fs::canonicalize(dir)?;
+    if inside.contains(&canonical) {
+        return Err(Error::Loop(dir.display().to_string()));
+    }
+    inside.push(canonical);
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry
+            .file_name()
+            .into_string()
+            .map_err(|n| Error::NonUtf8Name(n.to_string_lossy().into_owned()))?;
+        // keep /.redgem, since it holds configuration rather than something to serve
+        if name.starts_with('.') && !hidden && !(prefix.is_empty() && name == ".redgem") {
+            continue;
+        }
+        let name = format!("{prefix}{}", name.nfc());
+        let path = entry.path();
+        if std::fs::metadata(&path)?.is_dir() {
+            walk(&path, &format!("{name}/"), hidden, inside, files)?;
+        } else {
+            files.push((name, path));
+        }
+    }
+    inside.pop();
+    Ok(())
+}
+
+/// whether a file is worth compressing, since compressing already compressed formats again only
+/// slows down serving them
+#[cfg(feature = "deflate")]
+fn compression(name: &str) -> Compression {
+    let ext = name
+        .rsplit_once('.')
+        .map(|(_, ext)| ext.to_ascii_lowercase());
+    match ext.as_deref() {
+        Some(
+            "7z" | "br" | "bz2" | "gif" | "gpub" | "gz" | "jpeg" | "jpg" | "mp3" | "mp4" | "ogg"
+            | "opus" | "png" | "webm" | "webp" | "xz" | "zip" | "zst" | "zstd",
+        ) => Compression::Stored,
+        _ => Compression::Deflate,
+    }
+}
+
+#[cfg(not(feature = "deflate"))]
+const fn compression(_name: &str) -> Compression {
+    Compression::Stored
+}
+
+/// zip up files into `out` one at a time, with modification dates no later than `latest`
+async fn build_zip<W: AsyncWrite + Unpin>(
+    files: &[(String, PathBuf)],
+    latest: Option<SystemTime>,
+    out: W,
+) -> Result<W, Error> {
+    // zip64 records are not handled by shift_offsets. this also rules out streaming each file
+    // into its entry, since async_zip then mixes up the sizes it records without zip64
+    let mut writer = ZipFileWriter::with_tokio(out).force_no_zip64();
+    for (name, path) in files {
+        let data = tokio::fs::read(path).await?;
+        let mut modified = tokio::fs::metadata(path).await?.modified()?;
+        if let Some(latest) = latest {
+            modified = modified.min(latest);
+        }
+        let entry = ZipEntryBuilder::new(name.as_str().into(), compression(name))
+            .last_modification_date(zip_date(modified));
+        // the level is fixed so that the same files always compress the same
+        #[cfg(feature = "deflate")]
+        let entry = entry.deflate_option(async_zip::DeflateOption::Normal);
+        writer.write_entry_whole(entry, &data).await?;
+    }
+    let mut out = writer.close().await?.into_inner();
+    out.flush().await?;
+    Ok(out)
+}
+
+/// zip up files that are already in memory, all dated `modified`
+pub async fn build_zip_from(
+    files: &[(String, Vec<u8>)],
+    modified: SystemTime,
+) -> Result<Vec<u8>, Error> {
+    let mut writer = ZipFileWriter::with_tokio(vec![]).force_no_zip64();
+    for (name, data) in files {
+        let entry = ZipEntryBuilder::new(name.as_str().into(), compression(name))
+            .last_modification_date(zip_date(modified));
+        writer.write_entry_whole(entry, data).await?;
+    }
+    Ok(writer.close().await?.into_inner())
+}
+
+/// convert a time into a zip timestamp in utc
+///
+/// see <https://howardhinnant.github.io/date_algorithms.html#civil_from_days>
+fn zip_date(time: SystemTime) -> ZipDateTime {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, secs) = (secs / 86400, secs % 86400);
+
+    let days = days + 719_468;
+    let era = days / 146_097;
+    let doe = days - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+
+    // zip timestamps cannot go before 1980
+    let year = i32::try_from(year).unwrap_or(i32::MAX).max(1980);
+    let to_u32 = |n: u64| u32::try_from(n).unwrap_or_default();
+    ZipDateTimeBuilder::new()
+        .year(year)
+        .month(to_u32(month))
+        .day(to_u32(day))
+        .hour(to_u32(secs / 3600))
+        .minute(to_u32(secs / 60 % 60))
+        .second(to_u32(secs % 60))
+        .build()
+}
+
+fn read_u16(buf: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(*buf.get(at..)?.first_chunk()?))
+}
+
+fn read_u32(buf: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(*buf.get(at..)?.first_chunk()?))
+}
+
+fn add_u32(buf: &mut [u8], at: usize, by: u32) -> Option<()> {
+    let new = read_u32(buf, at)?.checked_add(by)?;
+    buf.get_mut(at..at + 4)?.copy_from_slice(&new.to_le_bytes());
+    Some(())
+}
+
+/// shift every offset in a zip written without a comment or zip64 records, like `zip -A` does
+/// after other data gets put in front of it.
+///
+/// only the end of the zip is needed, from the start of its central directory
+fn shift_offsets(zip: &mut [u8], by: u64) -> Option<()> {
+    let by = u32::try_from(by).ok()?;
+    let eocd = zip.len().checked_sub(22)?;
+    if zip.get(eocd..eocd + 4)? != EOCD_SIGNATURE {
+        return None;
+    }
+    let entries = read_u16(zip, eocd + 10)?;
+    let mut pos = eocd.checked_sub(usize::try_from(read_u32(zip, eocd + 12)?).ok()?)?;
+    add_u32(zip, eocd + 16, by)?;
+
+    for _ in 0..entries {
+        if zip.get(pos..pos + 4)? != CENTRAL_SIGNATURE {
+            return None;
+        }
+        add_u32(zip, pos + 42, by)?;
+        let name_len = usize::from(read_u16(zip, pos + 28)?);
+        let extra_len = usize::from(read_u16(zip, pos + 30)?);
+        let comment_len = usize::from(read_u16(zip, pos + 32)?);
+        pos += 46 + name_len + extra_len + comment_len;
+    }
+    Some(())
+}
+
+/// shift the offsets of a zip at the end of a file, see [`shift_offsets`]
+async fn shift_file_offsets(file: &mut File, by: u64) -> Result<(), Error> {
+    let len = file.seek(SeekFrom::End(0)).await?;
+    let mut eocd = [0; 22];
+    file.seek(SeekFrom::Start(len.checked_sub(22).ok_or(Error::Offsets)?))
+        .await?;
+    file.read_exact(&mut eocd).await?;
+    let tail_len = u64::from(read_u32(&eocd, 12).ok_or(Error::Offsets)?) + 22;
+    let at = len.checked_sub(tail_len).ok_or(Error::Offsets)?;
+
+    let mut tail = vec![0; usize::try_from(tail_len).map_err(|_| Error::Offsets)?];
+    file.seek(SeekFrom::Start(at)).await?;
+    file.read_exact(&mut tail).await?;
+    shift_offsets(&mut tail, by).ok_or(Error::Offsets)?;
+    file.seek(SeekFrom::Start(at)).await?;
+    file.write_all(&tail).await?;
+    Ok(file.flush().await?)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::{Error, shift_offsets, write, zip_date};
+    use async_zip::{Compression, ZipEntryBuilder, base::write::ZipFileWriter};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    #[test]
+    fn dates() {
+        let date = zip_date(UNIX_EPOCH + Duration::from_hours(490_932));
+        assert_eq!(
+            (date.year(), date.month(), date.day(), date.hour()),
+            (2026, 1, 2, 12)
+        );
+        assert_eq!(zip_date(UNIX_EPOCH).year(), 1980);
+    }
+
+    #[tokio::test]
+    async fn offsets() {
+        let mut writer = ZipFileWriter::with_tokio(vec![]).force_no_zip64();
+        let entry = ZipEntryBuilder::new("index.gmi".into(), Compression::Stored);
+        writer.write_entry_whole(entry, b"meow\n").await.unwrap();
+        let mut zip = writer.close().await.unwrap().into_inner();
+
+        let mut exe = b"not really an executable".to_vec();
+        shift_offsets(&mut zip, u64::try_from(exe.len()).unwrap()).unwrap();
+        exe.append(&mut zip);
+
+        let zip = async_zip::base::read::mem::ZipFileReader::new(exe)
+            .await
+            .unwrap();
+        let mut text = String::new();
+        zip.reader_with_entry(0)
+            .await
+            .unwrap()
+            .read_to_string_checked(&mut text)
+            .await
+            .unwrap();
+        assert_eq!(text, "meow\n");
+    }
+
+    #[tokio::test]
+    async fn reproducible() {
+        let dir = std::env::temp_dir().join(format!("redgem-pack-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("in/sub")).unwrap();
+        std::fs::write(dir.join("in/index.gmi"), "# hi\n").unwrap();
+        std::fs::write(dir.join("in/sub/a.gmi"), "a\n".repeat(100)).unwrap();
+
+        let latest = Some(UNIX_EPOCH + Duration::from_hours(490_932));
+        write(&dir.join("in"), &dir.join("a.zip"), false, None, latest)
+            .await
+            .unwrap();
+        std::fs::File::options()
+            .write(true)
+            .open(dir.join("in/index.gmi"))
+            .unwrap()
+            .set_modified(SystemTime::now())
+            .unwrap();
+        write(&dir.join("in"), &dir.join("b.zip"), false, None, latest)
+            .await
+            .unwrap();
+        assert_eq!(
+            std::fs::read(dir.join("a.zip")).unwrap(),
+            std::fs::read(dir.join("b.zip")).unwrap()
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn attached() {
+        let dir = std::env::temp_dir().join(format!("redgem-attach-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("in")).unwrap();
+        std::fs::write(dir.join("in/index.gmi"), "# hi\n").unwrap();
+        std::fs::write(dir.join("exe"), "not really an executable").unwrap();
+
+        write(
+            &dir.join("in"),
+            &dir.join("a"),
+            false,
+            Some(&dir.join("exe")),
+            None,
+        )
+        .await
+        .unwrap();
+        std::fs::write(dir.join("in/index.gmi"), "# hello\n").unwrap();
+        // packing from an executable that already has a zip replaces it
+        write(
+            &dir.join("in"),
+            &dir.join("b"),
+            false,
+            Some(&dir.join("a")),
+            None,
+        )
+        .await
+        .unwrap();
+
+        let data = std::fs::read(dir.join("b")).unwrap();
+        assert!(data.starts_with(b"not really an executable"));
+        let zip = async_zip::tokio::read::fs::ZipFileReader::new(dir.join("b"))
+            .await
+            .unwrap();
+        assert_eq!(zip.file().entries().len(), 1);
+        let mut text = String::new();
+        zip.reader_with_entry(0)
+            .await
+            .unwrap()
+            .read_to_string_checked(&mut text)
+            .await
+            .unwrap();
+        assert_eq!(text, "# hello\n");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlink_loop() {
+        let dir = std::env::temp_dir().join(format!("redgem-loop-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("in/sub")).unwrap();
+        std::fs::write(dir.join("in/index.gmi"), "# hi\n").unwrap();
+        std::os::unix::fs::symlink("..", dir.join("in/sub/up")).unwrap();
+
+        assert!(matches!(
+            write(&dir.join("in"), &dir.join("a.zip"), false, None, None).await,
+            Err(Error::Loop(_))
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,74 @@
+//! a file holding our pid, locked for as long as we run so that a second copy refuses to start
+//! instead of fighting over the same listeners
+
+use std::{
+    fs::{File, OpenOptions},
+    io::ErrorKind,
+    os::{fd::AsRawFd, unix::fs::FileExt},
+    path::Path,
+};
+
+#[derive(Debug, foxerror::FoxError)]
+pub enum Error {
+    #[err(from)]
+    Io(std::io::Error),
+    /// another redgem is already running with it
+    Locked,
+}
+
+/// open and lock a pidfile, or take over the one of the process we are replacing.
+///
+/// the lock lasts until the returned file is closed, including in forked children
+pub fn lock(path: &Path) -> Result<File, Error> {
+    #[cfg(feature = "upgrade")]
+    if let Some(fd) = crate::upgrade::inherited_pidfile() {
+        return Ok(fd.into());
+    }
+
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    // SAFETY: flock on an fd we own
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } == -1 {
+        let err = std::io::Error::last_os_error();
+        return Err(if err.kind() == ErrorKind::WouldBlock {
+            Error::Locked
+        } else {
+            err.into()
+        });
+    }
+    Ok(file)
+}
+
+/// replace what is in a pidfile with our pid, once forking will not change it anymore
+pub fn write_pid(file: &File) -> std::io::Result<()> {
+    file.set_len(0)?;
+    file.write_all_at(format!("{}\n", std::process::id()).as_bytes(), 0)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::{Error, lock, write_pid};
+
+    #[test]
+    fn locked() {
+        let path = std::env::temp_dir().join(format!("redgem-pidfile-{}", std::process::id()));
+        std::fs::write(&path, "a much longer line than any pid\n").unwrap();
+        let file = lock(&path).unwrap();
+        assert!(matches!(lock(&path), Err(Error::Locked)));
+
+        write_pid(&file).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            format!("{}\n", std::process::id())
+        );
+
+        drop(file);
+        drop(lock(&path).unwrap());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,170 @@
+//! haproxy's proxy protocol, for finding out the real client address behind a load balancer
+//!
+//! see <https://www.haproxy.org/download/3.0/doc/proxy-protocol.txt>
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+const V1_MAX_LEN: usize = 107;
+
+#[derive(Debug, foxerror::FoxError)]
+pub enum Error {
+    #[err(from)]
+    Io(std::io::Error),
+    Missing,
+    Malformed,
+}
+
+/// read a proxy protocol header from the start of a stream.
+///
+/// this reads exactly as much as the header, so that the rest of the stream can be used like
+/// normal afterwards. returns `None` if the header does not convey a client address, such as for
+/// health checks from the proxy itself
+pub async fn read_header<S>(stream: &mut S) -> Result<Option<SocketAddr>, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    // the shortest valid header is `PROXY UNKNOWN\r\n`, which is still longer than this
+    let mut start = [0; 12];
+    stream.read_exact(&mut start).await?;
+
+    if &start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, start).await
+    } else {
+        Err(Error::Missing)
+    }
+}
+
+async fn read_v1<S>(stream: &mut S, start: [u8; 12]) -> Result<Option<SocketAddr>, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(Error::Malformed);
+        }
+        line.push(stream.read_u8().await?);
+    }
+
+    let line = str::from_utf8(&line[..line.len() - 2]).map_err(|_| Error::Malformed)?;
+    let mut parts = line.split(' ').skip(1);
+    match parts.next() {
+        Some("TCP4" | "TCP6") => (),
+        Some("UNKNOWN") => return Ok(None),
+        _ => return Err(Error::Malformed),
+    }
+    let (Some(src), Some(_dst), Some(port), Some(_dport), None) = (
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+        parts.next(),
+    ) else {
+        return Err(Error::Malformed);
+    };
+    let src: IpAddr = src.parse().map_err(|_| Error::Malformed)?;
+    let port = port.parse().map_err(|_| Error::Malformed)?;
+    Ok(Some(SocketAddr::new(src, port)))
+}
+
+async fn read_v2<S>(stream: &mut S) -> Result<Option<SocketAddr>, Error>
+where
+    S: AsyncRead + Unpin,
+{
+    let ver_cmd = stream.read_u8().await?;
+    let family = stream.read_u8().await?;
+    let len = stream.read_u16().await?;
+    let mut addrs = vec![0; len.into()];
+    stream.read_exact(&mut addrs).await?;
+
+    if ver_cmd >> 4 != 2 {
+        return Err(Error::Malformed);
+    }
+    match ver_cmd & 0xf {
+        // LOCAL, the proxy is connecting on its own behalf
+        0 => return Ok(None),
+        1 => (),
+        _ => return Err(Error::Malformed),
+    }
+
+    match family {
+        // TCP over IPv4
+        0x11 => {
+            let Some(&[a, b, c, d, _, _, _, _, p1, p2, ..]) = addrs.first_chunk::<12>() else {
+                return Err(Error::Malformed);
+            };
+            let ip = Ipv4Addr::new(a, b, c, d);
+            Ok(Some(SocketAddr::new(
+                ip.into(),
+                u16::from_be_bytes([p1, p2]),
+            )))
+        }
+        // TCP over IPv6
+        0x21 => {
+            let Some(chunk) = addrs.first_chunk::<36>() else {
+                return Err(Error::Malformed);
+            };
+            let ip: [u8; 16] = chunk[..16].try_into().map_err(|_| Error::Malformed)?;
+            let ip = Ipv6Addr::from(ip);
+            Ok(Some(SocketAddr::new(
+                ip.into(),
+                u16::from_be_bytes([chunk[32], chunk[33]]),
+            )))
+        }
+        _ => Ok(None),
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::read_header;
+
+    #[tokio::test]
+    async fn v1() {
+        let mut inp: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 1965\r\nmeow";
+        assert_eq!(
+            read_header(&mut inp).await.unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(inp, b"meow");
+
+        let mut inp: &[u8] = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 1965\r\n";
+        assert_eq!(
+            read_header(&mut inp).await.unwrap(),
+            Some("[2001:db8::1]:56324".parse().unwrap())
+        );
+
+        let mut inp: &[u8] = b"PROXY UNKNOWN\r\nmeow";
+        assert_eq!(read_header(&mut inp).await.unwrap(), None);
+        assert_eq!(inp, b"meow");
+
+        let mut inp: &[u8] = b"PROXY TCP4 192.0.2.1 198.51.100.1 56324\r\n";
+        assert!(read_header(&mut inp).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn v2() {
+        let mut inp: &[u8] = b"\r\n\r\n\0\r\nQUIT\n\x21\x11\0\x0c\
+            \xc0\0\x02\x01\xc6\x33\x64\x01\xdc\x04\x07\xadmeow";
+        assert_eq!(
+            read_header(&mut inp).await.unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        assert_eq!(inp, b"meow");
+
+        let mut inp: &[u8] = b"\r\n\r\n\0\r\nQUIT\n\x20\x00\0\0meow";
+        assert_eq!(read_header(&mut inp).await.unwrap(), None);
+        assert_eq!(inp, b"meow");
+    }
+
+    #[tokio::test]
+    async fn missing() {
+        let mut inp: &[u8] = b"\x16\x03\x01\x02\0\x01\0\x01\xfc\x03\x03\0";
+        assert!(read_header(&mut inp).await.is_err());
+    }
+}
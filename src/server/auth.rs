@@ -0,0 +1,231 @@
+//! restricting paths to certain client certificates
+
+use super::{Error, Zip, x509};
+use std::{
+    fmt::Write,
+    str::FromStr,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio_rustls::rustls::pki_types::CertificateDer;
+use unix_path::{Path, PathBuf};
+
+/// where to look for authorized fingerprints inside of the zip
+const AUTHORIZED_PATH: &[u8] = b".redgem/authorized";
+
+/// a path prefix that may only be accessed by a client certificate with a certain fingerprint
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rule {
+    prefix: PathBuf,
+    fingerprint: String,
+}
+
+impl Rule {
+    fn new(prefix: &str, fingerprint: &str) -> Self {
+        Self {
+            prefix: Path::new("/").join(prefix),
+            fingerprint: fingerprint.replace(':', "").to_ascii_lowercase(),
+        }
+    }
+}
+
+#[derive(Debug, foxerror::FoxError)]
+pub enum RuleError {
+    /// expected a path prefix and a fingerprint
+    MissingFingerprint,
+    /// fingerprint is not a hex-encoded sha-256 hash
+    BadFingerprint,
+    /// could not read the authorized file from the zip
+    #[err(from)]
+    Zip(async_zip::error::ZipError),
+    /// authorized file is not valid utf-8
+    NonUtf8,
+    /// bad rule on line
+    BadLine(usize),
+}
+
+impl std::fmt::Display for Rule {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let prefix = String::from_utf8_lossy(self.prefix.as_unix_str().as_bytes());
+        write!(f, "{prefix}={}", self.fingerprint)
+    }
+}
+
+impl FromStr for Rule {
+    type Err = RuleError;
+
+    /// parse a rule in the form of `PREFIX=FINGERPRINT`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, fingerprint) = s.split_once('=').ok_or(RuleError::MissingFingerprint)?;
+        let rule = Self::new(prefix, fingerprint);
+        if rule.fingerprint.len() != 64 || !rule.fingerprint.bytes().all(|b| b.is_ascii_hexdigit())
+        {
+            return Err(RuleError::BadFingerprint);
+        }
+        Ok(rule)
+    }
+}
+
+/// parse rules from a file, with a path prefix and fingerprint separated by whitespace on each
+/// line. empty lines and lines starting with `#` are ignored
+pub fn parse_rules(text: &str) -> Result<Vec<Rule>, RuleError> {
+    text.lines()
+        .enumerate()
+        .map(|(n, line)| (n, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(n, line)| {
+            line.split_once(char::is_whitespace)
+                .ok_or(RuleError::MissingFingerprint)
+                .and_then(|(prefix, fp)| format!("{prefix}={}", fp.trim()).parse())
+                .map_err(|_: RuleError| RuleError::BadLine(n + 1))
+        })
+        .collect()
+}
+
+/// read rules from `/.redgem/authorized` in the zip, if it exists
+pub async fn rules_from_zip(zip: &Zip) -> Result<Vec<Rule>, RuleError> {
+    let Some(id) = zip
+        .file()
+        .entries()
+        .iter()
+        .position(|e| e.filename().as_bytes() == AUTHORIZED_PATH)
+    else {
+        return Ok(vec![]);
+    };
+
+    let text = zip.read_checked(id).await?;
+    parse_rules(str::from_utf8(&text).map_err(|_| RuleError::NonUtf8)?)
+}
+
+/// hex-encoded sha-256 fingerprint of a certificate
+pub fn fingerprint(cert: &CertificateDer<'_>) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, cert);
+    digest
+        .as_ref()
+        .iter()
+        .fold(String::with_capacity(64), |mut out, b| {
+            _ = write!(out, "{b:02x}");
+            out
+        })
+}
+
+/// a certificate a client presented
+#[derive(Debug)]
+pub struct ClientCert {
+    fingerprint: String,
+    /// whether it is within its validity period, or has only expired when that is accepted
+    valid: bool,
+}
+
+impl ClientCert {
+    pub fn new(cert: &CertificateDer<'_>, accept_expired: bool) -> Self {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| i64::try_from(d.as_secs()).unwrap_or(i64::MAX));
+        let valid = x509::validity(cert)
+            .is_some_and(|v| v.not_before <= now && (accept_expired || now <= v.not_after));
+        Self {
+            fingerprint: fingerprint(cert),
+            valid,
+        }
+    }
+}
+
+/// check that a client presented a certificate that is currently valid
+pub(super) fn require(cert: Option<&ClientCert>) -> Result<&ClientCert, Error> {
+    let cert = cert.ok_or(Error::CertRequired)?;
+    if !cert.valid {
+        return Err(Error::CertNotValid);
+    }
+    Ok(cert)
+}
+
+/// check if a client may access a path.
+///
+/// only the rules with the longest prefix matching the path are considered
+pub(super) fn check(rules: &[Rule], path: &Path, cert: Option<&ClientCert>) -> Result<(), Error> {
+    let Some(longest) = rules
+        .iter()
+        .filter(|r| path.starts_with(&r.prefix))
+        .map(|r| r.prefix.iter().count())
+        .max()
+    else {
+        return Ok(());
+    };
+
+    let cert = require(cert)?;
+    if rules
+        .iter()
+        .filter(|r| r.prefix.iter().count() == longest && path.starts_with(&r.prefix))
+        .any(|r| r.fingerprint == cert.fingerprint)
+    {
+        Ok(())
+    } else {
+        Err(Error::CertNotAuthorized)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::{ClientCert, Error, Rule, check, parse_rules};
+    use unix_path::Path;
+
+    const A: &str = "bcc54e459fa8b2b51bc880e3405446004ed9e8dbbb1ffbeb56070356223a8eeb";
+    const B: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+    #[test]
+    fn parse() {
+        let rules = parse_rules(&format!(
+            "# comment\n\n/private {}\nmembers\t{B}\n",
+            A.to_uppercase()
+        ))
+        .unwrap();
+        assert_eq!(
+            rules,
+            [
+                format!("/private={A}").parse::<Rule>().unwrap(),
+                format!("/members={B}").parse::<Rule>().unwrap(),
+            ]
+        );
+        assert!(parse_rules("/private").is_err());
+        assert!(parse_rules("/private meow").is_err());
+    }
+
+    #[test]
+    fn longest_prefix() {
+        let rules = [
+            format!("/private={A}").parse::<Rule>().unwrap(),
+            format!("/private/b={B}").parse::<Rule>().unwrap(),
+        ];
+        assert_eq!(check(&rules, Path::new("/public"), None), Ok(()));
+        assert_eq!(check(&rules, Path::new("/privateer"), None), Ok(()));
+        assert_eq!(
+            check(&rules, Path::new("/private/a"), None),
+            Err(Error::CertRequired)
+        );
+        let cert = |fingerprint: &str, valid| ClientCert {
+            fingerprint: fingerprint.to_string(),
+            valid,
+        };
+        assert_eq!(
+            check(&rules, Path::new("/private/a"), Some(&cert(A, true))),
+            Ok(())
+        );
+        assert_eq!(
+            check(&rules, Path::new("/private/b/c"), Some(&cert(A, true))),
+            Err(Error::CertNotAuthorized)
+        );
+        assert_eq!(
+            check(&rules, Path::new("/private/b/c"), Some(&cert(B, true))),
+            Ok(())
+        );
+        assert_eq!(
+            check(&rules, Path::new("/private/b/c"), Some(&cert(B, false))),
+            Err(Error::CertNotValid)
+        );
+        assert_eq!(
+            check(&rules, Path::new("/public"), Some(&cert(B, false))),
+            Ok(())
+        );
+    }
+}
@@ -0,0 +1,141 @@
+//! looking for problems in a zip before serving it
+
+use super::{Index, Server, generate::encode_path, is_index_file, nfc};
+use fluent_uri::{IriRef, Uri};
+use std::collections::{BTreeMap, BTreeSet};
+use unix_path::{Path, PathBuf};
+use unix_str::UnixStr;
+
+#[derive(Debug, foxerror::FoxError)]
+pub enum Problem {
+    /// entry is corrupted or could not be decompressed
+    BadEntry(String),
+    /// several entries would be served at the same path
+    Collision(String),
+    /// unknown file extension, will be served as application/octet-stream
+    UnknownExtension(String),
+    /// directory does not have an index.gmi
+    MissingIndex(String),
+    /// link to a path that is not served
+    BrokenLink(String),
+}
+
+fn display(path: &Path) -> String {
+    String::from_utf8_lossy(path.as_unix_str().as_bytes()).into_owned()
+}
+
+impl Server {
+    /// look for problems in every entry that would be served
+    pub async fn check(&self) -> Vec<Problem> {
+        let mut problems = vec![];
+        let mut served = BTreeMap::<PathBuf, usize>::new();
+        let mut dirs = BTreeSet::new();
+        let Some(archive) = self.archive() else {
+            return problems;
+        };
+
+        for (i, entry) in archive.zip.file().entries().iter().enumerate() {
+            let Some(path) = self.config.entry_path(entry) else {
+                continue;
+            };
+
+            let mut parent = path.clone();
+            while parent.pop() {
+                dirs.insert(parent.clone());
+            }
+            if is_index_file(&path) {
+                let mut dir = path.clone();
+                dir.pop();
+                *served.entry(dir).or_default() += 1;
+            }
+            *served.entry(path.clone()).or_default() += 1;
+
+            let mimetype = self.config.mimetype(path.extension());
+            if mimetype.is_unknown() {
+                problems.push(Problem::UnknownExtension(display(&path)));
+            }
+
+            // only gemtext needs to be kept in memory, to look for links in
+            let checked = if mimetype.is_gemtext() {
+                archive.zip.read_checked(i).await.map(|body| {
+                    problems.extend(broken_links(&archive.index, &path, &body));
+                })
+            } else {
+                let mut sink = tokio::io::sink();
+                archive.zip.copy_checked(i, &mut sink).await.map(drop)
+            };
+            if checked.is_err() {
+                problems.push(Problem::BadEntry(display(&path)));
+            }
+        }
+
+        problems.extend(
+            served
+                .into_iter()
+                .filter(|(_, count)| *count > 1)
+                .map(|(path, _)| Problem::Collision(display(&path))),
+        );
+        problems.extend(
+            dirs.into_iter()
+                .filter(|dir| !archive.index.contains_key(dir))
+                .map(|dir| Problem::MissingIndex(display(&dir))),
+        );
+
+        problems
+    }
+}
+
+/// find links in a gemtext file to paths that are not served
+fn broken_links(index: &Index, path: &Path, text: &[u8]) -> Vec<Problem> {
+    let Ok(base) = Uri::parse(format!("gemini://localhost{}", encode_path(path))) else {
+        return vec![];
+    };
+
+    let mut preformatted = false;
+    let mut problems = vec![];
+    for line in String::from_utf8_lossy(text).lines() {
+        if line.starts_with("```") {
+            preformatted = !preformatted;
+            continue;
+        }
+        if preformatted {
+            continue;
+        }
+        let Some(link) = line
+            .strip_prefix("=>")
+            .and_then(|l| l.split_whitespace().next())
+        else {
+            continue;
+        };
+        if !link_exists(index, &base, link) {
+            problems.push(Problem::BrokenLink(format!(
+                "{} links to {link}",
+                display(path)
+            )));
+        }
+    }
+    problems
+}
+
+/// whether a link points to something that is served.
+///
+/// links to other hosts are assumed to be fine
+fn link_exists(index: &Index, base: &Uri<String>, link: &str) -> bool {
+    let Ok(link) = IriRef::parse(link) else {
+        return false;
+    };
+    let link = link.to_uri_ref();
+    if link.has_scheme() || link.authority().is_some() {
+        return true;
+    }
+    let Ok(target) = link.resolve_against(base) else {
+        return false;
+    };
+
+    let bytes = target.path().decode().to_bytes();
+    let trailing = bytes.is_empty() || bytes.ends_with(b"/");
+    let path = Path::new("/").join(UnixStr::from_bytes(&nfc(&bytes)));
+    index
+        .get(&path)
+        .is_some_and(|(_, is_index)| *is_index || !trailing)
+}
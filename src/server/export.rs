@@ -0,0 +1,53 @@
+//! extracting every entry of a zip into a directory, for getting at the files of a packed
+//! capsule without an unzip tool that understands zips attached to executables
+
+use super::{Zip, entry_name, is_unreachable};
+use async_zip::error::ZipError;
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
+
+#[derive(Debug, foxerror::FoxError)]
+pub enum ExportError {
+    #[err(from)]
+    Io(std::io::Error),
+    #[err(from)]
+    Zip(ZipError),
+}
+
+impl Zip {
+    /// write every entry into `dir`, returning how many files were written and how many entries
+    /// were left out for pointing outside of it
+    pub async fn export(&self, dir: &Path) -> Result<(usize, usize), ExportError> {
+        let (mut written, mut skipped) = (0, 0);
+        tokio::fs::create_dir_all(dir).await?;
+        for (i, entry) in self.file().entries().iter().enumerate() {
+            let name = entry_name(entry);
+            if is_unreachable(&name) {
+                skipped += 1;
+                continue;
+            }
+            let name = String::from_utf8_lossy(&name);
+            let path = dir.join(name.trim_start_matches('/'));
+            if entry.dir()? {
+                tokio::fs::create_dir_all(&path).await?;
+                continue;
+            }
+            if let Some(parent) = path.parent() {
+                tokio::fs::create_dir_all(parent).await?;
+            }
+            let mut file = tokio::fs::File::create(&path).await?;
+            self.copy_checked(i, &mut file).await?;
+            file.flush().await?;
+
+            // zips from elsewhere often have no permissions at all, rather than unreadable files
+            #[cfg(unix)]
+            if let Some(mode) = entry.unix_permissions().filter(|mode| mode & 0o777 != 0) {
+                use std::os::unix::fs::PermissionsExt;
+                let perms = std::fs::Permissions::from_mode(u32::from(mode) & 0o777);
+                tokio::fs::set_permissions(&path, perms).await?;
+            }
+            written += 1;
+        }
+        Ok((written, skipped))
+    }
+}
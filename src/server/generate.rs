@@ -0,0 +1,325 @@
+//! gemtext pages generated from the contents of the index
+
+use super::{Index, Source, Zip};
+use async_zip::{StoredZipEntry, ZipDateTime, error::ZipError};
+use fluent_uri::pct_enc::{EStr, EString, encoder};
+use std::fmt::Write;
+use unix_path::{Path, PathBuf};
+use unix_str::UnixStr;
+
+/// format a zip timestamp like `2023-05-01 12:00`
+pub fn format_date(date: ZipDateTime) -> String {
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}",
+        date.year(),
+        date.month(),
+        date.day(),
+        date.hour(),
+        date.minute()
+    )
+}
+
+/// percent-encode a path so that it can be used in a link line
+pub fn encode_path(path: &Path) -> String {
+    let mut buf = EString::<encoder::Path>::new();
+    for chunk in path.as_unix_str().as_bytes().utf8_chunks() {
+        buf.encode_str::<encoder::Path>(chunk.valid());
+        for &x in chunk.invalid() {
+            buf.push_estr(EStr::encode_byte(x));
+        }
+    }
+    buf.into_string()
+}
+
+/// split a gemlog post's file name into its date and a title
+///
+/// posts are expected to be named like `2023-05-01-some-title.gmi`
+pub fn post_date(name: &UnixStr) -> Option<(&str, String)> {
+    let name = name.to_str()?;
+    let date = name.get(..10)?;
+    let valid = date.bytes().enumerate().all(|(i, b)| match i {
+        4 | 7 => b == b'-',
+        _ => b.is_ascii_digit(),
+    });
+    if !valid {
+        return None;
+    }
+
+    let rest = &name[10..];
+    let rest = rest.rsplit_once('.').map_or(rest, |(stem, _)| stem);
+    let title = rest
+        .trim_start_matches(['-', '_', ' '])
+        .replace(['-', '_'], " ");
+    Some((date, title))
+}
+
+/// find every gemlog post directly inside of `dir`, newest first, except for the ones `hidden`
+/// is true for
+pub fn posts<'a>(
+    index: &'a Index,
+    dir: &Path,
+    hidden: impl Fn(&Path) -> bool,
+) -> Vec<(&'a Path, &'a str, String)> {
+    let mut posts: Vec<_> = index
+        .iter()
+        .filter(|(path, (source, is_index))| {
+            !is_index
+                && matches!(source, Source::Zip(_))
+                && path.parent() == Some(dir)
+                && !hidden(path)
+        })
+        .filter_map(|(path, _)| {
+            let (date, title) = post_date(path.file_name()?)?;
+            Some((path.as_path(), date, title))
+        })
+        .collect();
+    posts.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+    posts
+}
+
+fn gemlog_name(dir: &Path) -> String {
+    dir.file_name().map_or_else(
+        || "gemlog".into(),
+        |n| String::from_utf8_lossy(n.as_bytes()).into_owned(),
+    )
+}
+
+fn write_posts(out: &mut String, posts: &[(&Path, &str, String)]) {
+    for (path, date, title) in posts {
+        if title.is_empty() {
+            _ = writeln!(out, "=> {} {date}", encode_path(path));
+        } else {
+            _ = writeln!(out, "=> {} {date} {title}", encode_path(path));
+        }
+    }
+}
+
+/// a subscribable gemtext page listing gemlog posts, followed by links to the archive pages
+///
+/// see <gemini://geminiprotocol.net/docs/companion/subscription.gmi>
+pub fn gemlog(index: &Index, dir: &Path, hidden: impl Fn(&Path) -> bool) -> Vec<u8> {
+    let mut out = format!("# {}\n\n", gemlog_name(dir));
+    let posts = posts(index, dir, hidden);
+    write_posts(&mut out, &posts);
+
+    if !posts.is_empty() {
+        out.push_str("\n## archive\n\n");
+        // posts are newest first, so every year is one run of them
+        for year in posts.chunk_by(|a, b| a.1[..4] == b.1[..4]) {
+            let year = &year[0].1[..4];
+            _ = writeln!(out, "=> {}/ {year}", encode_path(&dir.join(year)));
+        }
+    }
+
+    out.into_bytes()
+}
+
+/// subscribable gemtext pages grouping gemlog posts by year and by month, along with the
+/// directories they are served as
+pub fn archives(
+    index: &Index,
+    dir: &Path,
+    hidden: impl Fn(&Path) -> bool,
+) -> Vec<(PathBuf, Vec<u8>)> {
+    let name = gemlog_name(dir);
+    let posts = posts(index, dir, hidden);
+    let mut pages = vec![];
+
+    for posts in posts.chunk_by(|a, b| a.1[..4] == b.1[..4]) {
+        let year = &posts[0].1[..4];
+        let year_dir = dir.join(year);
+        let mut out = format!("# {name} {year}\n\n");
+        for posts in posts.chunk_by(|a, b| a.1[..7] == b.1[..7]) {
+            let month = &posts[0].1[5..7];
+            let month_dir = year_dir.join(month);
+            _ = writeln!(out, "=> {}/ {year}-{month}", encode_path(&month_dir));
+
+            let mut page = format!("# {name} {year}-{month}\n\n");
+            write_posts(&mut page, posts);
+            pages.push((month_dir, page.into_bytes()));
+        }
+        out.push('\n');
+        write_posts(&mut out, posts);
+        pages.push((year_dir, out.into_bytes()));
+    }
+
+    pages
+}
+
+/// a gemtext page listing every file in the index, along with their sizes and modification times,
+/// except for the ones `hidden` is true for
+pub fn sitemap(
+    index: &Index,
+    entries: &[StoredZipEntry],
+    hidden: impl Fn(&Path) -> bool,
+) -> Vec<u8> {
+    let mut out = "# sitemap\n\n".to_string();
+
+    for (path, (source, is_index)) in index {
+        let Source::Zip(id) = source else {
+            continue;
+        };
+        let Some(entry) = entries.get(*id) else {
+            continue;
+        };
+        if *is_index || hidden(path) {
+            continue;
+        }
+        let encoded = encode_path(path);
+        _ = writeln!(
+            out,
+            "=> {encoded} {encoded} ({} bytes, {})",
+            entry.uncompressed_size(),
+            format_date(*entry.last_modification_date())
+        );
+    }
+
+    out.into_bytes()
+}
+
+/// a gemtext page listing every file in the index, most recently modified first, except for the
+/// ones `hidden` is true for
+///
+/// the dates start the link text, so it can be subscribed to like a gemlog
+pub fn changes(
+    index: &Index,
+    entries: &[StoredZipEntry],
+    hidden: impl Fn(&Path) -> bool,
+) -> Vec<u8> {
+    let mut changed: Vec<_> = index
+        .iter()
+        .filter(|(path, (_, is_index))| !is_index && !hidden(path))
+        .filter_map(|(path, (source, _))| match source {
+            Source::Zip(id) => Some((path, *entries.get(*id)?.last_modification_date())),
+            Source::Generated(_) | Source::Checksums => None,
+        })
+        .collect();
+    let key = |d: &ZipDateTime| {
+        (
+            d.year(),
+            d.month(),
+            d.day(),
+            d.hour(),
+            d.minute(),
+            d.second(),
+        )
+    };
+    changed.sort_by(|a, b| key(&b.1).cmp(&key(&a.1)).then_with(|| a.0.cmp(b.0)));
+
+    let mut out = "# recent changes\n\n".to_string();
+    for (path, date) in changed {
+        let encoded = encode_path(path);
+        _ = writeln!(out, "=> {encoded} {} {encoded}", format_date(date));
+    }
+
+    out.into_bytes()
+}
+
+/// a robots.txt keeping archivers and indexers out of denied paths
+///
+/// only patterns containing a `/` become rules, as the part before their first wildcard, since
+/// robots.txt can only disallow path prefixes. see
+/// <gemini://geminiprotocol.net/docs/companion/robots.gmi>
+pub fn robots(deny: &[String]) -> Vec<u8> {
+    let mut out = "User-agent: archiver\nUser-agent: indexer\n".to_string();
+    let mut prefixes = vec![];
+    for pat in deny.iter().filter(|pat| pat.contains('/')) {
+        let pat = pat.strip_prefix('/').unwrap_or(pat);
+        let prefix = pat.split(['*', '?']).next().unwrap_or_default();
+        if !prefix.is_empty() && !prefixes.contains(&prefix) {
+            prefixes.push(prefix);
+        }
+    }
+
+    if prefixes.is_empty() {
+        out.push_str("Disallow:\n");
+    }
+    for prefix in prefixes {
+        _ = writeln!(
+            out,
+            "Disallow: {}",
+            encode_path(&Path::new("/").join(prefix))
+        );
+    }
+
+    out.into_bytes()
+}
+
+/// a listing in the format of `sha256sum` of every file in the index, read from the zip,
+/// except for the ones `hidden` is true for
+pub async fn checksums(
+    zip: &Zip,
+    index: &Index,
+    hidden: impl Fn(&Path) -> bool,
+) -> Result<Vec<u8>, ZipError> {
+    let mut out = String::new();
+    for (path, (source, is_index)) in index {
+        let Source::Zip(id) = source else {
+            continue;
+        };
+        if *is_index || hidden(path) {
+            continue;
+        }
+        let name = String::from_utf8_lossy(path.as_unix_str().as_bytes());
+        let name = name.trim_start_matches('/');
+        // sha256sum marks lines with escaped names with a leading \
+        if name.contains(['\\', '\n', '\r']) {
+            out.push('\\');
+        }
+        for b in zip.sha256(*id).await?.as_ref() {
+            _ = write!(out, "{b:02x}");
+        }
+        out.push_str("  ");
+        for c in name.chars() {
+            match c {
+                '\\' => out.push_str("\\\\"),
+                '\n' => out.push_str("\\n"),
+                '\r' => out.push_str("\\r"),
+                c => out.push(c),
+            }
+        }
+        out.push('\n');
+    }
+    Ok(out.into_bytes())
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::{post_date, robots};
+    use unix_str::UnixStr;
+
+    #[test]
+    fn robots_rules() {
+        let deny = [
+            "*.bak",
+            "/drafts",
+            "private/**",
+            "/**/secret/*",
+            "/private/*.gmi",
+        ];
+        assert_eq!(
+            robots(&deny.map(String::from)),
+            b"User-agent: archiver\nUser-agent: indexer\n\
+            Disallow: /drafts\nDisallow: /private/\n"
+        );
+        assert_eq!(
+            robots(&["*.bak".to_string()]),
+            b"User-agent: archiver\nUser-agent: indexer\nDisallow:\n"
+        );
+    }
+
+    #[test]
+    fn dates() {
+        assert_eq!(
+            post_date(UnixStr::from_bytes(b"2023-05-01-hello_world.gmi")).unwrap(),
+            ("2023-05-01", "hello world".to_string())
+        );
+        assert_eq!(
+            post_date(UnixStr::from_bytes(b"2023-05-01.gmi")).unwrap(),
+            ("2023-05-01", String::new())
+        );
+        assert!(post_date(UnixStr::from_bytes(b"2023-5-01-nope.gmi")).is_none());
+        assert!(post_date(UnixStr::from_bytes(b"about.gmi")).is_none());
+    }
+}
@@ -0,0 +1,81 @@
+//! tiny glob matching for path deny rules
+
+use unix_path::Path;
+
+fn glob(pat: &[u8], s: &[u8]) -> bool {
+    // whether pat[i..] matches s[j..] for each i and j tried, so that patterns with many stars
+    // do not retry the same suffixes over and over
+    let mut memo = vec![None; (pat.len() + 1) * (s.len() + 1)];
+    glob_from(pat, s, 0, 0, &mut memo)
+}
+
+fn glob_from(pat: &[u8], s: &[u8], i: usize, j: usize, memo: &mut [Option<bool>]) -> bool {
+    let key = i * (s.len() + 1) + j;
+    if let Some(matched) = memo[key] {
+        return matched;
+    }
+    let matched = match &pat[i..] {
+        [] => j == s.len(),
+        [b'*', b'*', rest @ ..] => {
+            // let `**/` match zero directories
+            (rest.first() == Some(&b'/') && glob_from(pat, s, i + 3, j, memo))
+                || (j..=s.len()).any(|k| glob_from(pat, s, i + 2, k, memo))
+        }
+        [b'*', ..] => (j..=s.len())
+            .take_while(|&k| k == j || s[k - 1] != b'/')
+            .any(|k| glob_from(pat, s, i + 1, k, memo)),
+        [b'?', ..] => s.get(j).is_some_and(|&c| c != b'/') && glob_from(pat, s, i + 1, j + 1, memo),
+        [c, ..] => s.get(j) == Some(c) && glob_from(pat, s, i + 1, j + 1, memo),
+    };
+    memo[key] = Some(matched);
+    matched
+}
+
+/// check if a path matches a glob pattern
+///
+/// `*` and `?` do not match across `/`, while `**` does. patterns containing a `/` are matched
+/// against the whole path, otherwise they are matched against each component of it
+pub fn matches(pattern: &str, path: &Path) -> bool {
+    let pat = pattern.as_bytes();
+    if pat.contains(&b'/') {
+        let full = path.as_unix_str().as_bytes();
+        let pat = pat.strip_prefix(b"/").unwrap_or(pat);
+        glob(pat, full.strip_prefix(b"/").unwrap_or(full))
+    } else {
+        path.iter().any(|c| glob(pat, c.as_bytes()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::matches;
+    use unix_path::Path;
+
+    #[test]
+    fn component() {
+        assert!(matches("*.key", Path::new("/tls/gemini.key")));
+        assert!(matches("*.key", Path::new("/gemini.key")));
+        assert!(matches("secret", Path::new("/secret/notes.gmi")));
+        assert!(matches("?.gmi", Path::new("/a.gmi")));
+        assert!(!matches("?.gmi", Path::new("/ab.gmi")));
+        assert!(!matches("*.key", Path::new("/gemini.key.gmi")));
+    }
+
+    #[test]
+    fn anchored() {
+        assert!(matches("/private/**", Path::new("/private/a/b.gmi")));
+        assert!(matches("private/**", Path::new("/private/a.gmi")));
+        assert!(matches("/**/drafts/*", Path::new("/drafts/a.gmi")));
+        assert!(matches("/**/drafts/*", Path::new("/gemlog/drafts/a.gmi")));
+        assert!(!matches("/private/*", Path::new("/private/a/b.gmi")));
+        assert!(!matches("/private/**", Path::new("/public/private/a.gmi")));
+    }
+
+    #[test]
+    fn many_stars() {
+        let path = format!("/{}", "a".repeat(200));
+        let pattern = format!("/{}b", "**a".repeat(20));
+        assert!(!matches(&pattern, Path::new(&path)));
+        assert!(matches(&format!("/{}", "*a".repeat(20)), Path::new(&path)));
+    }
+}
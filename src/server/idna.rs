@@ -0,0 +1,128 @@
+//! just enough of idna to compare hostnames
+//!
+//! this does not do the full uts 46 mapping, only lowercasing and punycode (rfc 3492), which
+//! covers the forms that clients actually disagree about
+
+const BASE: u32 = 36;
+const TMIN: u32 = 1;
+const TMAX: u32 = 26;
+const SKEW: u32 = 38;
+const DAMP: u32 = 700;
+const INITIAL_BIAS: u32 = 72;
+const INITIAL_N: u32 = 128;
+
+const fn adapt(mut delta: u32, numpoints: u32, first: bool) -> u32 {
+    delta /= if first { DAMP } else { 2 };
+    delta += delta / numpoints;
+    let mut k = 0;
+    while delta > ((BASE - TMIN) * TMAX) / 2 {
+        delta /= BASE - TMIN;
+        k += BASE;
+    }
+    k + (BASE - TMIN + 1) * delta / (delta + SKEW)
+}
+
+const fn threshold(k: u32, bias: u32) -> u32 {
+    if k <= bias {
+        TMIN
+    } else if k >= bias + TMAX {
+        TMAX
+    } else {
+        k - bias
+    }
+}
+
+/// punycode digits go a-z then 0-9, which is base 36 rotated by 10
+const fn digit(d: u32) -> char {
+    match char::from_digit((d + 10) % BASE, BASE) {
+        Some(c) => c,
+        None => panic!("punycode digit should be below base"),
+    }
+}
+
+/// encode a single label with punycode, without the `xn--` prefix
+fn encode(label: &str) -> Option<String> {
+    let chars: Vec<u32> = label.chars().map(u32::from).collect();
+    let mut out: String = label.chars().filter(char::is_ascii).collect();
+    let basic = u32::try_from(out.len()).ok()?;
+    let total = u32::try_from(chars.len()).ok()?;
+    if basic > 0 {
+        out.push('-');
+    }
+
+    let mut code = INITIAL_N;
+    let mut delta: u32 = 0;
+    let mut bias = INITIAL_BIAS;
+    let mut handled = basic;
+
+    while handled < total {
+        let next = chars.iter().copied().filter(|&c| c >= code).min()?;
+        delta = delta.checked_add((next - code).checked_mul(handled + 1)?)?;
+        code = next;
+        for &c in &chars {
+            if c < code {
+                delta = delta.checked_add(1)?;
+            }
+            if c == code {
+                let mut rem = delta;
+                let mut k = BASE;
+                loop {
+                    let t = threshold(k, bias);
+                    if rem < t {
+                        break;
+                    }
+                    out.push(digit(t + (rem - t) % (BASE - t)));
+                    rem = (rem - t) / (BASE - t);
+                    k += BASE;
+                }
+                out.push(digit(rem));
+                bias = adapt(delta, handled + 1, handled == basic);
+                delta = 0;
+                handled += 1;
+            }
+        }
+        delta = delta.checked_add(1)?;
+        code += 1;
+    }
+
+    Some(out)
+}
+
+/// convert a hostname into its lowercase ascii form, punycoding any unicode labels
+pub fn to_ascii(host: &str) -> Option<String> {
+    let labels = host
+        .split('.')
+        .map(|label| {
+            let label = label.to_lowercase();
+            if label.is_ascii() {
+                Some(label)
+            } else {
+                encode(&label).map(|e| format!("xn--{e}"))
+            }
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(labels.join("."))
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::to_ascii;
+
+    #[test]
+    fn punycode() {
+        for (unicode, ascii) in [
+            ("bücher.example", "xn--bcher-kva.example"),
+            ("例え.テスト", "xn--r8jz45g.xn--zckzah"),
+            ("münchen.de", "xn--mnchen-3ya.de"),
+            ("example.com", "example.com"),
+        ] {
+            assert_eq!(to_ascii(unicode).unwrap(), ascii);
+        }
+    }
+
+    #[test]
+    fn lowercase() {
+        assert_eq!(to_ascii("BÜCHER.Example").unwrap(), "xn--bcher-kva.example");
+    }
+}
@@ -0,0 +1,120 @@
+//! remembering which certificates the servers we relay to use, like ssh's `known_hosts`
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    io::ErrorKind,
+    path::PathBuf,
+    sync::{Mutex, PoisonError},
+};
+
+#[derive(Debug, foxerror::FoxError)]
+pub enum KnownHostsError {
+    /// could not read known hosts file
+    #[err(from)]
+    Io(std::io::Error),
+    /// bad known host on line
+    BadLine(usize),
+}
+
+/// certificate fingerprints of hosts, trusted on first use
+#[derive(Debug)]
+pub struct KnownHosts {
+    /// where to save hosts to, if they should outlive us
+    path: Option<PathBuf>,
+    /// whether to trust a host's new certificate when it changes, instead of refusing it
+    accept_new: bool,
+    hosts: Mutex<BTreeMap<String, String>>,
+}
+
+impl KnownHosts {
+    /// read known hosts from a file, if it exists
+    pub fn load(path: Option<PathBuf>, accept_new: bool) -> Result<Self, KnownHostsError> {
+        let hosts = match path.as_ref().map(std::fs::read_to_string) {
+            Some(Ok(text)) => parse(&text)?,
+            Some(Err(e)) if e.kind() != ErrorKind::NotFound => return Err(e.into()),
+            _ => BTreeMap::new(),
+        };
+        Ok(Self {
+            path,
+            accept_new,
+            hosts: Mutex::new(hosts),
+        })
+    }
+
+    /// whether a host may be trusted with a certificate, remembering it if it is the first one
+    /// we have seen for the host
+    pub fn check(&self, host: &str, fingerprint: &str) -> bool {
+        let mut hosts = self.hosts.lock().unwrap_or_else(PoisonError::into_inner);
+        match hosts.get(host) {
+            Some(known) if known == fingerprint => return true,
+            Some(known) if !self.accept_new => {
+                eprintln!("certificate for {host} changed from {known} to {fingerprint}, refusing");
+                return false;
+            }
+            Some(_) => eprintln!("certificate for {host} changed to {fingerprint}, accepting"),
+            None => (),
+        }
+        hosts.insert(host.to_string(), fingerprint.to_string());
+
+        if let Some(path) = &self.path {
+            let text = hosts.iter().fold(String::new(), |mut out, (host, fp)| {
+                _ = writeln!(out, "{host} {fp}");
+                out
+            });
+            drop(hosts);
+            // replace the file in one go, so a crash cannot leave half of it behind
+            let tmp = path.with_extension("tmp");
+            if let Err(e) = std::fs::write(&tmp, text).and_then(|()| std::fs::rename(&tmp, path)) {
+                eprintln!("could not save known hosts: {e}");
+            }
+        }
+        true
+    }
+}
+
+/// parse known hosts, with a host and a fingerprint separated by whitespace on each line. empty
+/// lines and lines starting with `#` are ignored
+fn parse(text: &str) -> Result<BTreeMap<String, String>, KnownHostsError> {
+    text.lines()
+        .enumerate()
+        .map(|(n, line)| (n, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(n, line)| {
+            line.split_once(char::is_whitespace)
+                .map(|(host, fp)| (host.to_string(), fp.trim().to_string()))
+                .ok_or(KnownHostsError::BadLine(n + 1))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::{KnownHosts, parse};
+    use std::sync::Mutex;
+
+    #[test]
+    fn trust_on_first_use() {
+        let known = KnownHosts {
+            path: None,
+            accept_new: false,
+            hosts: Mutex::new(parse("# comment\n\nexample.com aaaa\n").unwrap()),
+        };
+        assert!(known.check("example.com", "aaaa"));
+        assert!(!known.check("example.com", "bbbb"));
+        assert!(known.check("example.org", "bbbb"));
+        assert!(known.check("example.org", "bbbb"));
+        assert!(!known.check("example.org", "aaaa"));
+
+        let known = KnownHosts {
+            accept_new: true,
+            ..known
+        };
+        assert!(known.check("example.org", "aaaa"));
+        assert!(known.check("example.org", "aaaa"));
+        assert!(known.check("example.org", "bbbb"));
+
+        assert!(parse("example.com").is_err());
+    }
+}
@@ -0,0 +1,47 @@
+//! listing what would be served, for debugging why something is not
+
+use super::{Server, Source, entry_name, generate::encode_path};
+
+impl Server {
+    /// describe every path in the index, one per line.
+    ///
+    /// each line has the path, its guessed type, its compressed and uncompressed sizes, and the
+    /// entry it is an alias for if it is a directory index, separated by tabs
+    pub fn list(&self) -> Vec<String> {
+        let Some(archive) = self.archive() else {
+            return vec![];
+        };
+        let entries = archive.zip.file().entries();
+        archive
+            .index
+            .iter()
+            .map(|(path, (source, is_index))| {
+                let mut url = encode_path(path);
+                if *is_index && !url.ends_with('/') {
+                    url.push('/');
+                }
+                let mimetype =
+                    self.config
+                        .mimetype(if *is_index { None } else { path.extension() });
+                let (compressed, uncompressed, name) = match source {
+                    Source::Zip(id) => entries.get(*id).map_or_else(
+                        || ("-".into(), "-".into(), String::new()),
+                        |entry| {
+                            (
+                                entry.compressed_size().to_string(),
+                                entry.uncompressed_size().to_string(),
+                                String::from_utf8_lossy(&entry_name(entry)).into_owned(),
+                            )
+                        },
+                    ),
+                    Source::Generated(page) => {
+                        ("-".into(), page.len().to_string(), "(generated)".into())
+                    }
+                    Source::Checksums => ("-".into(), "-".into(), "(generated)".into()),
+                };
+                let alias = if *is_index { name.as_str() } else { "-" };
+                format!("{url}\t{mimetype}\t{compressed}\t{uncompressed}\t{alias}")
+            })
+            .collect()
+    }
+}
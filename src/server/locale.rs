@@ -0,0 +1,116 @@
+//! error texts in other languages, read from the zip
+
+use super::{ErrorMeta, Zip};
+
+#[derive(Debug, foxerror::FoxError)]
+pub enum LocaleError {
+    /// could not read the error texts from the zip
+    #[err(from)]
+    Zip(async_zip::error::ZipError),
+    /// error texts are not valid utf-8
+    NonUtf8,
+    /// bad error text on line
+    BadLine(usize),
+}
+
+/// where to look for the error texts of a language inside of the zip
+fn bundle_path(lang: &str) -> String {
+    format!(".redgem/errors.{lang}.toml")
+}
+
+/// read error texts for a language from `/.redgem/errors.LANG.toml` in the zip, if it exists
+pub async fn error_meta_from_zip(zip: &Zip, lang: &str) -> Result<Vec<ErrorMeta>, LocaleError> {
+    let path = bundle_path(lang);
+    let Some(id) = zip
+        .file()
+        .entries()
+        .iter()
+        .position(|e| e.filename().as_bytes() == path.as_bytes())
+    else {
+        return Ok(vec![]);
+    };
+
+    let text = zip.read_checked(id).await?;
+    parse_bundle(str::from_utf8(&text).map_err(|_| LocaleError::NonUtf8)?)
+}
+
+/// parse error texts from a flat toml table, with a status code as each key and the text to use
+/// as its string value
+pub fn parse_bundle(text: &str) -> Result<Vec<ErrorMeta>, LocaleError> {
+    text.lines()
+        .enumerate()
+        .map(|(n, line)| (n, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(n, line)| parse_line(line).ok_or(LocaleError::BadLine(n + 1)))
+        .collect()
+}
+
+fn parse_line(line: &str) -> Option<ErrorMeta> {
+    let (key, value) = line.split_once('=')?;
+    let key = key.trim();
+    let key = key
+        .strip_prefix('"')
+        .and_then(|k| k.strip_suffix('"'))
+        .unwrap_or(key);
+    let (value, rest) = parse_string(value.trim_start())?;
+    let rest = rest.trim_start();
+    if !rest.is_empty() && !rest.starts_with('#') {
+        return None;
+    }
+    format!("{key}={value}").parse().ok()
+}
+
+/// parse a toml basic or literal string, returning it and what comes after
+fn parse_string(text: &str) -> Option<(String, &str)> {
+    if let Some(literal) = text.strip_prefix('\'') {
+        let (value, rest) = literal.split_once('\'')?;
+        return Some((value.to_string(), rest));
+    }
+
+    let mut chars = text.strip_prefix('"')?.char_indices();
+    let mut value = String::new();
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Some((value, &text[i + 2..])),
+            '\\' => value.push(match chars.next()?.1 {
+                '"' => '"',
+                '\\' => '\\',
+                't' => '\t',
+                'u' => {
+                    let hex: String = chars.by_ref().take(4).map(|(_, c)| c).collect();
+                    char::from_u32(u32::from_str_radix(&hex, 16).ok()?)?
+                }
+                _ => return None,
+            }),
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::parse_bundle;
+
+    #[test]
+    fn parse() {
+        let bundle = parse_bundle(
+            "# erreurs\n51 = \"introuvable\"\n\"40\" = 'd\u{e9}lai \"d\u{e9}pass\u{e9}\"' # ok\n\
+             53 = \"h\\u00f4te \\\"inconnu\\\"\"\n",
+        )
+        .unwrap();
+        assert_eq!(
+            bundle,
+            [
+                "51=introuvable".parse().unwrap(),
+                "40=d\u{e9}lai \"d\u{e9}pass\u{e9}\"".parse().unwrap(),
+                "53=h\u{f4}te \"inconnu\"".parse().unwrap(),
+            ]
+        );
+        assert!(parse_bundle("51 = introuvable").is_err());
+        assert!(parse_bundle("51 = \"introuvable").is_err());
+        assert!(parse_bundle("20 = \"ok\"").is_err());
+        assert!(parse_bundle("51 = \"a\" b").is_err());
+    }
+}
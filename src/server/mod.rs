@@ -1,21 +1,50 @@
+use crate::metrics::Metrics;
 use async_zip::{
-    base::read::{WithEntry, ZipEntryReader},
-    tokio::read::fs::ZipFileReader,
+    Compression, StringEncoding, ZipEntry, ZipFile,
+    base::read::{WithEntry, ZipEntryReader, mem},
+    error::ZipError,
+    tokio::read::fs,
+};
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    io::{Cursor, SeekFrom},
+    sync::{Arc, PoisonError, RwLock},
+    time::Duration,
 };
-use std::{collections::BTreeMap, time::Duration};
 use tokio::{
     fs::File,
-    io::{AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader, copy},
-    net::TcpStream,
+    io::{
+        AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt, AsyncWrite, AsyncWriteExt, BufReader,
+        Take,
+    },
     time::timeout,
 };
-use tokio_rustls::server::TlsStream;
-use tokio_util::compat::{Compat, FuturesAsyncReadCompatExt};
-use unix_path::{Path, PathBuf};
+use tokio_rustls::{rustls::pki_types::CertificateDer, server::TlsStream};
+use tokio_util::{
+    compat::{Compat, FuturesAsyncReadCompatExt},
+    either::Either,
+};
+use unicode_normalization::UnicodeNormalization;
+use unix_path::{Component, Path, PathBuf};
 use unix_str::UnixStr;
 
+pub mod auth;
+pub mod check;
+mod cp437;
+mod export;
+mod generate;
+mod glob;
+mod idna;
+pub mod known_hosts;
+mod list;
+mod locale;
+mod relay;
 mod request;
 mod response;
+mod x509;
+
+pub use relay::allowed_authority;
 
 #[derive(Debug, Eq, PartialEq, foxerror::FoxError)]
 enum Error {
@@ -34,6 +63,13 @@ enum Error {
     BadEntry,
     Timeout,
     UriBuild,
+    CertRequired,
+    CertNotAuthorized,
+    CertNotValid,
+    TooLarge,
+    BadRange,
+    ProxyFailed,
+    Loading,
 }
 
 impl Error {
@@ -52,68 +88,1043 @@ impl Error {
             Self::BadEntry => b"40 failed to open zip entry\r\n",
             Self::Timeout => b"40 timed out\r\n",
             Self::UriBuild => b"40 failed to build uri\r\n",
+            Self::CertRequired => b"60 client certificate required\r\n",
+            Self::CertNotAuthorized => b"61 certificate not authorized\r\n",
+            Self::CertNotValid => b"62 certificate not valid\r\n",
+            Self::TooLarge => b"50 file too large to serve\r\n",
+            Self::BadRange => b"59 cannot serve that byte range\r\n",
+            Self::ProxyFailed => b"43 could not reach upstream server\r\n",
+            Self::Loading => b"41 index building, try again soon\r\n",
         }
     }
+
+    /// the two digit status code
+    const fn status(&self) -> u8 {
+        let bytes = self.bytes();
+        (bytes[0] - b'0') * 10 + (bytes[1] - b'0')
+    }
 }
 
-pub struct Server {
-    zip: ZipFileReader,
-    index: BTreeMap<PathBuf, (usize, bool)>,
+/// what happened on a connection
+#[derive(Debug)]
+pub struct Outcome {
+    /// the url that was requested, if a valid one arrived
+    pub url: Option<String>,
+    /// status code of the response
+    pub status: u8,
+    /// bytes of the response that were sent, including the header
+    pub bytes: u64,
+    /// why the request was refused, if it was the client's fault
+    pub rejection: Option<Rejection>,
 }
 
-impl Server {
-    pub fn from_zip(zip: ZipFileReader) -> Self {
+/// why a request was refused before it got to looking up a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    /// the request did not arrive in time
+    Timeout,
+    /// the request did not fit in 1024 bytes
+    TooLong,
+    /// the request was not a valid gemini url
+    Malformed,
+    /// the request was for another scheme or host, such as a client trying to use us as a proxy
+    Misdirected,
+}
+
+impl Rejection {
+    const fn from_error(e: &Error) -> Option<Self> {
+        match e {
+            Error::Timeout => Some(Self::Timeout),
+            Error::RequestTooLong => Some(Self::TooLong),
+            Error::NonUtf8(_)
+            | Error::UnparseableUri
+            | Error::NoAuthority
+            | Error::Userinfo
+            | Error::HasFragment => Some(Self::Malformed),
+            Error::NonGeminiScheme | Error::SniMismatch => Some(Self::Misdirected),
+            _ => None,
+        }
+    }
+
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Timeout => "request_timeout",
+            Self::TooLong => "request_too_long",
+            Self::Malformed => "malformed_request",
+            Self::Misdirected => "misdirected_request",
+        }
+    }
+}
+
+/// replacement text for the failures with a status code
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorMeta {
+    status: u8,
+    meta: String,
+}
+
+#[derive(Debug, foxerror::FoxError)]
+pub enum ErrorMetaError {
+    /// expected a status code and the text to use
+    MissingMeta,
+    /// not a failure status code
+    BadStatus,
+    /// text must be a single line of at most 1024 bytes
+    BadMeta,
+}
+
+impl std::fmt::Display for ErrorMeta {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.status, self.meta)
+    }
+}
+
+impl std::str::FromStr for ErrorMeta {
+    type Err = ErrorMetaError;
+
+    /// parse an override in the form of `STATUS=TEXT`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (status, meta) = s.split_once('=').ok_or(ErrorMetaError::MissingMeta)?;
+        let status = status.parse().map_err(|_| ErrorMetaError::BadStatus)?;
+        if !(40..=69).contains(&status) {
+            return Err(ErrorMetaError::BadStatus);
+        }
+        if meta.is_empty() || meta.len() > 1024 || meta.contains(['\r', '\n']) {
+            return Err(ErrorMetaError::BadMeta);
+        }
+        Ok(Self {
+            status,
+            meta: meta.to_string(),
+        })
+    }
+}
+
+/// a type to serve files with an extension as, instead of the built-in one
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MimeOverride {
+    ext: String,
+    mimetype: response::MimeType,
+}
+
+#[derive(Debug, foxerror::FoxError)]
+pub enum MimeOverrideError {
+    /// expected an extension and a type
+    MissingType,
+    /// type must be in the form of TYPE/SUBTYPE
+    BadType,
+}
+
+impl std::fmt::Display for MimeOverride {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.ext, self.mimetype)
+    }
+}
+
+impl std::str::FromStr for MimeOverride {
+    type Err = MimeOverrideError;
+
+    /// parse an override in the form of `EXT=TYPE/SUBTYPE`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (ext, mimetype) = s.split_once('=').ok_or(MimeOverrideError::MissingType)?;
+        let ext = ext.strip_prefix('.').unwrap_or(ext);
+        let mimetype = response::MimeType::parse(mimetype).ok_or(MimeOverrideError::BadType)?;
+        Ok(Self {
+            ext: ext.to_ascii_lowercase(),
+            mimetype,
+        })
+    }
+}
+
+/// a url to redirect every request to, keeping their path
+#[derive(Debug, Clone)]
+pub struct RedirectTarget(request::Request);
+
+#[derive(Debug, foxerror::FoxError)]
+pub enum RedirectTargetError {
+    /// expected a gemini url without a query or fragment
+    Invalid,
+}
+
+impl std::fmt::Display for RedirectTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.0.as_str())
+    }
+}
+
+impl std::str::FromStr for RedirectTarget {
+    type Err = RedirectTargetError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        request::Request::parse(s.as_bytes(), None)
+            .map(Self)
+            .map_err(|_| RedirectTargetError::Invalid)
+    }
+}
+
+/// which entry to serve when several in the zip have the same name
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum DuplicatePolicy {
+    First,
+    #[default]
+    Last,
+    /// refuse to load the zip at all
+    Error,
+}
+
+#[derive(Debug, foxerror::FoxError)]
+pub enum DuplicatePolicyError {
+    /// expected first, last, or error
+    Unknown,
+}
+
+impl std::fmt::Display for DuplicatePolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::First => "first",
+            Self::Last => "last",
+            Self::Error => "error",
+        })
+    }
+}
+
+impl std::str::FromStr for DuplicatePolicy {
+    type Err = DuplicatePolicyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first" => Ok(Self::First),
+            "last" => Ok(Self::Last),
+            "error" => Ok(Self::Error),
+            _ => Err(DuplicatePolicyError::Unknown),
+        }
+    }
+}
+
+/// where the contents of an [`Index`] entry come from
+#[derive(Debug, Clone)]
+enum Source {
+    /// an entry in the zip file
+    Zip(usize),
+    /// a page generated while building the index
+    Generated(Arc<[u8]>),
+    /// the sha-256 of every file, generated the first time it is asked for
+    Checksums,
+}
+
+/// map of paths to their contents, and whether they are a directory index
+type Index = BTreeMap<PathBuf, (Source, bool)>;
+
+/// a reader for an entry in a zip on disk
+type FileEntry<'a> = ZipEntryReader<'a, Compat<BufReader<File>>, WithEntry<'a>>;
+
+/// a reader for an entry in a zip in memory
+type MemoryEntry<'a> = ZipEntryReader<'a, futures_lite::io::Cursor<&'a [u8]>, WithEntry<'a>>;
+
+/// a reader for an entry in either kind of zip
+type Entry<'a> = Either<Compat<FileEntry<'a>>, Compat<MemoryEntry<'a>>>;
+
+/// a reader for part of an entry, positioned at the start of that part
+type Ranged<'a> = Take<Either<Entry<'a>, Either<File, Cursor<&'a [u8]>>>>;
+
+/// the contents of a file, part of a file, or generated page
+type Contents<'a> = Either<Either<Entry<'a>, Ranged<'a>>, Cursor<Arc<[u8]>>>;
+
+/// the body of a successful response, followed by the footer for gemtext
+type Body<'a> = response::OptionalChain<Contents<'a>, Cursor<Arc<[u8]>>>;
+
+/// what the local header of each zip entry starts with, `PK\x03\x04`
+const LOCAL_SIGNATURE: u32 = 0x0403_4b50;
+
+/// move to `start` bytes into the data of the stored entry whose local header is at `offset`
+async fn seek_to_data<R: AsyncRead + AsyncSeek + Unpin>(
+    reader: &mut R,
+    offset: u64,
+    start: u64,
+) -> Result<(), ZipError> {
+    reader.seek(SeekFrom::Start(offset)).await?;
+    let mut header = [0; 30];
+    reader.read_exact(&mut header).await?;
+    let signature = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    if signature != LOCAL_SIGNATURE {
+        return Err(ZipError::UnexpectedHeaderError(signature, LOCAL_SIGNATURE));
+    }
+    // the lengths in the local header can differ from the ones in the central directory
+    let name_len = u16::from_le_bytes([header[26], header[27]]);
+    let extra_len = u16::from_le_bytes([header[28], header[29]]);
+    let data = offset + 30 + u64::from(name_len) + u64::from(extra_len);
+    reader.seek(SeekFrom::Start(data + start)).await?;
+    Ok(())
+}
+
+/// a zip to serve files from
+pub enum Zip {
+    /// read from disk as needed
+    File(fs::ZipFileReader),
+    /// held entirely in memory, such as when read from stdin
+    Memory(mem::ZipFileReader),
+}
+
+impl From<fs::ZipFileReader> for Zip {
+    fn from(zip: fs::ZipFileReader) -> Self {
+        Self::File(zip)
+    }
+}
+
+impl From<mem::ZipFileReader> for Zip {
+    fn from(zip: mem::ZipFileReader) -> Self {
+        Self::Memory(zip)
+    }
+}
+
+impl Zip {
+    fn file(&self) -> &ZipFile {
+        match self {
+            Self::File(zip) => zip.file(),
+            Self::Memory(zip) => zip.file(),
+        }
+    }
+
+    /// start reading an entry
+    async fn entry(&self, id: usize) -> Result<Entry<'_>, ZipError> {
+        Ok(match self {
+            Self::File(zip) => Either::Left(zip.reader_with_entry(id).await?.compat()),
+            Self::Memory(zip) => Either::Right(zip.reader_with_entry(id).await?.compat()),
+        })
+    }
+
+    /// read up to `len` bytes from the start of an entry
+    async fn read_start(&self, id: usize, len: u64) -> Result<Vec<u8>, ZipError> {
+        let mut out = vec![];
+        self.entry(id)
+            .await?
+            .take(len)
+            .read_to_end(&mut out)
+            .await?;
+        Ok(out)
+    }
+
+    /// start reading `len` bytes of an entry from `start`, seeking straight there when the entry
+    /// is stored uncompressed and reading through everything before it otherwise
+    async fn range(&self, id: usize, start: u64, len: u64) -> Result<Ranged<'_>, ZipError> {
+        let entry = self
+            .file()
+            .entries()
+            .get(id)
+            .ok_or(ZipError::EntryIndexOutOfBounds)?;
+        if entry.compression() != Compression::Stored {
+            let mut reader = self.entry(id).await?;
+            tokio::io::copy(&mut (&mut reader).take(start), &mut tokio::io::sink()).await?;
+            return Ok(Either::Left(reader).take(len));
+        }
+
+        let offset = entry.header_offset();
+        let raw = match self {
+            Self::File(zip) => {
+                let mut file = File::open(zip.path()).await?;
+                seek_to_data(&mut file, offset, start).await?;
+                Either::Left(file)
+            }
+            Self::Memory(zip) => {
+                let mut cursor = Cursor::new(zip.data());
+                seek_to_data(&mut cursor, offset, start).await?;
+                Either::Right(cursor)
+            }
+        };
+        Ok(Either::Right(raw).take(len))
+    }
+
+    /// the sha-256 digest of the contents of an entry
+    async fn sha256(&self, id: usize) -> Result<ring::digest::Digest, ZipError> {
+        let mut entry = self.entry(id).await?;
+        let mut context = ring::digest::Context::new(&ring::digest::SHA256);
+        let mut buf = vec![0; DEFAULT_BUFFER_SIZE];
+        loop {
+            match entry.read(&mut buf).await? {
+                0 => return Ok(context.finish()),
+                n => context.update(&buf[..n]),
+            }
+        }
+    }
+
+    /// every file entry by its decoded name, along with its index, crc, and uncompressed size
+    pub fn files(&self) -> BTreeMap<String, (usize, u32, u64)> {
+        self.file()
+            .entries()
+            .iter()
+            .enumerate()
+            .filter(|(_, entry)| !entry.dir().unwrap_or_default())
+            .map(|(i, entry)| {
+                let name = String::from_utf8_lossy(&entry_name(entry)).into_owned();
+                (name, (i, entry.crc32(), entry.uncompressed_size()))
+            })
+            .collect()
+    }
+
+    /// read an entry entirely into memory, checking its crc
+    pub async fn read_checked(&self, id: usize) -> Result<Vec<u8>, ZipError> {
+        let mut out = vec![];
+        self.copy_checked(id, &mut out).await?;
+        Ok(out)
+    }
+
+    /// copy an entry to a writer a bit at a time, checking its crc once all of it has been read.
+    /// what was written before a bad crc is found is left as is
+    pub async fn copy_checked<W>(&self, id: usize, out: &mut W) -> Result<u64, ZipError>
+    where
+        W: AsyncWrite + Unpin + ?Sized,
+    {
+        let (written, crc, expected) = match self {
+            Self::File(zip) => {
+                let mut entry = zip.reader_with_entry(id).await?.compat();
+                let written = tokio::io::copy(&mut entry, out).await?;
+                let entry = entry.get_mut();
+                (written, entry.compute_hash(), entry.entry().crc32())
+            }
+            Self::Memory(zip) => {
+                let mut entry = zip.reader_with_entry(id).await?.compat();
+                let written = tokio::io::copy(&mut entry, out).await?;
+                let entry = entry.get_mut();
+                (written, entry.compute_hash(), entry.entry().crc32())
+            }
+        };
+        if crc != expected {
+            return Err(ZipError::CRC32CheckError);
+        }
+        Ok(written)
+    }
+}
+
+/// how much of a response to read before sending it on, by default.
+///
+/// reads this large skip past the buffer that zip entries are read from on disk, so this is also
+/// the size files are read from disk in
+pub const DEFAULT_BUFFER_SIZE: usize = 8192;
+
+/// the smallest buffer responses are sent through. an empty one would never read anything
+pub const MIN_BUFFER_SIZE: usize = 1024;
+
+/// options that change how a [`Server`] responds to requests
+#[derive(Debug, Default, Clone)]
+#[allow(clippy::struct_excessive_bools, reason = "they come from switches")]
+pub struct Config {
+    /// hostname to redirect requests that are addressed to an ip literal to
+    pub canonical_host: Option<String>,
+    /// directory to generate a subscribable gemlog index for, along with archive pages for each
+    /// year and month
+    pub gemlog: Option<String>,
+    /// whether to generate `/sitemap.gmi`
+    pub sitemap: bool,
+    /// whether to generate `/changes.gmi`
+    pub changes: bool,
+    /// emoji to serve as `/favicon.txt`
+    pub favicon: Option<String>,
+    /// whether to generate `/robots.txt` from the deny rules
+    pub robots: bool,
+    /// whether to generate `/sha256sums.txt` when it is first asked for
+    pub checksums: bool,
+    /// whether to serve paths with a component starting with `.`
+    pub serve_hidden: bool,
+    /// which entry to serve when several would be served at the same path
+    pub duplicates: DuplicatePolicy,
+    /// whether to serve entries whose unix mode is missing the world readable bit
+    pub ignore_permissions: bool,
+    /// whether to serve `/PATH.gmi` at `/PATH` too, when there is nothing else there
+    pub clean_urls: bool,
+    /// glob patterns of paths to never serve
+    pub deny: Vec<String>,
+    /// client certificates allowed to access path prefixes
+    pub authorized: Vec<auth::Rule>,
+    /// types to use for extensions instead of the built-in ones
+    pub mime: Vec<MimeOverride>,
+    /// gemtext appended to every text/gemini response
+    pub footer: Option<Arc<[u8]>>,
+    /// size in bytes of the buffer responses are sent through, [`DEFAULT_BUFFER_SIZE`] if unset
+    /// and at least [`MIN_BUFFER_SIZE`]
+    pub buffer_size: Option<usize>,
+    /// how long a client may take to finish the tls handshake, 10 seconds if unset
+    pub handshake_timeout: Option<Duration>,
+    /// how long a client may take to send its request, 30 seconds if unset
+    pub header_timeout: Option<Duration>,
+    /// how long sending a response may take in total, ten minutes if unset
+    pub response_timeout: Option<Duration>,
+    /// how long sending a response may go without reading or writing anything
+    pub idle_timeout: Option<Duration>,
+    /// size in bytes above which files are refused instead of served
+    pub max_file_size: Option<u64>,
+    /// whether files that are not text can be asked for in parts with `?bytes=START-END`
+    pub byte_ranges: bool,
+    /// text to use instead of the default for failures
+    pub error_meta: Vec<ErrorMeta>,
+    /// language to read error texts from `/.redgem/errors.LANG.toml` for
+    pub lang: Option<String>,
+    /// languages to serve variants of files in, like `/page.fr.gmi` for `/page.gmi`, most
+    /// preferred first
+    pub lang_pref: Vec<String>,
+    /// whether to let expired client certificates through, for when they are only used as an
+    /// identity
+    pub accept_expired_certs: bool,
+    /// whether every request needs a client certificate, not just ones for restricted paths
+    pub require_client_cert: bool,
+    /// url to redirect every request to instead of serving anything
+    pub redirect_all_to: Option<RedirectTarget>,
+    /// whether to look at the start of files with unknown extensions to guess their type
+    pub sniff_types: bool,
+    /// whether to look at the start of text files to tell which legacy charset they use, if
+    /// they are not utf-8
+    pub detect_charset: bool,
+    /// hosts to relay requests for to, acting as a gemini proxy
+    pub proxy_allow: Vec<String>,
+    /// file to remember the certificates of hosts we relay to in
+    pub known_hosts: Option<std::path::PathBuf>,
+    /// whether to trust a relayed host's certificate when it changes
+    pub tofu_accept_new: bool,
+}
+
+impl Config {
+    fn buffer_size(&self) -> usize {
+        self.buffer_size
+            .unwrap_or(DEFAULT_BUFFER_SIZE)
+            .max(MIN_BUFFER_SIZE)
+    }
+
+    fn handshake_timeout(&self) -> Duration {
+        self.handshake_timeout.unwrap_or(Duration::from_secs(10))
+    }
+
+    fn header_timeout(&self) -> Duration {
+        self.header_timeout.unwrap_or(Duration::from_secs(30))
+    }
+
+    fn response_timeout(&self) -> Duration {
+        self.response_timeout.unwrap_or(Duration::from_mins(10))
+    }
+
+    fn is_denied(&self, path: &Path) -> bool {
+        self.deny.iter().any(|pat| glob::matches(pat, path))
+    }
+
+    /// guess a file's type from its extension, preferring the configured overrides
+    fn mimetype(&self, ext: Option<&UnixStr>) -> response::MimeType {
+        ext.and_then(|ext| {
+            self.mime
+                .iter()
+                .rfind(|o| o.ext.as_bytes().eq_ignore_ascii_case(ext.as_bytes()))
+        })
+        .map_or_else(
+            || response::MimeType::from_extension(ext),
+            |o| o.mimetype.clone(),
+        )
+    }
+
+    /// a successful response, followed by the footer if there is one and it is gemtext
+    fn respond_with_footer<'a>(
+        &self,
+        mimetype: response::MimeType,
+        body: Contents<'a>,
+    ) -> response::Response<Body<'a>> {
+        let body = match &self.footer {
+            Some(footer) if mimetype.is_gemtext() => {
+                response::OptionalChain::chain(body, Cursor::new(footer.clone()))
+            }
+            _ => response::OptionalChain::single(body),
+        };
+        response::Response::with_type(mimetype, body)
+    }
+
+    /// the variant of a file in the most preferred language that has one, along with that
+    /// language
+    fn lang_variant(&self, index: &Index, path: &Path) -> Option<(PathBuf, &str)> {
+        let ext = path.extension()?;
+        self.lang_pref.iter().find_map(|lang| {
+            let mut variant_ext = lang.as_bytes().to_vec();
+            variant_ext.push(b'.');
+            variant_ext.extend_from_slice(ext.as_bytes());
+            let variant = path.with_extension(UnixStr::from_bytes(&variant_ext));
+            index
+                .contains_key(&variant)
+                .then_some((variant, lang.as_str()))
+        })
+    }
+
+    /// the path a zip entry would be served at, if it should be served at all
+    fn entry_path(&self, entry: &ZipEntry) -> Option<PathBuf> {
+        let name = entry_name(entry);
+        if name.iter().last().is_some_and(|&b| b == b'/') || is_unreachable(&name) {
+            return None;
+        }
+        // a mode of 0 means the archiver did not record one at all
+        if !self.ignore_permissions
+            && entry
+                .unix_permissions()
+                .is_some_and(|mode| mode != 0 && mode & 0o004 == 0)
+        {
+            return None;
+        }
+        let path = Path::new("/").join(UnixStr::from_bytes(&nfc(&name)));
+        if !self.serve_hidden && path.iter().any(|c| c.as_bytes().starts_with(b".")) {
+            return None;
+        }
+        if self.is_denied(&path) {
+            return None;
+        }
+        Some(path)
+    }
+}
+
+/// the settings with their defaults filled in, as `name: value` lines named after the options
+/// that set them. repeated settings get a line each
+impl std::fmt::Display for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn or_none(value: Option<impl std::fmt::Display>) -> String {
+            value.map_or_else(|| "none".to_string(), |v| v.to_string())
+        }
+        fn secs(time: Duration) -> String {
+            if time == Duration::MAX {
+                "none".to_string()
+            } else {
+                format!("{}s", time.as_secs())
+            }
+        }
+
+        writeln!(
+            f,
+            "canonical-host: {}",
+            or_none(self.canonical_host.as_ref())
+        )?;
+        writeln!(
+            f,
+            "redirect-all-to: {}",
+            or_none(self.redirect_all_to.as_ref())
+        )?;
+        writeln!(f, "gemlog: {}", or_none(self.gemlog.as_ref()))?;
+        writeln!(f, "sitemap: {}", self.sitemap)?;
+        writeln!(f, "changes: {}", self.changes)?;
+        writeln!(f, "favicon: {}", or_none(self.favicon.as_ref()))?;
+        writeln!(f, "robots: {}", self.robots)?;
+        writeln!(f, "checksums: {}", self.checksums)?;
+        writeln!(f, "serve-hidden: {}", self.serve_hidden)?;
+        writeln!(f, "ignore-permissions: {}", self.ignore_permissions)?;
+        writeln!(f, "duplicates: {}", self.duplicates)?;
+        writeln!(f, "clean-urls: {}", self.clean_urls)?;
+        for pat in &self.deny {
+            writeln!(f, "deny: {pat}")?;
+        }
+        for rule in &self.authorized {
+            writeln!(f, "authorized: {rule}")?;
+        }
+        writeln!(f, "accept-expired-certs: {}", self.accept_expired_certs)?;
+        writeln!(f, "require-client-cert: {}", self.require_client_cert)?;
+        for o in &self.mime {
+            writeln!(f, "mime: {o}")?;
+        }
+        writeln!(f, "sniff-types: {}", self.sniff_types)?;
+        writeln!(f, "detect-charset: {}", self.detect_charset)?;
+        let footer = self.footer.as_ref().map(|b| format!("{} bytes", b.len()));
+        writeln!(f, "footer: {}", or_none(footer))?;
+        writeln!(f, "lang: {}", or_none(self.lang.as_ref()))?;
+        for lang in &self.lang_pref {
+            writeln!(f, "lang-pref: {lang}")?;
+        }
+        for meta in &self.error_meta {
+            writeln!(f, "error-meta: {meta}")?;
+        }
+        writeln!(f, "max-file-size: {}", or_none(self.max_file_size))?;
+        writeln!(f, "byte-ranges: {}", self.byte_ranges)?;
+        writeln!(f, "buffer-size: {}", self.buffer_size())?;
+        writeln!(f, "handshake-timeout: {}", secs(self.handshake_timeout()))?;
+        writeln!(f, "header-timeout: {}", secs(self.header_timeout()))?;
+        writeln!(f, "response-timeout: {}", secs(self.response_timeout()))?;
+        writeln!(f, "idle-timeout: {}", or_none(self.idle_timeout.map(secs)))?;
+        for host in &self.proxy_allow {
+            writeln!(f, "proxy-allow: {host}")?;
+        }
+        let known_hosts = self.known_hosts.as_ref().map(|p| p.display());
+        writeln!(f, "known-hosts: {}", or_none(known_hosts))?;
+        writeln!(f, "tofu-accept-new: {}", self.tofu_accept_new)
+    }
+}
+
+/// the name of a zip entry, decoded to utf-8.
+///
+/// names without the utf-8 flag are supposed to be cp437, but plenty of archivers write utf-8
+/// without setting it, so only names that are not valid utf-8 get decoded
+fn entry_name(entry: &ZipEntry) -> Cow<'_, [u8]> {
+    let name = entry.filename();
+    match name.encoding() {
+        StringEncoding::Raw if str::from_utf8(name.as_bytes()).is_err() => {
+            Cow::Owned(cp437::decode(name.as_bytes()).into_bytes())
+        }
+        _ => Cow::Borrowed(name.as_bytes()),
+    }
+}
+
+/// whether a zip entry name could never be requested, since it goes up a directory
+fn is_unreachable(name: &[u8]) -> bool {
+    Path::new(UnixStr::from_bytes(name))
+        .components()
+        .any(|c| c == Component::ParentDir)
+}
+
+/// normalize the utf-8 parts of a path to nfc, so that names from systems that use nfd, like
+/// macos, still match links typed in nfc
+fn nfc(bytes: &[u8]) -> Vec<u8> {
+    if bytes.is_ascii() {
+        return bytes.to_vec();
+    }
+    let mut out = Vec::with_capacity(bytes.len());
+    for chunk in bytes.utf8_chunks() {
+        out.extend_from_slice(chunk.valid().nfc().collect::<String>().as_bytes());
+        out.extend_from_slice(chunk.invalid());
+    }
+    out
+}
+
+/// whether a path is a directory's index file
+fn is_index_file(path: &Path) -> bool {
+    path.file_name()
+        .map(UnixStr::as_bytes)
+        .is_some_and(|n| n == b"index.gmi")
+}
+
+/// a zip and everything read from it, swapped out as a whole when reloading
+struct Archive {
+    zip: Zip,
+    index: Index,
+    /// the configured rules, along with the ones from `/.redgem/authorized`
+    authorized: Vec<auth::Rule>,
+    /// error texts for the configured language, followed by the configured ones
+    error_meta: Vec<ErrorMeta>,
+    /// `/sha256sums.txt`, once something has asked for it
+    checksums: tokio::sync::OnceCell<Arc<[u8]>>,
+}
+
+#[derive(Debug, foxerror::FoxError)]
+pub enum LoadError {
+    /// could not read /.redgem/authorized from zip
+    #[err(from)]
+    Authorized(auth::RuleError),
+    /// could not read error texts from zip
+    #[err(from)]
+    Locale(locale::LocaleError),
+    /// could not read known hosts
+    #[err(from)]
+    KnownHosts(known_hosts::KnownHostsError),
+    /// several entries in zip would be served at
+    Duplicate(String),
+}
+
+impl Archive {
+    /// index a zip, reading the files in `/.redgem` that apply to it
+    async fn load(zip: Zip, config: &Config) -> Result<Self, LoadError> {
+        let mut authorized = auth::rules_from_zip(&zip).await?;
+        authorized.extend_from_slice(&config.authorized);
+        let mut error_meta = match &config.lang {
+            Some(lang) => locale::error_meta_from_zip(&zip, lang).await?,
+            None => vec![],
+        };
+        error_meta.extend_from_slice(&config.error_meta);
+
         let mut index = BTreeMap::new();
 
         for (i, entry) in zip.file().entries().iter().enumerate() {
-            let path = entry.filename().as_bytes();
-            if path.iter().last().is_some_and(|&b| b == b'/') {
+            let name = entry_name(entry);
+            if is_unreachable(&name) {
+                eprintln!(
+                    "{} goes outside of the zip and can not be served",
+                    String::from_utf8_lossy(&name)
+                );
                 continue;
             }
-            let path = Path::new("/").join(UnixStr::from_bytes(path));
+            let Some(path) = config.entry_path(entry) else {
+                continue;
+            };
 
-            if path
-                .file_name()
-                .map(UnixStr::as_bytes)
-                .is_some_and(|n| n == b"index.gmi")
-            {
+            if is_index_file(&path) {
                 let mut newpath = path.clone();
                 newpath.pop();
-                index.insert(newpath, (i, true));
+                insert_entry(
+                    &mut index,
+                    newpath,
+                    (Source::Zip(i), true),
+                    config.duplicates,
+                )?;
+            }
+
+            insert_entry(&mut index, path, (Source::Zip(i), false), config.duplicates)?;
+        }
+
+        // the generated listings are shared by every client, so they leave out whatever needs a
+        // client certificate
+        let hidden =
+            |path: &Path| config.is_denied(path) || auth::check(&authorized, path, None).is_err();
+
+        if let Some(gemlog) = &config.gemlog {
+            let dir = Path::new("/").join(gemlog);
+            for (path, page) in generate::archives(&index, &dir, hidden) {
+                index
+                    .entry(path)
+                    .or_insert_with(|| (Source::Generated(page.into()), true));
+            }
+            if !index.contains_key(&dir) {
+                let page = generate::gemlog(&index, &dir, hidden);
+                index.insert(dir, (Source::Generated(page.into()), true));
+            }
+        }
+
+        if config.sitemap {
+            let path = PathBuf::from("/sitemap.gmi");
+            if !index.contains_key(&path) {
+                let page = generate::sitemap(&index, zip.file().entries(), hidden);
+                index.insert(path, (Source::Generated(page.into()), false));
             }
+        }
+
+        if config.changes {
+            let path = PathBuf::from("/changes.gmi");
+            if !index.contains_key(&path) {
+                let page = generate::changes(&index, zip.file().entries(), hidden);
+                index.insert(path, (Source::Generated(page.into()), false));
+            }
+        }
+
+        if config.robots {
+            index
+                .entry(PathBuf::from("/robots.txt"))
+                .or_insert_with(|| {
+                    (
+                        Source::Generated(generate::robots(&config.deny).into()),
+                        false,
+                    )
+                });
+        }
+
+        if config.checksums {
+            index
+                .entry(PathBuf::from("/sha256sums.txt"))
+                .or_insert((Source::Checksums, false));
+        }
+
+        // see <gemini://mozz.us/files/rfc_gemini_favicon.gmi>
+        if let Some(favicon) = &config.favicon {
+            let page = favicon.as_bytes();
+            index
+                .entry(PathBuf::from("/favicon.txt"))
+                .or_insert_with(|| (Source::Generated(page.into()), false));
+        }
+
+        Ok(Self {
+            zip,
+            index,
+            authorized,
+            error_meta,
+            checksums: tokio::sync::OnceCell::new(),
+        })
+    }
+}
+
+/// add a zip entry to the index, following the policy when something is already there
+fn insert_entry(
+    index: &mut Index,
+    path: PathBuf,
+    entry: (Source, bool),
+    policy: DuplicatePolicy,
+) -> Result<(), LoadError> {
+    if index.contains_key(&path) {
+        let name = String::from_utf8_lossy(path.as_unix_str().as_bytes()).into_owned();
+        match policy {
+            DuplicatePolicy::First => {
+                eprintln!("{name} is in the zip more than once, serving the first");
+                return Ok(());
+            }
+            DuplicatePolicy::Last => {
+                eprintln!("{name} is in the zip more than once, serving the last");
+            }
+            DuplicatePolicy::Error => return Err(LoadError::Duplicate(name)),
+        }
+    }
+    index.insert(path, entry);
+    Ok(())
+}
+
+pub struct Server {
+    /// the zip being served, once it has been loaded
+    archive: RwLock<Option<Arc<Archive>>>,
+    config: Config,
+    metrics: Metrics,
+    relay: relay::Relay,
+}
+
+/// a connection that a gemini request can be read from
+pub trait Connection: AsyncRead + AsyncWrite + Unpin {
+    /// the hostname the client asked for during the handshake, if any
+    fn server_name(&self) -> Option<&str> {
+        None
+    }
+
+    /// the certificate the client presented, if any
+    fn client_cert(&self) -> Option<&CertificateDer<'static>> {
+        None
+    }
+}
+
+impl<S> Connection for TlsStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn server_name(&self) -> Option<&str> {
+        self.get_ref().1.server_name()
+    }
+
+    fn client_cert(&self) -> Option<&CertificateDer<'static>> {
+        self.get_ref().1.peer_certificates().and_then(<[_]>::first)
+    }
+}
+
+/// plaintext, for when tls is terminated by something in front of us
+impl Connection for tokio::net::TcpStream {}
+
+#[cfg(unix)]
+impl Connection for tokio::net::UnixStream {}
+
+impl Server {
+    /// serve a zip, along with the rules in its `/.redgem/authorized` and its error texts
+    pub async fn load(zip: impl Into<Zip>, config: Config) -> Result<Self, LoadError> {
+        let srv = Self::unloaded(config)?;
+        srv.swap_zip(zip).await?;
+        Ok(srv)
+    }
 
-            index.insert(path, (i, false));
+    /// a server without a zip yet, answering requests with a 41 until one is given to
+    /// [`Server::swap_zip`]
+    pub fn unloaded(config: Config) -> Result<Self, LoadError> {
+        let known =
+            known_hosts::KnownHosts::load(config.known_hosts.clone(), config.tofu_accept_new)?;
+        Ok(Self {
+            archive: RwLock::new(None),
+            relay: relay::Relay::new(&config.proxy_allow, Arc::new(known)),
+            config,
+            metrics: Metrics::default(),
+        })
+    }
+
+    /// a server for another capsule without a zip yet, trusting the same relayed certificates
+    /// as this one. they share the known hosts file, so one must not forget what another learned
+    pub fn sibling(&self, config: Config) -> Self {
+        Self {
+            archive: RwLock::new(None),
+            relay: relay::Relay::new(&config.proxy_allow, self.relay.known_hosts()),
+            config,
+            metrics: Metrics::default(),
         }
+    }
 
-        Self { zip, index }
+    /// start serving a different zip, rereading its `/.redgem` files.
+    ///
+    /// responses that are already being sent carry on from the old zip
+    pub async fn swap_zip(&self, zip: impl Into<Zip>) -> Result<(), LoadError> {
+        let archive = Arc::new(Archive::load(zip.into(), &self.config).await?);
+        *self.archive.write().unwrap_or_else(PoisonError::into_inner) = Some(archive);
+        Ok(())
+    }
+
+    /// the zip currently being served, if it has been loaded yet
+    fn archive(&self) -> Option<Arc<Archive>> {
+        self.archive
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .clone()
+    }
+
+    /// use the error texts of the zip being served, or only the configured ones while loading
+    fn with_error_meta<B>(&self, response: response::Response<B>) -> response::Response<B> {
+        match self.archive() {
+            Some(archive) => response.with_error_meta(&archive.error_meta),
+            None => response.with_error_meta(&self.config.error_meta),
+        }
     }
 
-    pub async fn handle_connection(&self, mut stream: TlsStream<TcpStream>) {
-        let Ok(request) = timeout(Duration::from_secs(30), self.parse_req(&mut stream)).await
+    pub const fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    /// how many paths the zip being served has, or none while it is loading
+    pub fn index_entries(&self) -> usize {
+        self.archive().map_or(0, |archive| archive.index.len())
+    }
+
+    /// how long a client may take to finish the tls handshake, which happens before we get the
+    /// connection
+    pub fn handshake_timeout(&self) -> Duration {
+        self.config.handshake_timeout()
+    }
+
+    /// whether any path is restricted to client certificates, assuming some might be while the
+    /// zip is still loading
+    pub fn has_authorized(&self) -> bool {
+        self.archive()
+            .is_none_or(|archive| !archive.authorized.is_empty())
+    }
+
+    /// respond to the request on a connection, returning what happened for logging
+    pub async fn handle_connection<S: Connection>(&self, mut stream: S) -> Outcome {
+        let mut outcome = Outcome {
+            url: None,
+            status: 0,
+            bytes: 0,
+            rejection: None,
+        };
+        let Ok(request) = timeout(self.config.header_timeout(), self.parse_req(&mut stream)).await
         else {
+            let response =
+                self.with_error_meta(response::Response::<Body<'_>>::from(Error::Timeout));
+            outcome.status = response.status();
+            outcome.rejection = Some(Rejection::Timeout);
             _ = timeout(
                 Duration::from_secs(30),
-                send_response::<Compat<ZipEntryReader<'_, Compat<BufReader<File>>, WithEntry<'_>>>>(
-                    stream,
-                    Error::Timeout.into(),
-                ),
+                send_response(stream, response, &mut outcome.bytes, &self.config),
             )
             .await;
-            return;
+            return outcome;
         };
+        match &request {
+            Ok(request) => outcome.url = Some(request.as_str().to_string()),
+            Err(e) => outcome.rejection = Rejection::from_error(e),
+        }
+
+        let cert = stream
+            .client_cert()
+            .map(|c| auth::ClientCert::new(c, self.config.accept_expired_certs));
 
+        let archive = self.archive();
         let response = match request {
-            Ok(request) => self.get_file(request).await,
+            Ok(request) if self.relay.allows(&request) => {
+                if self.config.require_client_cert
+                    && let Err(e) = auth::require(cert.as_ref())
+                {
+                    e.into()
+                } else {
+                    self.relay_request(stream, &request, &mut outcome).await;
+                    return outcome;
+                }
+            }
+            Ok(request) => match &archive {
+                Some(archive) => self.get_file(archive, request, cert.as_ref()).await,
+                None => Error::Loading.into(),
+            },
             Err(e) => e.into(),
         };
+        let response = self.with_error_meta(response);
+        outcome.status = response.status();
 
-        _ = timeout(Duration::from_mins(10), send_response(stream, response)).await;
+        _ = timeout(
+            self.config.response_timeout(),
+            send_response(stream, response, &mut outcome.bytes, &self.config),
+        )
+        .await;
+        outcome
     }
 
-    async fn parse_req(
-        &self,
-        stream: &mut TlsStream<TcpStream>,
-    ) -> Result<request::Request, Error> {
+    async fn parse_req<S: Connection>(&self, stream: &mut S) -> Result<request::Request, Error> {
         let mut buffer = [0; 1026];
         let mut len = 0;
 
@@ -130,30 +1141,161 @@ impl Server {
             // doing it this way allows redgem to be a bit more strict about rejecting malformed
             // requests that have additional content after the line ending
             if let Some(buf) = buffer[..len].strip_suffix(b"\r\n") {
-                let tls = stream.get_ref().1;
-                let servername = tls.server_name();
-                return request::Request::parse(buf, servername);
+                if let Ok(req) = request::Request::parse_relayed(buf)
+                    && self.relay.allows(&req)
+                {
+                    return Ok(req);
+                }
+                if self.config.byte_ranges {
+                    return request::Request::parse_with_range(buf, stream.server_name());
+                }
+                return request::Request::parse(buf, stream.server_name());
+            }
+        }
+    }
+
+    /// pass a request on to the server it is for, and its response back
+    async fn relay_request<S: Connection>(
+        &self,
+        stream: S,
+        req: &request::Request,
+        outcome: &mut Outcome,
+    ) {
+        let upstream = timeout(Duration::from_secs(30), self.relay.fetch(req)).await;
+        let Ok(Ok((header, body))) = upstream else {
+            let response =
+                self.with_error_meta(response::Response::<Body<'_>>::from(Error::ProxyFailed));
+            outcome.status = response.status();
+            _ = timeout(
+                Duration::from_secs(30),
+                send_response(stream, response, &mut outcome.bytes, &self.config),
+            )
+            .await;
+            return;
+        };
+        // the header was already checked to start with two digits
+        outcome.status = header.get(..2).and_then(|s| s.parse().ok()).unwrap_or(0);
+
+        let read = Cursor::new(format!("{header}\r\n")).chain(body);
+        _ = timeout(
+            self.config.response_timeout(),
+            copy_to(stream, read, &mut outcome.bytes, &self.config),
+        )
+        .await;
+    }
+
+    /// start reading a file or generated page, or just the given range of bytes of a file
+    async fn open<'a>(
+        &self,
+        archive: &'a Archive,
+        source: &Source,
+        range: Option<(u64, Option<u64>)>,
+    ) -> Result<Contents<'a>, Error> {
+        let id = match source {
+            Source::Zip(id) => *id,
+            Source::Generated(_) | Source::Checksums if range.is_some() => {
+                return Err(Error::BadRange);
+            }
+            Source::Generated(page) => return Ok(Either::Right(Cursor::new(page.clone()))),
+            Source::Checksums => {
+                let page = archive
+                    .checksums
+                    .get_or_try_init(|| async {
+                        // the listing is shared by every client, so it leaves out whatever
+                        // needs a client certificate
+                        let page = generate::checksums(&archive.zip, &archive.index, |path| {
+                            self.config.is_denied(path)
+                                || auth::check(&archive.authorized, path, None).is_err()
+                        });
+                        page.await.map(Into::into)
+                    })
+                    .await
+                    .map_err(|_| Error::BadEntry)?;
+                return Ok(Either::Right(Cursor::new(page.clone())));
             }
+        };
+        let size = archive
+            .zip
+            .file()
+            .entries()
+            .get(id)
+            .map_or(0, |e| e.uncompressed_size());
+        if self.config.max_file_size.is_some_and(|max| size > max) {
+            return Err(Error::TooLarge);
         }
+        let entry = match range {
+            None => archive.zip.entry(id).await.map(Either::Left),
+            Some((start, _)) if start >= size => return Err(Error::BadRange),
+            Some((start, end)) => {
+                let end = end.map_or(size - 1, |end| end.min(size - 1));
+                let range = archive.zip.range(id, start, end - start + 1).await;
+                range.map(Either::Right)
+            }
+        };
+        entry.map(Either::Left).map_err(|_| Error::BadEntry)
     }
 
-    async fn get_file(
+    async fn get_file<'a>(
         &self,
+        archive: &'a Archive,
         req: request::Request,
-    ) -> response::Response<Compat<ZipEntryReader<'_, Compat<BufReader<File>>, WithEntry<'_>>>>
-    {
+        cert: Option<&auth::ClientCert>,
+    ) -> response::Response<Body<'a>> {
+        if self.config.require_client_cert
+            && let Err(e) = auth::require(cert)
+        {
+            return e.into();
+        }
+
+        if let Some(RedirectTarget(base)) = &self.config.redirect_all_to {
+            return match req.with_base(base) {
+                Ok(new) => response::Response::permanent_redirect(new),
+                Err(e) => e.into(),
+            };
+        }
+
+        if let Some(host) = &self.config.canonical_host
+            && req.is_ip_literal()
+        {
+            return match req.with_host(host) {
+                Ok(new) => response::Response::permanent_redirect(new),
+                Err(e) => e.into(),
+            };
+        }
+
         let path = req.pathname();
         let bytes = path.to_bytes();
         // pretend that an empty path has a trailing / since the spec
         // forbids redirects between "" and "/"
         let trailing = bytes.is_empty() || bytes.ends_with(b"/");
-        let path = Path::new("/").join(UnixStr::from_bytes(&bytes));
+        let mut path = Path::new("/").join(UnixStr::from_bytes(&nfc(&bytes)));
+        if self.config.clean_urls && !trailing && !archive.index.contains_key(&path) {
+            let mut gmi = path.as_unix_str().as_bytes().to_vec();
+            gmi.extend_from_slice(b".gmi");
+            path = Path::new(UnixStr::from_bytes(&gmi)).to_path_buf();
+        }
+        let lang = if !trailing
+            && let Some((variant, lang)) = self.config.lang_variant(&archive.index, &path)
+        {
+            path = variant;
+            Some(lang)
+        } else {
+            None
+        };
+
+        if self.config.is_denied(&path) {
+            return Error::NotFound.into();
+        }
 
-        let Some(&(id, is_index)) = self.index.get(&path) else {
+        if let Err(e) = auth::check(&archive.authorized, &path, cert) {
+            return e.into();
+        }
+
+        let Some((source, is_index)) = archive.index.get(&path) else {
             return Error::NotFound.into();
         };
 
-        match (is_index, trailing) {
+        match (*is_index, trailing) {
             (false, true) => {
                 // trailing / on normal file
                 return Error::NotFound.into();
@@ -168,21 +1310,95 @@ impl Server {
             (false, false) | (true, true) => (),
         }
 
-        let Ok(entry) = self.zip.reader_with_entry(id).await else {
-            return Error::BadEntry.into();
+        let mut mimetype = self
+            .config
+            .mimetype(if *is_index { None } else { path.extension() });
+        // only gemtext has a lang parameter
+        let lang = lang.filter(|_| mimetype.is_gemtext());
+        let sniff = self.config.sniff_types && mimetype.is_unknown();
+        let detect = self.config.detect_charset && mimetype.is_plain_text();
+        if (sniff || detect)
+            && let Source::Zip(id) = source
+            && let Ok(start) = archive.zip.read_start(*id, 1024).await
+        {
+            if sniff && let Some(sniffed) = response::MimeType::sniff(&start) {
+                mimetype = sniffed;
+            }
+            if detect && let Some(charset) = response::legacy_charset(&start) {
+                mimetype = mimetype.with_param("charset", charset);
+            }
+        }
+        if let Some(lang) = lang {
+            mimetype = mimetype.with_param("lang", lang);
+        }
+
+        // ranges are for resuming downloads, so text is always sent whole
+        let range = req.byte_range();
+        if range.is_some() && (*is_index || mimetype.is_text()) {
+            return Error::BadRange.into();
+        }
+
+        let body = match self.open(archive, source, range).await {
+            Ok(body) => body,
+            Err(e) => return e.into(),
         };
-        let mimetype =
-            response::MimeType::from_extension(if is_index { None } else { path.extension() });
-        response::Response::with_type(mimetype, entry.compat())
+        self.metrics.hit(&generate::encode_path(&path));
+        self.config.respond_with_footer(mimetype, body)
     }
 }
 
-/// send a [`response::Response`] and then close the connection with `close_notify`
-async fn send_response<R>(mut stream: TlsStream<TcpStream>, response: response::Response<R>)
+/// send a [`response::Response`] and then close the connection, with `close_notify` over tls.
+///
+/// `sent` is kept up to date as we go, so that it is still accurate if this gets cancelled
+async fn send_response<R, S>(
+    stream: S,
+    response: response::Response<R>,
+    sent: &mut u64,
+    config: &Config,
+) where
+    R: AsyncRead + Unpin,
+    S: Connection,
+{
+    copy_to(stream, response.into_read(), sent, config).await;
+}
+
+/// copy everything from a reader to a connection, counting how much was sent
+async fn copy_to<R, S>(mut stream: S, mut read: R, sent: &mut u64, config: &Config)
 where
     R: AsyncRead + Unpin,
+    S: Connection,
 {
-    if copy(&mut response.into_read(), &mut stream).await.is_ok() {
-        _ = stream.shutdown().await;
+    let mut buf = vec![0; config.buffer_size()];
+    let mut len = 0;
+    let idle = config.idle_timeout.unwrap_or(Duration::MAX);
+    // the header is read on its own, so hold onto it until the start of the body is read too.
+    // this lets small responses go out in a single tls record
+    let mut header = true;
+    loop {
+        let result = timeout(idle, read.read(&mut buf[len..]))
+            .await
+            .unwrap_or_else(|_| Err(std::io::ErrorKind::TimedOut.into()));
+        let count = *result.as_ref().unwrap_or(&0);
+        len += count;
+        if count != 0 && std::mem::take(&mut header) && len < buf.len() {
+            continue;
+        }
+        if len != 0 {
+            if !matches!(
+                timeout(idle, stream.write_all(&buf[..len])).await,
+                Ok(Ok(()))
+            ) {
+                return;
+            }
+            *sent += len as u64;
+            len = 0;
+        }
+        if result.is_err() {
+            return;
+        }
+        if count == 0 {
+            break;
+        }
     }
+    _ = stream.shutdown().await;
 }
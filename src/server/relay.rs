@@ -0,0 +1,104 @@
+//! relaying requests for other capsules, so redgem can be a gateway for clients that cannot
+//! reach them directly
+
+use super::{known_hosts::KnownHosts, request::Request};
+use crate::{fetch, tls::TofuServerCert};
+use std::sync::Arc;
+use tokio::{io::BufReader, net::TcpStream};
+use tokio_rustls::{TlsConnector, client::TlsStream, rustls::ClientConfig};
+
+/// the port relayed hosts are assumed to be on when none is given
+const DEFAULT_PORT: u16 = 1965;
+
+/// split an allowed `host[:port]` into its lowercased host and port, or [`None`] if the port is
+/// not a number
+pub fn allowed_authority(allowed: &str) -> Option<(String, u16)> {
+    let allowed = allowed.to_ascii_lowercase();
+    match allowed.rsplit_once(':') {
+        // the colons in a bracketed ipv6 address are not a port
+        Some((host, port)) if host.ends_with(']') || !host.contains(':') => {
+            Some((host.to_string(), port.parse().ok()?))
+        }
+        _ => Some((allowed, DEFAULT_PORT)),
+    }
+}
+
+/// which hosts requests may be relayed to, and how to connect to them
+pub struct Relay {
+    hosts: Vec<(String, u16)>,
+    known: Arc<KnownHosts>,
+    connector: TlsConnector,
+}
+
+impl std::fmt::Debug for Relay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Relay")
+            .field("hosts", &self.hosts)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Relay {
+    pub fn new(hosts: &[String], known: Arc<KnownHosts>) -> Self {
+        let builder = ClientConfig::builder();
+        let verifier = TofuServerCert::new(builder.crypto_provider().clone(), known.clone());
+        let config = builder
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(verifier))
+            .with_no_client_auth();
+        Self {
+            hosts: hosts.iter().filter_map(|h| allowed_authority(h)).collect(),
+            known,
+            connector: TlsConnector::from(Arc::new(config)),
+        }
+    }
+
+    /// the certificates this trusts, to share with other relays
+    pub fn known_hosts(&self) -> Arc<KnownHosts> {
+        self.known.clone()
+    }
+
+    /// whether a request is for one of the hosts and ports we relay to
+    pub fn allows(&self, req: &Request) -> bool {
+        if self.hosts.is_empty() {
+            return false;
+        }
+        let Some(host) = req.host() else {
+            return false;
+        };
+        let port = req.port().unwrap_or(DEFAULT_PORT);
+        self.hosts
+            .iter()
+            .any(|(h, p)| *p == port && h.eq_ignore_ascii_case(&host))
+    }
+
+    /// send a request upstream, returning the response header and the rest of the response
+    pub async fn fetch(
+        &self,
+        req: &Request,
+    ) -> Result<(String, BufReader<TlsStream<TcpStream>>), fetch::Error> {
+        fetch::request(&self.connector, req.as_uri()).await
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn authorities() {
+        assert_eq!(
+            allowed_authority("Example.com"),
+            Some(("example.com".into(), 1965))
+        );
+        assert_eq!(
+            allowed_authority("example.com:1966"),
+            Some(("example.com".into(), 1966))
+        );
+        assert_eq!(allowed_authority("[::1]"), Some(("[::1]".into(), 1965)));
+        assert_eq!(allowed_authority("[::1]:70"), Some(("[::1]".into(), 70)));
+        assert_eq!(allowed_authority("example.com:meow"), None);
+        assert_eq!(allowed_authority("example.com:"), None);
+    }
+}
@@ -1,35 +1,63 @@
-use super::Error;
-use fluent_uri::{Uri, component::Scheme, pct_enc::Decode};
+use super::{Error, idna};
+use fluent_uri::{
+    Iri, Uri,
+    component::{Host, Scheme},
+    pct_enc::Decode,
+};
 
 /// a parsed gemini request
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Request(Uri<String>);
 
 impl Request {
     /// parse a gemini request from bytes
     ///
     /// this expects the trailing line ending to already have been removed, and will return an
-    /// error if the input contains a line ending
+    /// error if the input contains a line ending.
+    ///
+    /// non-ascii characters are percent-encoded, since some clients send unicode hostnames as-is
     pub fn parse(inp: &[u8], expect_host: Option<&str>) -> Result<Self, Error> {
-        let u = Uri::parse(str::from_utf8(inp)?.to_string()).map_err(|_| Error::UnparseableUri)?;
+        let req = Self::parse_for_host(inp, expect_host)?;
+        if req.query().is_some() {
+            return Err(Error::HasQuery);
+        }
+        Ok(req)
+    }
 
-        if u.scheme() != const { Scheme::new_or_panic("gemini") } {
-            return Err(Error::NonGeminiScheme);
+    /// like [`Self::parse`], but allowing a `bytes=START-END` query
+    pub fn parse_with_range(inp: &[u8], expect_host: Option<&str>) -> Result<Self, Error> {
+        let req = Self::parse_for_host(inp, expect_host)?;
+        if req.query().is_some() && req.byte_range().is_none() {
+            return Err(Error::HasQuery);
         }
+        Ok(req)
+    }
+
+    fn parse_for_host(inp: &[u8], expect_host: Option<&str>) -> Result<Self, Error> {
+        let req = Self::parse_relayed(inp)?;
 
-        if let Some(authority) = u.authority() {
-            if expect_host.is_some_and(|h| !h.eq_ignore_ascii_case(authority.host())) {
-                return Err(Error::SniMismatch);
-            }
-            if authority.has_userinfo() {
-                return Err(Error::Userinfo);
-            }
-        } else {
-            return Err(Error::NoAuthority);
+        if let Some(expect) = expect_host
+            && !req.host().is_some_and(|h| h.eq_ignore_ascii_case(expect))
+        {
+            return Err(Error::SniMismatch);
         }
 
-        if u.has_query() {
-            return Err(Error::HasQuery);
+        Ok(req)
+    }
+
+    /// parse a gemini request for another server, which may be for any host and have a query
+    pub fn parse_relayed(inp: &[u8]) -> Result<Self, Error> {
+        let u = Iri::parse(str::from_utf8(inp)?)
+            .map_err(|_| Error::UnparseableUri)?
+            .to_uri();
+
+        if u.scheme() != const { Scheme::new_or_panic("gemini") } {
+            return Err(Error::NonGeminiScheme);
+        }
+
+        let authority = u.authority().ok_or(Error::NoAuthority)?;
+        if authority.has_userinfo() {
+            return Err(Error::Userinfo);
         }
 
         if u.has_fragment() {
@@ -39,17 +67,96 @@ impl Request {
         Ok(Self(u))
     }
 
+    /// the host the request is for, with unicode hostnames converted to ascii
+    pub fn host(&self) -> Option<String> {
+        let authority = self.0.authority()?;
+        match authority.host_parsed() {
+            Host::RegName(name) => name
+                .decode()
+                .to_string()
+                .ok()
+                .and_then(|name| idna::to_ascii(&name)),
+            _ => Some(authority.host().to_string()),
+        }
+    }
+
     /// get the path from a request
     #[inline]
     pub fn pathname(&self) -> Decode<'_> {
         self.0.path().decode()
     }
 
+    /// the port the request is for, if it has a valid one
+    pub fn port(&self) -> Option<u16> {
+        self.0.authority()?.port_to_u16().ok().flatten()
+    }
+
+    /// get the query from a request, if it has one
+    #[inline]
+    pub fn query(&self) -> Option<Decode<'_>> {
+        self.0.query().map(|q| q.decode())
+    }
+
+    /// the first and last byte asked for by a `bytes=START-END` query, where the end may be left
+    /// out to ask for everything after the start
+    pub fn byte_range(&self) -> Option<(u64, Option<u64>)> {
+        let query = self.0.query()?.as_str();
+        let (start, end) = query.strip_prefix("bytes=")?.split_once('-')?;
+        let digits = |s: &str| s.bytes().all(|b| b.is_ascii_digit());
+        if start.is_empty() || !digits(start) || !digits(end) {
+            return None;
+        }
+        let start = start.parse().ok()?;
+        if end.is_empty() {
+            return Some((start, None));
+        }
+        let end = end.parse().ok().filter(|&end| end >= start)?;
+        Some((start, Some(end)))
+    }
+
     #[inline]
     pub fn as_str(&self) -> &str {
         self.0.as_str()
     }
 
+    #[inline]
+    pub const fn as_uri(&self) -> &Uri<String> {
+        &self.0
+    }
+
+    /// check if the request is addressed to an ip address instead of a hostname
+    pub fn is_ip_literal(&self) -> bool {
+        self.0
+            .authority()
+            .is_some_and(|a| !matches!(a.host_parsed(), Host::RegName(_)))
+    }
+
+    /// create a new request with the same path on a different host.
+    ///
+    /// the port is kept, if there was one
+    pub fn with_host(&self, host: &str) -> Result<Self, Error> {
+        let host = idna::to_ascii(host).ok_or(Error::UriBuild)?;
+        let port = self.port().map_or(String::new(), |p| format!(":{p}"));
+        let uri = Uri::parse(format!(
+            "{}://{host}{port}{}",
+            self.0.scheme().as_str(),
+            self.0.path().as_str()
+        ))
+        .map_err(|_| Error::UriBuild)?;
+
+        Ok(Self(uri))
+    }
+
+    /// create a new request for the same path under another base url, such as
+    /// `gemini://new.example/` or `gemini://new.example/old/`
+    pub fn with_base(&self, base: &Self) -> Result<Self, Error> {
+        let prefix = base.as_str().strip_suffix('/').unwrap_or(base.as_str());
+        let path = self.0.path().as_str();
+        let uri = Uri::parse(format!("{prefix}{path}")).map_err(|_| Error::UriBuild)?;
+
+        Ok(Self(uri))
+    }
+
     /// create a new request with a `/` added to the end of the path.
     ///
     /// the result will be nonsensical if it already has a trailing `/`
@@ -108,6 +215,114 @@ mod tests {
         );
     }
 
+    #[test]
+    fn idn_host() {
+        for req in [
+            "gemini://bücher.example/".as_bytes(),
+            b"gemini://b%C3%BCcher.example/",
+            b"gemini://xn--bcher-kva.example/",
+        ] {
+            assert!(Request::parse(req, Some("xn--bcher-kva.example")).is_ok());
+        }
+    }
+
+    #[test]
+    fn ip_literal() {
+        for req in [
+            b"gemini://203.0.113.5/meow".as_slice(),
+            b"gemini://[2001:db8::5]:1965/meow",
+        ] {
+            assert!(Request::parse(req, None).unwrap().is_ip_literal());
+        }
+        assert!(
+            !Request::parse(b"gemini://example.com/", None)
+                .unwrap()
+                .is_ip_literal()
+        );
+    }
+
+    #[test]
+    fn with_host() {
+        assert_eq!(
+            Request::parse(b"gemini://203.0.113.5:1965/me%20ow", None)
+                .unwrap()
+                .with_host("example.com")
+                .unwrap()
+                .as_str(),
+            "gemini://example.com:1965/me%20ow"
+        );
+    }
+
+    #[test]
+    fn with_base() {
+        let req = Request::parse(b"gemini://old.example/me%20ow/", None).unwrap();
+        for (base, new) in [
+            (
+                &b"gemini://new.example/"[..],
+                "gemini://new.example/me%20ow/",
+            ),
+            (b"gemini://new.example", "gemini://new.example/me%20ow/"),
+            (
+                b"gemini://new.example:1966/old/",
+                "gemini://new.example:1966/old/me%20ow/",
+            ),
+        ] {
+            let base = Request::parse(base, None).unwrap();
+            assert_eq!(req.with_base(&base).unwrap().as_str(), new);
+        }
+    }
+
+    #[test]
+    fn relayed() {
+        let req = Request::parse_relayed(b"gemini://B%C3%BCcher.example:1966/search?meow").unwrap();
+        assert_eq!(req.host().as_deref(), Some("xn--bcher-kva.example"));
+        assert_eq!(
+            Request::parse(req.as_str().as_bytes(), None).unwrap_err(),
+            Error::HasQuery
+        );
+        assert_eq!(
+            Request::parse_relayed(b"gemini://a@example.com/").unwrap_err(),
+            Error::Userinfo
+        );
+        assert_eq!(
+            Request::parse_relayed(b"https://example.com/").unwrap_err(),
+            Error::NonGeminiScheme
+        );
+    }
+
+    #[test]
+    fn byte_ranges() {
+        let range = |req: &[u8]| Request::parse_with_range(req, None).map(|r| r.byte_range());
+        assert_eq!(range(b"gemini://a/f.zip?bytes=5-9"), Ok(Some((5, Some(9)))));
+        assert_eq!(range(b"gemini://a/f.zip?bytes=5-"), Ok(Some((5, None))));
+        assert_eq!(range(b"gemini://a/f.zip?bytes=5-5"), Ok(Some((5, Some(5)))));
+        assert_eq!(range(b"gemini://a/f.zip"), Ok(None));
+        for bad in [
+            &b"gemini://a/f.zip?bytes=9-5"[..],
+            b"gemini://a/f.zip?bytes=-5",
+            b"gemini://a/f.zip?bytes=+5-9",
+            b"gemini://a/f.zip?bytes=5",
+            b"gemini://a/f.zip?meow",
+        ] {
+            assert_eq!(range(bad), Err(Error::HasQuery));
+        }
+        assert_eq!(
+            Request::parse(b"gemini://a/f.zip?bytes=5-9", None).unwrap_err(),
+            Error::HasQuery
+        );
+    }
+
+    #[test]
+    fn accessors() {
+        let req = Request::parse_relayed(b"gemini://example.com:1966/search?caf%C3%A9").unwrap();
+        assert_eq!(req.port(), Some(1966));
+        assert_eq!(req.query().unwrap().to_bytes().as_ref(), "café".as_bytes());
+
+        let req = Request::parse(b"gemini://example.com:/meow", None).unwrap();
+        assert_eq!(req.port(), None);
+        assert!(req.query().is_none());
+    }
+
     #[test]
     fn bad_host() {
         assert_eq!(
@@ -1,6 +1,7 @@
-use super::{Error, request::Request};
+use super::{Error, ErrorMeta, request::Request};
 use pin_project_lite::pin_project;
 use std::{
+    borrow::Cow,
     io::Cursor,
     pin::Pin,
     task::{Context, Poll, ready},
@@ -9,10 +10,10 @@ use tokio::io::{AsyncRead, ReadBuf};
 use unix_str::UnixStr;
 
 /// the file type for a successful [`Response`]
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct MimeType {
-    domtype: &'static str,
-    subtype: &'static str,
+    domtype: Cow<'static, str>,
+    subtype: Cow<'static, str>,
 }
 
 impl MimeType {
@@ -63,7 +64,90 @@ impl MimeType {
             Some(_) => ("application", "octet-stream"),
         };
 
-        Self { domtype, subtype }
+        Self::new(domtype, subtype)
+    }
+
+    const fn new(domtype: &'static str, subtype: &'static str) -> Self {
+        Self {
+            domtype: Cow::Borrowed(domtype),
+            subtype: Cow::Borrowed(subtype),
+        }
+    }
+
+    /// guess the type from the first bytes of a file, for when its extension did not help
+    pub fn sniff(start: &[u8]) -> Option<Self> {
+        let (domtype, subtype) = match start {
+            [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', ..] => ("image", "png"),
+            [0xff, 0xd8, 0xff, ..] => ("image", "jpeg"),
+            [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => ("image", "gif"),
+            [
+                b'R',
+                b'I',
+                b'F',
+                b'F',
+                _,
+                _,
+                _,
+                _,
+                b'W',
+                b'E',
+                b'B',
+                b'P',
+                ..,
+            ] => ("image", "webp"),
+            [b'%', b'P', b'D', b'F', b'-', ..] => ("application", "pdf"),
+            [0x7f, b'E', b'L', b'F', ..] => ("application", "x-executable"),
+            [b'P', b'K', 3, 4, ..] => ("application", "zip"),
+            _ if is_text(start) => ("text", "plain"),
+            _ => return None,
+        };
+
+        Some(Self::new(domtype, subtype))
+    }
+
+    /// parse a type in the form of `TYPE/SUBTYPE`, which may be followed by parameters
+    pub fn parse(s: &str) -> Option<Self> {
+        let (domtype, subtype) = s.split_once('/')?;
+        if domtype.is_empty()
+            || subtype.is_empty()
+            || s.len() > 1024
+            || s.contains(char::is_control)
+            || domtype.contains(char::is_whitespace)
+        {
+            return None;
+        }
+        Some(Self {
+            domtype: Cow::Owned(domtype.to_string()),
+            subtype: Cow::Owned(subtype.to_string()),
+        })
+    }
+
+    /// add a parameter, like `charset` or `lang`, to the type
+    pub fn with_param(self, name: &str, value: &str) -> Self {
+        Self {
+            subtype: Cow::Owned(format!("{}; {name}={value}", self.subtype)),
+            ..self
+        }
+    }
+
+    /// whether this is some kind of text without any parameters yet
+    pub fn is_plain_text(&self) -> bool {
+        self.domtype == "text" && !self.subtype.contains(';')
+    }
+
+    /// whether the type could not be guessed
+    pub fn is_unknown(&self) -> bool {
+        self.domtype == "application" && self.subtype == "octet-stream"
+    }
+
+    /// whether this is any kind of text
+    pub fn is_text(&self) -> bool {
+        self.domtype == "text"
+    }
+
+    /// whether this is gemtext, whatever parameters it has
+    pub fn is_gemtext(&self) -> bool {
+        self.domtype == "text" && self.subtype.split(';').next().map(str::trim) == Some("gemini")
     }
 
     fn bytes_append(&self, target: &mut Vec<u8>) {
@@ -73,12 +157,63 @@ impl MimeType {
     }
 }
 
+impl std::fmt::Display for MimeType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.domtype, self.subtype)
+    }
+}
+
+/// whether the start of a file looks like utf-8 text
+fn is_text(start: &[u8]) -> bool {
+    let text = match str::from_utf8(start) {
+        Ok(text) => text,
+        // the start may end partway through a character
+        Err(e) if e.error_len().is_none() => {
+            str::from_utf8(&start[..e.valid_up_to()]).unwrap_or_default()
+        }
+        Err(_) => return false,
+    };
+    !text
+        .chars()
+        .any(|c| c.is_control() && !matches!(c, '\t' | '\n' | '\r' | '\x0c'))
+}
+
+/// guess the legacy charset of text that is not utf-8, from its first bytes.
+///
+/// text using the c1 control characters is unlikely, so their presence suggests windows-1252's
+/// punctuation instead of iso-8859-1
+pub fn legacy_charset(start: &[u8]) -> Option<&'static str> {
+    match str::from_utf8(start) {
+        Err(e) if e.error_len().is_some() => {
+            if start.iter().any(|b| (0x80..=0x9f).contains(b)) {
+                Some("windows-1252")
+            } else {
+                Some("iso-8859-1")
+            }
+        }
+        _ => None,
+    }
+}
+
 /// a gemini protocol response
 #[non_exhaustive]
 pub enum Response<B> {
-    Success { mimetype: MimeType, body: B },
-    Failure { kind: Error },
-    PermanentRedirect { to: Request },
+    Success {
+        mimetype: MimeType,
+        body: B,
+    },
+    Failure {
+        kind: Error,
+        meta: Option<String>,
+    },
+    PermanentRedirect {
+        to: Request,
+    },
+    /// any other status, with a meta that has already been checked
+    Other {
+        status: u8,
+        meta: String,
+    },
 }
 
 impl<B> Response<B> {
@@ -92,29 +227,121 @@ impl<B> Response<B> {
         Self::PermanentRedirect { to }
     }
 
-    /// turn the response into a tokio [`AsyncRead`]
-    pub fn into_read(self) -> OptionalChain<Cursor<Vec<u8>>, B> {
+    /// create a response with any status besides success, if it is one gemini has and the meta
+    /// can be sent as-is
+    pub fn other(status: u8, meta: impl Into<String>) -> Option<Self> {
+        let meta = meta.into();
+        let valid = matches!(status, 10 | 11 | 30 | 31 | 40..=44 | 50..=53 | 59 | 60..=62);
+        (valid && meta.len() <= 1024 && !meta.contains(['\r', '\n']))
+            .then_some(Self::Other { status, meta })
+    }
+
+    /// replace the text of a failure, if there is an override for its status
+    pub fn with_error_meta(self, overrides: &[ErrorMeta]) -> Self {
+        match self {
+            Self::Failure { kind, meta: None } => {
+                let meta = overrides
+                    .iter()
+                    .rfind(|o| o.status == kind.status())
+                    .map(|o| o.meta.clone());
+                Self::Failure { kind, meta }
+            }
+            other => other,
+        }
+    }
+
+    /// the two digit status code
+    pub const fn status(&self) -> u8 {
+        match self {
+            Self::Success { .. } => 20,
+            Self::Failure { kind, .. } => kind.status(),
+            Self::PermanentRedirect { .. } => 31,
+            Self::Other { status, .. } => *status,
+        }
+    }
+
+    /// turn the response into a tokio [`AsyncRead`].
+    ///
+    /// failures without a custom text use their static header as-is, other headers are
+    /// allocated once at their final size
+    pub fn into_read(self) -> OptionalChain<Cursor<Cow<'static, [u8]>>, B> {
         match self {
             Self::Success { mimetype, body } => {
-                let mut header = b"20 ".to_vec();
+                let mut header = Vec::with_capacity(
+                    mimetype.domtype.len() + mimetype.subtype.len() + const { b"20 /\r\n".len() },
+                );
+                header.extend_from_slice(b"20 ");
                 mimetype.bytes_append(&mut header);
                 header.extend_from_slice(b"\r\n");
-                OptionalChain::chain(Cursor::new(header), body)
+                OptionalChain::chain(Cursor::new(header.into()), body)
+            }
+            Self::Failure { kind, meta: None } => {
+                OptionalChain::single(Cursor::new(Cow::Borrowed(kind.bytes())))
+            }
+            Self::Failure {
+                kind,
+                meta: Some(meta),
+            } => {
+                let header = format!("{} {meta}\r\n", kind.status());
+                OptionalChain::single(Cursor::new(header.into_bytes().into()))
             }
-            Self::Failure { kind } => OptionalChain::single(Cursor::new(kind.bytes().to_vec())),
             Self::PermanentRedirect { to } => {
-                let mut header = b"31 ".to_vec();
-                header.extend_from_slice(to.as_str().as_bytes());
+                let to = to.as_str().as_bytes();
+                let mut header = Vec::with_capacity(to.len() + const { b"31 \r\n".len() });
+                header.extend_from_slice(b"31 ");
+                header.extend_from_slice(to);
                 header.extend_from_slice(b"\r\n");
-                OptionalChain::single(Cursor::new(header))
+                OptionalChain::single(Cursor::new(header.into()))
+            }
+            Self::Other { status, meta } => {
+                let header = format!("{status} {meta}\r\n");
+                OptionalChain::single(Cursor::new(header.into_bytes().into()))
             }
         }
     }
 }
 
+#[allow(dead_code, reason = "not every status is sent by redgem itself")]
+impl<B> Response<B> {
+    /// create a response asking for input, with a prompt
+    pub fn input(prompt: impl Into<String>) -> Option<Self> {
+        Self::other(10, prompt)
+    }
+
+    /// create a response asking for input that should not be echoed, like a password
+    pub fn sensitive_input(prompt: impl Into<String>) -> Option<Self> {
+        Self::other(11, prompt)
+    }
+
+    /// create a temporary redirect response
+    pub fn temporary_redirect(to: &Request) -> Self {
+        Self::Other {
+            status: 30,
+            meta: to.as_str().to_string(),
+        }
+    }
+
+    /// create a 40 through 44 failure that the client may try again later
+    pub fn temporary_failure(status: u8, meta: impl Into<String>) -> Option<Self> {
+        (40..=44)
+            .contains(&status)
+            .then(|| Self::other(status, meta))?
+    }
+
+    /// create a 60 through 62 response about the client certificate
+    pub fn certificate(status: u8, meta: impl Into<String>) -> Option<Self> {
+        (60..=62)
+            .contains(&status)
+            .then(|| Self::other(status, meta))?
+    }
+}
+
 impl<B> From<Error> for Response<B> {
     fn from(err: Error) -> Self {
-        Self::Failure { kind: err }
+        Self::Failure {
+            kind: err,
+            meta: None,
+        }
     }
 }
 
@@ -183,3 +410,79 @@ where
         }
     }
 }
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::{MimeType, OptionalChain, Request, Response};
+
+    /// the header a response without a body would send
+    fn header(response: Response<()>) -> String {
+        let OptionalChain::Single { first } = response.into_read() else {
+            panic!("response should not have a body");
+        };
+        String::from_utf8(first.into_inner().into_owned()).unwrap()
+    }
+
+    #[test]
+    fn statuses() {
+        assert_eq!(header(Response::input("name?").unwrap()), "10 name?\r\n");
+        assert_eq!(
+            header(Response::sensitive_input("password?").unwrap()),
+            "11 password?\r\n"
+        );
+        let to = Request::parse(b"gemini://example.com/new", None).unwrap();
+        assert_eq!(
+            header(Response::temporary_redirect(&to)),
+            "30 gemini://example.com/new\r\n"
+        );
+        assert_eq!(
+            header(Response::temporary_failure(44, "5").unwrap()),
+            "44 5\r\n"
+        );
+        assert_eq!(
+            header(Response::certificate(61, "not you").unwrap()),
+            "61 not you\r\n"
+        );
+        assert!(Response::<()>::temporary_failure(51, "nope").is_none());
+        assert!(Response::<()>::certificate(59, "nope").is_none());
+        assert!(Response::<()>::other(20, "text/gemini").is_none());
+        assert!(Response::<()>::input("a\r\n20 text/gemini").is_none());
+        assert!(Response::<()>::input("a".repeat(1025)).is_none());
+    }
+
+    #[test]
+    fn sniff() {
+        let sniff = |start: &[u8]| MimeType::sniff(start).map(|m| m.to_string());
+        assert_eq!(
+            sniff(b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR").as_deref(),
+            Some("image/png")
+        );
+        assert_eq!(sniff(b"%PDF-1.7\n").as_deref(), Some("application/pdf"));
+        assert_eq!(
+            sniff(b"\x7fELF\x02\x01\x01\0").as_deref(),
+            Some("application/x-executable")
+        );
+        assert_eq!(sniff(b"meow\nmrrp\n").as_deref(), Some("text/plain"));
+        // cut off in the middle of a character
+        assert_eq!(
+            sniff(&"m\u{e9}ow".as_bytes()[..2]).as_deref(),
+            Some("text/plain")
+        );
+        assert_eq!(sniff(b"\0\x01\x02\x03"), None);
+        assert_eq!(sniff(b"\xc3\x28"), None);
+    }
+
+    #[test]
+    fn legacy_charset() {
+        assert_eq!(super::legacy_charset("m\u{e9}ow".as_bytes()), None);
+        assert_eq!(super::legacy_charset(b"m\xe9ow"), Some("iso-8859-1"));
+        assert_eq!(super::legacy_charset(b"\x93meow\x94"), Some("windows-1252"));
+        assert_eq!(
+            MimeType::from_extension(None)
+                .with_param("charset", "iso-8859-1")
+                .to_string(),
+            "text/gemini; charset=iso-8859-1"
+        );
+    }
+}
@@ -0,0 +1,149 @@
+//! just enough der parsing to find out when a certificate is valid, see rfc 5280
+
+/// seconds since the unix epoch that a certificate is valid from and until, inclusive
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Validity {
+    pub not_before: i64,
+    pub not_after: i64,
+}
+
+const SEQUENCE: u8 = 0x30;
+const UTC_TIME: u8 = 0x17;
+const GENERALIZED_TIME: u8 = 0x18;
+
+/// split a der element off the front of some input, returning its tag, contents, and the rest
+fn element(input: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, input) = input.split_first()?;
+    let (&first, mut input) = input.split_first()?;
+    let len = if first < 0x80 {
+        usize::from(first)
+    } else {
+        let count = usize::from(first & 0x7f);
+        if count == 0 || count > size_of::<usize>() {
+            return None;
+        }
+        let (bytes, rest) = input.split_at_checked(count)?;
+        input = rest;
+        bytes.iter().fold(0, |len, &b| (len << 8) | usize::from(b))
+    };
+    let (contents, rest) = input.split_at_checked(len)?;
+    Some((tag, contents, rest))
+}
+
+/// find the validity period of a der-encoded certificate
+pub fn validity(cert: &[u8]) -> Option<Validity> {
+    let (SEQUENCE, cert, _) = element(cert)? else {
+        return None;
+    };
+    let (SEQUENCE, tbs, _) = element(cert)? else {
+        return None;
+    };
+    let (tag, _, mut rest) = element(tbs)?;
+    // the version is optional, and explicitly tagged with [0] when present
+    if tag == 0xa0 {
+        (_, _, rest) = element(rest)?;
+    }
+    // serial number, signature algorithm, and issuer
+    let (_, _, rest) = element(rest)?;
+    let (_, _, rest) = element(rest)?;
+    let (SEQUENCE, validity, _) = element(rest)? else {
+        return None;
+    };
+
+    let (tag, not_before, rest) = element(validity)?;
+    let not_before = parse_time(tag, not_before)?;
+    let (tag, not_after, _) = element(rest)?;
+    let not_after = parse_time(tag, not_after)?;
+    Some(Validity {
+        not_before,
+        not_after,
+    })
+}
+
+/// parse a `UTCTime` or `GeneralizedTime` in the forms allowed by rfc 5280, which are always in
+/// utc and have whole seconds
+fn parse_time(tag: u8, time: &[u8]) -> Option<i64> {
+    let digits = time.strip_suffix(b"Z")?;
+    if !digits.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    let num = |range: std::ops::Range<usize>| {
+        digits[range]
+            .iter()
+            .fold(0, |n, &d| n * 10 + i64::from(d - b'0'))
+    };
+
+    let (year, rest) = match (tag, digits.len()) {
+        (UTC_TIME, 12) => {
+            let year = num(0..2);
+            // two digit years are 1950 through 2049
+            (if year >= 50 { 1900 + year } else { 2000 + year }, 2)
+        }
+        (GENERALIZED_TIME, 14) => (num(0..4), 4),
+        _ => return None,
+    };
+    let month = num(rest..rest + 2);
+    let day = num(rest + 2..rest + 4);
+    let (hour, minute, second) = (
+        num(rest + 4..rest + 6),
+        num(rest + 6..rest + 8),
+        num(rest + 8..rest + 10),
+    );
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+        return None;
+    }
+    // leap seconds are allowed
+    if second > 60 {
+        return None;
+    }
+
+    Some(days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+/// days since the unix epoch of a date in the proleptic gregorian calendar, from
+/// <https://howardhinnant.github.io/date_algorithms.html#days_from_civil>
+const fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let yoe = year - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    era * 146_097 + doe - 719_468
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::{GENERALIZED_TIME, UTC_TIME, Validity, parse_time, validity};
+    use tokio_rustls::rustls::pki_types::{CertificateDer, pem::PemObject};
+
+    #[test]
+    fn times() {
+        assert_eq!(parse_time(UTC_TIME, b"700101000000Z"), Some(0));
+        assert_eq!(parse_time(UTC_TIME, b"491231235959Z"), Some(2_524_607_999));
+        assert_eq!(
+            parse_time(GENERALIZED_TIME, b"20240229120000Z"),
+            Some(1_709_208_000)
+        );
+        assert_eq!(
+            parse_time(GENERALIZED_TIME, b"99991231235959Z"),
+            Some(253_402_300_799)
+        );
+        assert_eq!(parse_time(UTC_TIME, b"700101000000+0100"), None);
+        assert_eq!(parse_time(UTC_TIME, b"20240229120000Z"), None);
+        assert_eq!(parse_time(GENERALIZED_TIME, b"20241301000000Z"), None);
+    }
+
+    #[test]
+    fn certificate() {
+        let cert = CertificateDer::from_pem_file(crate::tests::CERT_PATH).unwrap();
+        assert_eq!(
+            validity(&cert),
+            Some(Validity {
+                not_before: 1_747_593_745,
+                not_after: 2_062_953_745,
+            })
+        );
+        assert!(validity(&cert[..cert.len() / 2]).is_none());
+    }
+}
@@ -0,0 +1,259 @@
+//! signing zips with ed25519, so that a server given the public key only serves zips that have
+//! not been changed since they were signed
+
+use crate::{pack::EOCD_SIGNATURE, server::Zip};
+use argh::{ArgsInfo, FromArgs};
+use async_zip::{base::read::mem, error::ZipError, tokio::read::fs::ZipFileReader};
+use ring::{
+    rand::SystemRandom,
+    signature::{ED25519, Ed25519KeyPair, KeyPair, UnparsedPublicKey},
+};
+use std::{
+    fmt::{self, Write as _},
+    io::Write as _,
+    path::{Path, PathBuf},
+    process::ExitCode,
+    str::FromStr,
+};
+
+/// what the zip comment of a signed zip starts with, followed by the signature in hex
+const PREFIX: &str = "redgem-signature: ";
+
+/// sign a zip, or an executable made by pack, so that it is only served with --pubkey while it
+/// is unchanged.
+///
+/// the signature is kept in the zip comment, replacing whatever comment it had
+#[derive(Debug, FromArgs, ArgsInfo)]
+#[argh(subcommand, name = "sign")]
+pub struct Opt {
+    /// pkcs#8 file holding the ed25519 private key
+    #[argh(option)]
+    key: PathBuf,
+    /// write a new private key to --key instead of signing, and print its public key
+    #[argh(switch)]
+    generate: bool,
+    /// zip to sign in place
+    #[argh(positional)]
+    zip: Option<PathBuf>,
+}
+
+/// an ed25519 public key, written as hex
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PublicKey([u8; 32]);
+
+#[derive(Debug, foxerror::FoxError)]
+pub enum KeyError {
+    /// expected 64 hex digits
+    BadHex,
+}
+
+#[derive(Debug, foxerror::FoxError)]
+pub enum SignatureError {
+    /// could not find the end of the zip
+    NotZip,
+    /// zip is not signed
+    Unsigned,
+    /// signature does not match, so the zip was changed or signed with a different key
+    Mismatch,
+}
+
+#[derive(Debug, foxerror::FoxError)]
+pub enum OpenError {
+    #[err(from)]
+    Io(std::io::Error),
+    #[err(from)]
+    Zip(ZipError),
+    #[err(from)]
+    Signature(SignatureError),
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .fold(String::with_capacity(bytes.len() * 2), |mut out, b| {
+            _ = write!(out, "{b:02x}");
+            out
+        })
+}
+
+fn unhex(text: &str) -> Option<Vec<u8>> {
+    if !text.is_ascii() || !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+impl FromStr for PublicKey {
+    type Err = KeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bytes = unhex(s).ok_or(KeyError::BadHex)?;
+        Ok(Self(bytes.try_into().map_err(|_| KeyError::BadHex)?))
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&hex(&self.0))
+    }
+}
+
+/// split a zip into the part that gets signed, which is everything but the comment and its
+/// length, and the comment
+fn split(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    // the end of central directory record is 22 bytes, followed by a comment of up to 64 KiB
+    let last = data.len().checked_sub(22)?;
+    (last.saturating_sub(u16::MAX.into())..=last)
+        .rev()
+        .find(|&at| {
+            data[at..].starts_with(EOCD_SIGNATURE)
+                && usize::from(u16::from_le_bytes([data[at + 20], data[at + 21]])) == last - at
+        })
+        .map(|at| (&data[..at + 20], &data[at + 22..]))
+}
+
+/// check that a zip was signed by `key` and has not changed since
+pub fn verify(data: &[u8], key: &PublicKey) -> Result<(), SignatureError> {
+    let (signed, comment) = split(data).ok_or(SignatureError::NotZip)?;
+    let signature = str::from_utf8(comment)
+        .ok()
+        .and_then(|c| c.strip_prefix(PREFIX))
+        .and_then(unhex)
+        .ok_or(SignatureError::Unsigned)?;
+    UnparsedPublicKey::new(&ED25519, key.0)
+        .verify(signed, &signature)
+        .map_err(|_| SignatureError::Mismatch)
+}
+
+/// a copy of a zip with its comment replaced by a signature
+fn sign(data: &[u8], pair: &Ed25519KeyPair) -> Result<Vec<u8>, SignatureError> {
+    let (signed, _) = split(data).ok_or(SignatureError::NotZip)?;
+    let comment = format!("{PREFIX}{}", hex(pair.sign(signed).as_ref()));
+    let len = u16::try_from(comment.len()).map_err(|_| SignatureError::NotZip)?;
+    let mut out = signed.to_vec();
+    out.extend_from_slice(&len.to_le_bytes());
+    out.extend_from_slice(comment.as_bytes());
+    Ok(out)
+}
+
+/// open a zip, checking its signature first if there is a key to check it against.
+///
+/// a checked zip is read into memory, so that it cannot be changed underneath us afterwards
+pub async fn open(path: &Path, key: Option<&PublicKey>) -> Result<Zip, OpenError> {
+    let Some(key) = key else {
+        return Ok(ZipFileReader::new(path).await?.into());
+    };
+    let data = tokio::fs::read(path).await?;
+    verify(&data, key)?;
+    Ok(mem::ZipFileReader::new(data).await?.into())
+}
+
+fn generate(path: &Path) -> ExitCode {
+    let Ok(pkcs8) = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()) else {
+        eprintln!("could not generate a key");
+        return ExitCode::from(4);
+    };
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    if let Err(e) = options
+        .open(path)
+        .and_then(|mut file| file.write_all(pkcs8.as_ref()))
+    {
+        eprintln!("could not write key to {}: {e}", path.display());
+        return ExitCode::from(1);
+    }
+    let Ok(pair) = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()) else {
+        eprintln!("could not read back the generated key");
+        return ExitCode::from(4);
+    };
+    println!("{}", hex(pair.public_key().as_ref()));
+    ExitCode::SUCCESS
+}
+
+pub fn run(opt: &Opt) -> ExitCode {
+    if opt.generate {
+        return generate(&opt.key);
+    }
+    let Some(zip) = &opt.zip else {
+        eprintln!("a zip to sign is required unless --generate is given");
+        return ExitCode::from(1);
+    };
+    let pair = match std::fs::read(&opt.key) {
+        Ok(pkcs8) => match Ed25519KeyPair::from_pkcs8(&pkcs8) {
+            Ok(pair) => pair,
+            Err(e) => {
+                eprintln!("could not use key {}: {e}", opt.key.display());
+                return ExitCode::from(4);
+            }
+        },
+        Err(e) => {
+            eprintln!("could not read key {}: {e}", opt.key.display());
+            return ExitCode::from(4);
+        }
+    };
+
+    let signed = std::fs::read(zip)
+        .map_err(OpenError::from)
+        .and_then(|data| Ok(sign(&data, &pair)?))
+        .and_then(|signed| Ok(std::fs::write(zip, signed)?));
+    match signed {
+        Ok(()) => {
+            eprintln!(
+                "signed {} for public key {}",
+                zip.display(),
+                hex(pair.public_key().as_ref())
+            );
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            eprintln!("could not sign {}: {e}", zip.display());
+            ExitCode::from(2)
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::{PublicKey, SignatureError, sign, verify};
+    use ring::{
+        rand::SystemRandom,
+        signature::{Ed25519KeyPair, KeyPair},
+    };
+
+    #[test]
+    fn signatures() {
+        let pkcs8 = Ed25519KeyPair::generate_pkcs8(&SystemRandom::new()).unwrap();
+        let pair = Ed25519KeyPair::from_pkcs8(pkcs8.as_ref()).unwrap();
+        let key = PublicKey(pair.public_key().as_ref().try_into().unwrap());
+        assert_eq!(key.to_string().parse::<PublicKey>().unwrap(), key);
+        assert!("abc".parse::<PublicKey>().is_err());
+
+        // an empty zip with a comment, attached to some executable
+        let mut zip = b"\x7fELF...PK\x05\x06".to_vec();
+        zip.extend_from_slice(&[0; 16]);
+        zip.extend_from_slice(b"\x05\x00hello");
+        assert!(matches!(verify(&zip, &key), Err(SignatureError::Unsigned)));
+
+        let signed = sign(&zip, &pair).unwrap();
+        verify(&signed, &key).unwrap();
+        // signing again replaces the signature rather than nesting it
+        assert_eq!(sign(&signed, &pair).unwrap(), signed);
+
+        let mut tampered = signed;
+        tampered[1] = b'e';
+        assert!(matches!(
+            verify(&tampered, &key),
+            Err(SignatureError::Mismatch)
+        ));
+        assert!(matches!(
+            verify(b"not a zip", &key),
+            Err(SignatureError::NotZip)
+        ));
+    }
+}
@@ -0,0 +1,114 @@
+//! sampling how much memory and how many descriptors the process is using, and how big the index
+//! is, for the metrics endpoint
+
+use crate::server::Server;
+use std::{
+    fmt::Write,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::Duration,
+};
+
+/// how often to sample, reading /proc on every scrape would let scrapers make us do work
+const INTERVAL: Duration = Duration::from_secs(10);
+
+/// resource usage as of the last sample, zero until the first one or where it cannot be read
+#[derive(Debug, Default)]
+pub struct Stats {
+    /// resident set size in bytes
+    rss: AtomicU64,
+    open_fds: AtomicU64,
+    /// paths in the index of the zip being served
+    index_entries: AtomicU64,
+}
+
+impl Stats {
+    /// take a new sample, keeping the previous values of whatever cannot be read
+    pub fn sample(&self, index_entries: usize) {
+        if let Some(rss) = rss() {
+            self.rss.store(rss, Ordering::Relaxed);
+        }
+        if let Some(open_fds) = open_fds() {
+            self.open_fds.store(open_fds, Ordering::Relaxed);
+        }
+        self.index_entries
+            .store(index_entries as u64, Ordering::Relaxed);
+    }
+
+    /// every gauge, in prometheus' text exposition format
+    pub fn render(&self, out: &mut String) {
+        for (name, help, value) in [
+            (
+                "redgem_resident_memory_bytes",
+                "resident set size of the process",
+                &self.rss,
+            ),
+            (
+                "redgem_open_fds",
+                "open file descriptors, including sockets",
+                &self.open_fds,
+            ),
+            (
+                "redgem_index_entries",
+                "paths in the index of the zip being served",
+                &self.index_entries,
+            ),
+        ] {
+            _ = writeln!(out, "# HELP {name} {help}");
+            _ = writeln!(out, "# TYPE {name} gauge");
+            _ = writeln!(out, "{name} {}", value.load(Ordering::Relaxed));
+        }
+    }
+}
+
+/// the resident set size from a /proc/self/status, which is in kibibytes
+fn parse_rss(status: &str) -> Option<u64> {
+    let line = status.lines().find_map(|l| l.strip_prefix("VmRSS:"))?;
+    let kib = line.trim().strip_suffix("kB")?.trim().parse::<u64>().ok()?;
+    Some(kib * 1024)
+}
+
+fn rss() -> Option<u64> {
+    parse_rss(&std::fs::read_to_string("/proc/self/status").ok()?)
+}
+
+fn open_fds() -> Option<u64> {
+    let dir = std::fs::read_dir("/proc/self/fd").ok()?;
+    // one of them is the descriptor for reading the directory itself
+    Some((dir.count() as u64).saturating_sub(1))
+}
+
+/// keep the server's stats up to date until the runtime shuts down
+pub async fn sample(srv: Arc<Server>) {
+    let mut interval = tokio::time::interval(INTERVAL);
+    loop {
+        interval.tick().await;
+        srv.metrics().stats.sample(srv.index_entries());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Stats, parse_rss};
+
+    #[test]
+    fn rss() {
+        let status = "Name:\tredgem\nVmPeak:\t  20000 kB\nVmRSS:\t    1234 kB\nThreads:\t4\n";
+        assert_eq!(parse_rss(status), Some(1234 * 1024));
+        assert_eq!(parse_rss("Name:\tredgem\n"), None);
+    }
+
+    #[test]
+    fn render() {
+        let stats = Stats::default();
+        stats.sample(42);
+        let mut text = String::new();
+        stats.render(&mut text);
+        assert!(text.contains("# TYPE redgem_open_fds gauge\n"));
+        assert!(text.contains("\nredgem_index_entries 42\n"));
+        #[cfg(target_os = "linux")]
+        assert!(!text.contains("\nredgem_resident_memory_bytes 0\n"));
+    }
+}
@@ -0,0 +1,60 @@
+//! sending request counts and durations to a statsd server over udp
+
+use crate::server::Outcome;
+use std::{
+    net::{ToSocketAddrs, UdpSocket},
+    sync::OnceLock,
+    time::Duration,
+};
+
+/// where to send metrics, set once at startup
+static SOCKET: OnceLock<UdpSocket> = OnceLock::new();
+
+/// start sending metrics to a statsd server at `host:port`
+pub fn init(addr: &str) -> std::io::Result<()> {
+    let addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| std::io::Error::other("address did not resolve"))?;
+    let local = if addr.is_ipv4() {
+        "0.0.0.0:0"
+    } else {
+        "[::]:0"
+    };
+    let sock = UdpSocket::bind(local)?;
+    sock.connect(addr)?;
+    // a slow statsd server should not slow us down
+    sock.set_nonblocking(true)?;
+    _ = SOCKET.set(sock);
+    Ok(())
+}
+
+/// count a request, its status class, and how long it took
+pub fn record(outcome: &Outcome, took: Duration) {
+    let Some(sock) = SOCKET.get() else {
+        return;
+    };
+    // lost packets are just lost, like statsd intends
+    _ = sock.send(packet(outcome.status, took).as_bytes());
+}
+
+fn packet(status: u8, took: Duration) -> String {
+    format!(
+        "redgem.requests:1|c\nredgem.status.{}x:1|c\nredgem.duration:{}|ms",
+        status / 10,
+        took.as_millis()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    #[test]
+    fn packet() {
+        assert_eq!(
+            super::packet(51, Duration::from_micros(12_345)),
+            "redgem.requests:1|c\nredgem.status.5x:1|c\nredgem.duration:12|ms"
+        );
+    }
+}
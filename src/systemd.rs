@@ -0,0 +1,51 @@
+//! telling systemd how we are doing, see `sd_notify(3)`
+
+use std::{io, os::unix::net::UnixDatagram, time::Duration};
+
+/// send a state change to the service manager, if we were started by one that listens for them
+pub fn notify(state: &str) -> io::Result<()> {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return Ok(());
+    };
+    let sock = UnixDatagram::unbound()?;
+
+    // sockets starting with @ are in linux's abstract namespace
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    if let Some(name) = path.as_encoded_bytes().strip_prefix(b"@") {
+        #[cfg(target_os = "android")]
+        use std::os::android::net::SocketAddrExt;
+        #[cfg(target_os = "linux")]
+        use std::os::linux::net::SocketAddrExt;
+
+        let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+        sock.send_to_addr(state.as_bytes(), &addr)?;
+        return Ok(());
+    }
+    sock.send_to(state.as_bytes(), path)?;
+    Ok(())
+}
+
+/// how often to tell the service manager we are still alive, if it wants us to.
+///
+/// this must be checked before forking, since the manager only expects it from the process it
+/// started
+pub fn watchdog_interval() -> Option<Duration> {
+    if let Some(pid) = std::env::var_os("WATCHDOG_PID")
+        && pid.to_str()?.parse() != Ok(std::process::id())
+    {
+        return None;
+    }
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    // half the timeout, like `sd_watchdog_enabled(3)` suggests
+    Some(Duration::from_micros(usec) / 2)
+}
+
+/// keep the service manager from restarting us, for as long as the runtime is responsive
+pub async fn watchdog(interval: Duration) {
+    loop {
+        if let Err(e) = notify("WATCHDOG=1") {
+            eprintln!("could not notify watchdog: {e}");
+        }
+        tokio::time::sleep(interval).await;
+    }
+}
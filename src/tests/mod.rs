@@ -1,48 +1,83 @@
 #![allow(clippy::unwrap_used)]
 
-use async_zip::tokio::read::fs::ZipFileReader;
+use async_zip::{
+    Compression, StringEncoding, ZipEntryBuilder, ZipString,
+    base::{read::mem, write::ZipFileWriter},
+    tokio::read::fs::ZipFileReader,
+};
 use std::{
     net::{Ipv6Addr, SocketAddr},
+    path::Path,
     pin::Pin,
     sync::Arc,
+    time::Duration,
 };
 use tokio::{
-    io::{AsyncWriteExt, copy},
-    net::{TcpListener, TcpStream},
+    io::{AsyncReadExt, AsyncWriteExt, copy},
+    net::{TcpListener, TcpStream, UnixStream},
 };
 use tokio_rustls::{
     TlsAcceptor, TlsConnector,
     rustls::{
         ClientConfig, RootCertStore, ServerConfig,
         pki_types::{CertificateDer, PrivateKeyDer, ServerName, pem::PemObject},
+        server::danger::ClientCertVerifier,
     },
     server::TlsStream,
 };
 
-use crate::server::Server;
+use crate::{
+    server::{
+        Config, DuplicatePolicy, ErrorMeta, LoadError, MimeOverride, Rejection, Server,
+        check::Problem,
+    },
+    tls::AnyClientCert,
+};
 
-const CERT_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/tests/test.pem");
+pub const CERT_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/tests/test.pem");
 const KEY_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/tests/test.key");
 const ZIP_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/tests/test.zip");
+/// a certificate authority, and a client certificate it signed
+const CA_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/tests/test-ca.pem");
+const CLIENT_CERT_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/tests/test-client.pem");
+const CLIENT_KEY_PATH: &str = concat!(env!("CARGO_MANIFEST_DIR"), "/src/tests/test-client.key");
 
-async fn serve_tls<F>(callback: F) -> SocketAddr
-where
-    F: Fn(TlsStream<TcpStream>) -> Pin<Box<dyn Future<Output = ()> + Send + 'static>>
-        + Send
-        + Clone
-        + 'static,
-{
+fn acceptor() -> TlsAcceptor {
+    let verifier = AnyClientCert::new(ServerConfig::builder().crypto_provider().clone());
+    acceptor_with(Arc::new(verifier))
+}
+
+fn acceptor_with(verifier: Arc<dyn ClientCertVerifier>) -> TlsAcceptor {
     let cert = CertificateDer::pem_file_iter(CERT_PATH)
         .unwrap()
         .collect::<Result<Vec<_>, _>>()
         .unwrap();
     let key = PrivateKeyDer::from_pem_file(KEY_PATH).unwrap();
     let config = ServerConfig::builder()
-        .with_no_client_auth()
+        .with_client_cert_verifier(verifier)
         .with_single_cert(cert, key)
         .unwrap();
-    let acceptor = TlsAcceptor::from(Arc::new(config));
-    let listener = TcpListener::bind("[::1]:0").await.unwrap();
+    TlsAcceptor::from(Arc::new(config))
+}
+
+async fn serve_tls<F>(callback: F) -> SocketAddr
+where
+    F: Fn(TlsStream<TcpStream>) -> Pin<Box<dyn Future<Output = ()> + Send + 'static>>
+        + Send
+        + Clone
+        + 'static,
+{
+    serve_tls_on(acceptor(), "[::1]:0", callback).await
+}
+
+async fn serve_tls_on<F>(acceptor: TlsAcceptor, bind: &str, callback: F) -> SocketAddr
+where
+    F: Fn(TlsStream<TcpStream>) -> Pin<Box<dyn Future<Output = ()> + Send + 'static>>
+        + Send
+        + Clone
+        + 'static,
+{
+    let listener = TcpListener::bind(bind).await.unwrap();
     let addr = listener.local_addr().unwrap();
 
     tokio::spawn(async move {
@@ -52,8 +87,10 @@ where
             let callback = callback.clone();
 
             tokio::spawn(async move {
-                let stream = acceptor.accept(sock).await.unwrap();
-                callback(stream).await;
+                // clients may be refused, such as without a certificate the server trusts
+                if let Ok(stream) = acceptor.accept(sock).await {
+                    callback(stream).await;
+                }
             });
         }
     });
@@ -61,20 +98,109 @@ where
     addr
 }
 
+/// serve the test zip with a [`Server`]
+async fn serve_zip(config: Config) -> SocketAddr {
+    serve_zip_on(acceptor(), "[::1]:0", config).await
+}
+
+async fn serve_zip_on(acceptor: TlsAcceptor, bind: &str, config: Config) -> SocketAddr {
+    let zip = ZipFileReader::new(ZIP_PATH).await.unwrap();
+    let srv = Arc::new(Server::load(zip, config).await.unwrap());
+    serve_tls_on(acceptor, bind, move |s| {
+        let srv = srv.clone();
+        Box::pin(async move {
+            _ = srv.handle_connection(s).await;
+        })
+    })
+    .await
+}
+
+/// a stored entry, for [`zip_of`] and [`serve_entries`]
+fn entry(name: &str) -> ZipEntryBuilder {
+    ZipEntryBuilder::new(name.into(), Compression::Stored)
+}
+
+/// a zip holding each entry with its contents
+async fn zip_of<'a>(entries: impl IntoIterator<Item = (ZipEntryBuilder, &'a [u8])>) -> Vec<u8> {
+    let mut writer = ZipFileWriter::with_tokio(vec![]);
+    for (entry, data) in entries {
+        writer.write_entry_whole(entry, data).await.unwrap();
+    }
+    writer.close().await.unwrap().into_inner()
+}
+
+/// a [`Server`] for a zip in memory holding each entry with its contents
+async fn serve_entries<'a>(
+    entries: impl IntoIterator<Item = (ZipEntryBuilder, &'a [u8])>,
+    config: Config,
+) -> Server {
+    let zip = mem::ZipFileReader::new(zip_of(entries).await)
+        .await
+        .unwrap();
+    Server::load(zip, config).await.unwrap()
+}
+
+/// request a url straight from a [`Server`], without tls
+async fn get(srv: &Server, url: &str) -> Vec<u8> {
+    let (mut client, server) = UnixStream::pair().unwrap();
+    client
+        .write_all(format!("{url}\r\n").as_bytes())
+        .await
+        .unwrap();
+    let mut out = Vec::new();
+    let (_, read) = tokio::join!(srv.handle_connection(server), copy(&mut client, &mut out));
+    read.unwrap();
+    out
+}
+
 async fn request(addr: SocketAddr, req: &[u8]) -> Result<Vec<u8>, std::io::Error> {
+    request_as(addr, req, false).await
+}
+
+/// a client trusting the test certificate, optionally using it as a client certificate too
+fn connector(identity: bool) -> TlsConnector {
+    connector_as(identity.then_some((CERT_PATH, KEY_PATH)))
+}
+
+/// a client trusting the test certificate, with an optional client certificate and key
+fn connector_as(identity: Option<(&str, &str)>) -> TlsConnector {
     let mut trust = RootCertStore::empty();
     trust
         .add(CertificateDer::from_pem_file(CERT_PATH).unwrap())
         .unwrap();
-    let config = ClientConfig::builder()
-        .with_root_certificates(trust)
-        .with_no_client_auth();
-    let connector = TlsConnector::from(Arc::new(config));
+    let config = ClientConfig::builder().with_root_certificates(trust);
+    let config = if let Some((cert, key)) = identity {
+        let cert = CertificateDer::pem_file_iter(cert)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let key = PrivateKeyDer::from_pem_file(key).unwrap();
+        config.with_client_auth_cert(cert, key).unwrap()
+    } else {
+        config.with_no_client_auth()
+    };
+    TlsConnector::from(Arc::new(config))
+}
+
+/// make a request, optionally using the test certificate as a client certificate
+async fn request_as(
+    addr: SocketAddr,
+    req: &[u8],
+    identity: bool,
+) -> Result<Vec<u8>, std::io::Error> {
+    request_with(addr, req, &connector(identity)).await
+}
+
+async fn request_with(
+    addr: SocketAddr,
+    req: &[u8],
+    connector: &TlsConnector,
+) -> Result<Vec<u8>, std::io::Error> {
     let sn = ServerName::from(Ipv6Addr::from_bits(1));
     let sock = TcpStream::connect(&addr).await.unwrap();
-    let mut stream = connector.connect(sn, sock).await.unwrap();
+    let mut stream = connector.connect(sn, sock).await?;
 
-    stream.write_all(req).await.unwrap();
+    stream.write_all(req).await?;
 
     let mut out = Vec::new();
     copy(&mut stream, &mut out).await?;
@@ -83,15 +209,7 @@ async fn request(addr: SocketAddr, req: &[u8]) -> Result<Vec<u8>, std::io::Error
 
 #[tokio::test]
 async fn index() {
-    let zip = ZipFileReader::new(ZIP_PATH).await.unwrap();
-    let srv = Arc::new(Server::from_zip(zip));
-    let addr = serve_tls(move |s| {
-        let srv = srv.clone();
-        Box::pin(async move {
-            srv.handle_connection(s).await;
-        })
-    })
-    .await;
+    let addr = serve_zip(Config::default()).await;
     assert_eq!(
         request(addr, b"gemini://localhost/\r\n").await.unwrap(),
         b"20 text/gemini\r\nhewwo world\n"
@@ -104,15 +222,7 @@ async fn index() {
 
 #[tokio::test]
 async fn length() {
-    let zip = ZipFileReader::new(ZIP_PATH).await.unwrap();
-    let srv = Arc::new(Server::from_zip(zip));
-    let addr = serve_tls(move |s| {
-        let srv = srv.clone();
-        Box::pin(async move {
-            srv.handle_connection(s).await;
-        })
-    })
-    .await;
+    let addr = serve_zip(Config::default()).await;
     let mut hhhh = b"gemini://localhost/".to_vec();
     hhhh.extend_from_slice(&[b'h'; 1024]);
     let eol = b"\r\n";
@@ -132,6 +242,711 @@ async fn length() {
     );
 }
 
+#[tokio::test]
+async fn canonical_host() {
+    let addr = serve_zip(Config {
+        canonical_host: Some("example.com".to_string()),
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(
+        request(addr, b"gemini://[::1]/\r\n").await.unwrap(),
+        b"31 gemini://example.com/\r\n"
+    );
+    assert_eq!(
+        request(addr, b"gemini://localhost/\r\n").await.unwrap(),
+        b"20 text/gemini\r\nhewwo world\n"
+    );
+}
+
+#[tokio::test]
+async fn redirect_all_to() {
+    let addr = serve_zip(Config {
+        redirect_all_to: Some("gemini://new.example/old/".parse().unwrap()),
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(
+        request(addr, b"gemini://localhost/\r\n").await.unwrap(),
+        b"31 gemini://new.example/old/\r\n"
+    );
+    assert_eq!(
+        request(addr, b"gemini://localhost/gone/away.gmi\r\n")
+            .await
+            .unwrap(),
+        b"31 gemini://new.example/old/gone/away.gmi\r\n"
+    );
+}
+
+#[tokio::test]
+async fn require_client_cert() {
+    let addr = serve_zip(Config {
+        require_client_cert: true,
+        redirect_all_to: Some("gemini://new.example/".parse().unwrap()),
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(
+        request(addr, b"gemini://localhost/\r\n").await.unwrap(),
+        b"60 client certificate required\r\n"
+    );
+    assert_eq!(
+        request_as(addr, b"gemini://localhost/\r\n", true)
+            .await
+            .unwrap(),
+        b"31 gemini://new.example/\r\n"
+    );
+}
+
+#[tokio::test]
+async fn proxy_allow() {
+    let upstream = serve_zip(Config::default()).await;
+    let addr = serve_zip(Config {
+        proxy_allow: vec![format!("[::1]:{}", upstream.port())],
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(
+        request(
+            addr,
+            format!("gemini://[::1]:{}/\r\n", upstream.port()).as_bytes()
+        )
+        .await
+        .unwrap(),
+        b"20 text/gemini\r\nhewwo world\n"
+    );
+    // other hosts, and other ports on an allowed host, are served from our own zip as usual
+    assert_eq!(
+        request(addr, b"gemini://example.com/\r\n").await.unwrap(),
+        b"20 text/gemini\r\nhewwo world\n"
+    );
+    assert_eq!(
+        request(addr, b"gemini://[::1]:1/\r\n").await.unwrap(),
+        b"20 text/gemini\r\nhewwo world\n"
+    );
+}
+
+#[tokio::test]
+async fn shared_known_hosts() {
+    let v6 = serve_zip(Config::default()).await;
+    let v4 = serve_zip_on(acceptor(), "127.0.0.1:0", Config::default()).await;
+
+    let known = std::env::temp_dir().join(format!("redgem-known-{}", std::process::id()));
+    _ = std::fs::remove_file(&known);
+    let config = Config {
+        proxy_allow: vec![
+            format!("[::1]:{}", v6.port()),
+            format!("127.0.0.1:{}", v4.port()),
+        ],
+        known_hosts: Some(known.clone()),
+        ..Default::default()
+    };
+    let first = Server::unloaded(config.clone()).unwrap();
+    let second = first.sibling(config);
+    for (srv, url) in [
+        (&first, format!("gemini://[::1]:{}/", v6.port())),
+        (&second, format!("gemini://127.0.0.1:{}/", v4.port())),
+    ] {
+        assert_eq!(get(srv, &url).await, b"20 text/gemini\r\nhewwo world\n");
+    }
+    // saving after learning the second host kept the first one
+    let fp = "bcc54e459fa8b2b51bc880e3405446004ed9e8dbbb1ffbeb56070356223a8eeb";
+    assert_eq!(
+        std::fs::read_to_string(&known).unwrap(),
+        format!("127.0.0.1 {fp}\n::1 {fp}\n")
+    );
+    std::fs::remove_file(&known).unwrap();
+}
+
+#[tokio::test]
+async fn gemlog() {
+    let addr = serve_zip(Config {
+        gemlog: Some("gemlog".to_string()),
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(
+        request(addr, b"gemini://localhost/gemlog/\r\n")
+            .await
+            .unwrap(),
+        b"20 text/gemini\r\n# gemlog\n\n\
+        => /gemlog/2026-03-04-second.gmi 2026-03-04 second\n\
+        => /gemlog/2026-01-02-first-post.gmi 2026-01-02 first post\n\
+        \n## archive\n\n\
+        => /gemlog/2026/ 2026\n"
+    );
+    assert_eq!(
+        request(addr, b"gemini://localhost/gemlog/2026/\r\n")
+            .await
+            .unwrap(),
+        b"20 text/gemini\r\n# gemlog 2026\n\n\
+        => /gemlog/2026/03/ 2026-03\n\
+        => /gemlog/2026/01/ 2026-01\n\
+        \n\
+        => /gemlog/2026-03-04-second.gmi 2026-03-04 second\n\
+        => /gemlog/2026-01-02-first-post.gmi 2026-01-02 first post\n"
+    );
+    assert_eq!(
+        request(addr, b"gemini://localhost/gemlog/2026/01/\r\n")
+            .await
+            .unwrap(),
+        b"20 text/gemini\r\n# gemlog 2026-01\n\n\
+        => /gemlog/2026-01-02-first-post.gmi 2026-01-02 first post\n"
+    );
+}
+
+#[tokio::test]
+async fn sitemap() {
+    let addr = serve_zip(Config {
+        sitemap: true,
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(
+        request(addr, b"gemini://localhost/sitemap.gmi\r\n")
+            .await
+            .unwrap(),
+        b"20 text/gemini\r\n# sitemap\n\n\
+        => /gemlog/2026-01-02-first-post.gmi /gemlog/2026-01-02-first-post.gmi (13 bytes, 2026-01-02 12:00)\n\
+        => /gemlog/2026-03-04-second.gmi /gemlog/2026-03-04-second.gmi (9 bytes, 2026-03-04 12:00)\n\
+        => /index.gmi /index.gmi (12 bytes, 1980-01-01 12:01)\n"
+    );
+}
+
+#[tokio::test]
+async fn favicon() {
+    let addr = serve_zip(Config {
+        favicon: Some("🦊".to_string()),
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(
+        request(addr, b"gemini://localhost/favicon.txt\r\n")
+            .await
+            .unwrap(),
+        "20 text/plain\r\n🦊".as_bytes()
+    );
+}
+
+#[tokio::test]
+async fn checksums() {
+    let addr = serve_zip(Config {
+        checksums: true,
+        deny: vec!["2026-01-02-first-post.gmi".to_string()],
+        authorized: vec![
+            "/gemlog/2026-03-04-second.gmi=bcc54e459fa8b2b51bc880e3405446004ed9e8dbbb1ffbeb56070356223a8eeb"
+                .parse()
+                .unwrap(),
+        ],
+        ..Default::default()
+    })
+    .await;
+    // asked for twice, to get both the freshly worked out and the cached listing. having the
+    // certificate for a restricted file does not add it either, since the listing is shared
+    for identity in [true, false] {
+        assert_eq!(
+            request_as(addr, b"gemini://localhost/sha256sums.txt\r\n", identity)
+                .await
+                .unwrap(),
+            b"20 text/plain\r\n\
+            68a025eda90e527e70ecb53bd44794f5fbe83a1df47825a0a9d53f2da795777d  index.gmi\n"
+        );
+    }
+}
+
+#[tokio::test]
+async fn changes() {
+    let addr = serve_zip(Config {
+        changes: true,
+        sitemap: true,
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(
+        request(addr, b"gemini://localhost/changes.gmi\r\n")
+            .await
+            .unwrap(),
+        b"20 text/gemini\r\n# recent changes\n\n\
+        => /gemlog/2026-03-04-second.gmi 2026-03-04 12:00 /gemlog/2026-03-04-second.gmi\n\
+        => /gemlog/2026-01-02-first-post.gmi 2026-01-02 12:00 /gemlog/2026-01-02-first-post.gmi\n\
+        => /index.gmi 1980-01-01 12:01 /index.gmi\n"
+    );
+}
+
+#[tokio::test]
+async fn changes_authorized() {
+    let addr = serve_zip(Config {
+        changes: true,
+        authorized: vec![
+            "/gemlog/2026-03-04-second.gmi=bcc54e459fa8b2b51bc880e3405446004ed9e8dbbb1ffbeb56070356223a8eeb"
+                .parse()
+                .unwrap(),
+        ],
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(
+        request(addr, b"gemini://localhost/changes.gmi\r\n")
+            .await
+            .unwrap(),
+        b"20 text/gemini\r\n# recent changes\n\n\
+        => /gemlog/2026-01-02-first-post.gmi 2026-01-02 12:00 /gemlog/2026-01-02-first-post.gmi\n\
+        => /index.gmi 1980-01-01 12:01 /index.gmi\n"
+    );
+}
+
+#[tokio::test]
+async fn hidden() {
+    let addr = serve_zip(Config::default()).await;
+    assert_eq!(
+        request(addr, b"gemini://localhost/.secret.gmi\r\n")
+            .await
+            .unwrap(),
+        b"51 not found\r\n"
+    );
+
+    let addr = serve_zip(Config {
+        serve_hidden: true,
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(
+        request(addr, b"gemini://localhost/.secret.gmi\r\n")
+            .await
+            .unwrap(),
+        b"20 text/gemini\r\nshh\n"
+    );
+}
+
+#[tokio::test]
+async fn permissions() {
+    for (ignore_permissions, draft) in [
+        (false, &b"51 not found\r\n"[..]),
+        (true, b"20 text/gemini\r\nhi\n"),
+    ] {
+        let entries = [
+            ("public.gmi", 0o100_644),
+            ("draft.gmi", 0o100_640),
+            ("dos.gmi", 0),
+        ]
+        .map(|(name, mode)| (entry(name).unix_permissions(mode), &b"hi\n"[..]));
+        let config = Config {
+            ignore_permissions,
+            ..Default::default()
+        };
+        let srv = serve_entries(entries, config).await;
+        for (url, expected) in [
+            (
+                "gemini://localhost/public.gmi",
+                &b"20 text/gemini\r\nhi\n"[..],
+            ),
+            ("gemini://localhost/draft.gmi", draft),
+            ("gemini://localhost/dos.gmi", b"20 text/gemini\r\nhi\n"),
+        ] {
+            assert_eq!(get(&srv, url).await, expected);
+        }
+    }
+}
+
+#[tokio::test]
+async fn duplicates() {
+    let entries = || {
+        [
+            (entry("page.gmi"), &b"one\n"[..]),
+            (entry("page.gmi"), b"two\n"),
+        ]
+    };
+    for (duplicates, expected) in [
+        (DuplicatePolicy::First, &b"20 text/gemini\r\none\n"[..]),
+        (DuplicatePolicy::Last, b"20 text/gemini\r\ntwo\n"),
+    ] {
+        let config = Config {
+            duplicates,
+            ..Default::default()
+        };
+        let srv = serve_entries(entries(), config).await;
+        assert_eq!(get(&srv, "gemini://localhost/page.gmi").await, expected);
+    }
+
+    let zip = mem::ZipFileReader::new(zip_of(entries()).await)
+        .await
+        .unwrap();
+    let config = Config {
+        duplicates: DuplicatePolicy::Error,
+        ..Default::default()
+    };
+    assert!(matches!(
+        Server::load(zip, config).await,
+        Err(LoadError::Duplicate(name)) if name == "/page.gmi"
+    ));
+}
+
+#[tokio::test]
+async fn unicode_normalization() {
+    // é as e followed by a combining accent, like macos names files
+    let srv = serve_entries(
+        [(entry("cafe\u{301}.gmi"), &b"hi\n"[..])],
+        Config::default(),
+    )
+    .await;
+    for url in [
+        "gemini://localhost/caf%C3%A9.gmi",
+        "gemini://localhost/cafe%CC%81.gmi",
+    ] {
+        assert_eq!(get(&srv, url).await, b"20 text/gemini\r\nhi\n");
+    }
+}
+
+#[tokio::test]
+async fn cp437_names() {
+    let name = ZipString::new(b"caf\x82.gmi".to_vec(), StringEncoding::Raw);
+    let entries = [
+        (
+            ZipEntryBuilder::new(name, Compression::Stored),
+            &b"hi\n"[..],
+        ),
+        (entry("../up.gmi"), b"hi\n"),
+    ];
+    let config = Config {
+        serve_hidden: true,
+        ..Default::default()
+    };
+    let srv = serve_entries(entries, config).await;
+    assert_eq!(srv.list(), ["/caf%C3%A9.gmi\ttext/gemini\t3\t3\t-"]);
+}
+
+#[tokio::test]
+async fn deny() {
+    let addr = serve_zip(Config {
+        gemlog: Some("gemlog".to_string()),
+        deny: vec!["/gemlog/**".to_string()],
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(
+        request(addr, b"gemini://localhost/gemlog/2026-03-04-second.gmi\r\n")
+            .await
+            .unwrap(),
+        b"51 not found\r\n"
+    );
+    assert_eq!(
+        request(addr, b"gemini://localhost/gemlog/\r\n")
+            .await
+            .unwrap(),
+        b"51 not found\r\n"
+    );
+    assert_eq!(
+        request(addr, b"gemini://localhost/\r\n").await.unwrap(),
+        b"20 text/gemini\r\nhewwo world\n"
+    );
+}
+
+#[tokio::test]
+async fn max_file_size() {
+    let addr = serve_zip(Config {
+        max_file_size: Some(10),
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(
+        request(addr, b"gemini://localhost/\r\n").await.unwrap(),
+        b"50 file too large to serve\r\n"
+    );
+    assert_eq!(
+        request(addr, b"gemini://localhost/gemlog/2026-03-04-second.gmi\r\n")
+            .await
+            .unwrap(),
+        b"20 text/gemini\r\n# second\n"
+    );
+}
+
+#[tokio::test]
+async fn clean_urls() {
+    let addr = serve_zip(Config {
+        clean_urls: true,
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(
+        request(addr, b"gemini://localhost/gemlog/2026-03-04-second\r\n")
+            .await
+            .unwrap(),
+        b"20 text/gemini\r\n# second\n"
+    );
+    assert_eq!(
+        request(addr, b"gemini://localhost/gemlog/2026-03-04-second/\r\n")
+            .await
+            .unwrap(),
+        b"51 not found\r\n"
+    );
+    assert_eq!(
+        request(addr, b"gemini://localhost/.secret\r\n")
+            .await
+            .unwrap(),
+        b"51 not found\r\n"
+    );
+}
+
+#[tokio::test]
+async fn error_meta() {
+    let addr = serve_zip(Config {
+        error_meta: vec![
+            "51=nothing here".parse().unwrap(),
+            "51=nyothing here".parse().unwrap(),
+        ],
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(
+        request(addr, b"gemini://localhost/meow\r\n").await.unwrap(),
+        b"51 nyothing here\r\n"
+    );
+    assert_eq!(
+        request(addr, b"gemini://localhost/?meow\r\n")
+            .await
+            .unwrap(),
+        b"50 no input expected, silly\r\n"
+    );
+    assert!("20=meow".parse::<ErrorMeta>().is_err());
+    assert!("51=meow\r\n".parse::<ErrorMeta>().is_err());
+}
+
+#[tokio::test]
+async fn mime() {
+    let addr = serve_zip(Config {
+        mime: vec![
+            "gmi=text/plain".parse().unwrap(),
+            ".GMI=text/gemini; lang=en".parse().unwrap(),
+        ],
+        ..Default::default()
+    })
+    .await;
+    assert_eq!(
+        request(addr, b"gemini://localhost/index.gmi\r\n")
+            .await
+            .unwrap(),
+        b"20 text/gemini; lang=en\r\nhewwo world\n"
+    );
+    assert!("gmi".parse::<MimeOverride>().is_err());
+    assert!("gmi=text".parse::<MimeOverride>().is_err());
+    assert!("gmi=text/gemini\r\n".parse::<MimeOverride>().is_err());
+}
+
+#[tokio::test]
+async fn localized_errors() {
+    let entries = [(
+        entry(".redgem/errors.fr.toml"),
+        &b"51 = \"page introuvable\"\n"[..],
+    )];
+    let config = Config {
+        lang: Some("fr".to_string()),
+        ..Default::default()
+    };
+    let srv = serve_entries(entries, config).await;
+    assert_eq!(
+        get(&srv, "gemini://localhost/meow").await,
+        b"51 page introuvable\r\n"
+    );
+}
+
+#[tokio::test]
+async fn lang_pref() {
+    let entries = [
+        ("page.gmi", "hello\n"),
+        ("page.fr.gmi", "bonjour\n"),
+        ("page.de.gmi", "hallo\n"),
+        ("notes.txt", "notes\n"),
+        ("notes.de.txt", "notizen\n"),
+    ]
+    .map(|(name, text)| (entry(name), text.as_bytes()));
+    let config = Config {
+        lang_pref: vec!["es".to_string(), "de".to_string(), "fr".to_string()],
+        ..Default::default()
+    };
+    let srv = serve_entries(entries, config).await;
+
+    for (url, expected) in [
+        (
+            "gemini://localhost/page.gmi",
+            &b"20 text/gemini; lang=de\r\nhallo\n"[..],
+        ),
+        (
+            "gemini://localhost/page.fr.gmi",
+            b"20 text/gemini\r\nbonjour\n",
+        ),
+        (
+            "gemini://localhost/notes.txt",
+            b"20 text/plain\r\nnotizen\n",
+        ),
+    ] {
+        assert_eq!(get(&srv, url).await, expected);
+    }
+}
+
+#[tokio::test]
+async fn footer() {
+    let entries = [
+        ("page.gmi", "hello\n"),
+        ("notes.txt", "notes\n"),
+        ("post.gmi", "post\n"),
+        ("post.de.gmi", "beitrag\n"),
+        ("old.gem", "old\n"),
+    ]
+    .map(|(name, text)| (entry(name), text.as_bytes()));
+    let config = Config {
+        footer: Some(b"=> mailto:me@example.com contact\n"[..].into()),
+        lang_pref: vec!["de".to_string()],
+        mime: vec!["gem=text/gemini; lang=en".parse().unwrap()],
+        ..Default::default()
+    };
+    let srv = serve_entries(entries, config).await;
+
+    for (url, expected) in [
+        (
+            "gemini://localhost/page.gmi",
+            &b"20 text/gemini\r\nhello\n=> mailto:me@example.com contact\n"[..],
+        ),
+        ("gemini://localhost/notes.txt", b"20 text/plain\r\nnotes\n"),
+        (
+            "gemini://localhost/post.gmi",
+            b"20 text/gemini; lang=de\r\nbeitrag\n=> mailto:me@example.com contact\n",
+        ),
+        (
+            "gemini://localhost/old.gem",
+            b"20 text/gemini; lang=en\r\nold\n=> mailto:me@example.com contact\n",
+        ),
+        ("gemini://localhost/nope.gmi", b"51 not found\r\n"),
+    ] {
+        assert_eq!(get(&srv, url).await, expected);
+    }
+}
+
+#[tokio::test]
+async fn buffer_size() {
+    let config = Config {
+        buffer_size: Some(0),
+        ..Default::default()
+    };
+    assert!(config.to_string().contains("\nbuffer-size: 1024\n"));
+    let srv = serve_entries([(entry("index.gmi"), &b"hi\n"[..])], config).await;
+    assert_eq!(
+        get(&srv, "gemini://localhost/").await,
+        b"20 text/gemini\r\nhi\n"
+    );
+}
+
+#[test]
+fn print_config() {
+    let config = Config {
+        deny: vec!["*.bak".to_string(), "/drafts".to_string()],
+        mime: vec!["x=text/plain".parse().unwrap()],
+        header_timeout: Some(Duration::from_secs(5)),
+        ..Default::default()
+    };
+    let printed = config.to_string();
+    assert!(printed.contains("\ndeny: *.bak\ndeny: /drafts\n"));
+    assert!(printed.contains("\nmime: x=text/plain\n"));
+    assert!(printed.contains("\nheader-timeout: 5s\n"));
+    assert!(printed.contains("\nresponse-timeout: 600s\n"));
+    assert!(printed.contains("\nbuffer-size: 8192\n"));
+}
+
+#[tokio::test]
+async fn authorized() {
+    let addr = serve_zip(Config {
+        authorized: vec![
+            "/gemlog=bcc54e459fa8b2b51bc880e3405446004ed9e8dbbb1ffbeb56070356223a8eeb"
+                .parse()
+                .unwrap(),
+            "/gemlog/2026-03-04-second.gmi=0000000000000000000000000000000000000000000000000000000000000000"
+                .parse()
+                .unwrap(),
+        ],
+        sitemap: true,
+        changes: true,
+        gemlog: Some("gemlog".to_string()),
+        ..Default::default()
+    })
+    .await;
+    let first = b"gemini://localhost/gemlog/2026-01-02-first-post.gmi\r\n";
+    let second = b"gemini://localhost/gemlog/2026-03-04-second.gmi\r\n";
+    assert_eq!(
+        request(addr, first).await.unwrap(),
+        b"60 client certificate required\r\n"
+    );
+    assert_eq!(
+        request_as(addr, first, true).await.unwrap(),
+        b"20 text/gemini\r\n# first post\n"
+    );
+    assert_eq!(
+        request_as(addr, second, true).await.unwrap(),
+        b"61 certificate not authorized\r\n"
+    );
+    // the generated listings are the same for everyone, so restricted files stay out of them
+    for identity in [true, false] {
+        assert_eq!(
+            request_as(addr, b"gemini://localhost/sitemap.gmi\r\n", identity)
+                .await
+                .unwrap(),
+            b"20 text/gemini\r\n# sitemap\n\n\
+            => /index.gmi /index.gmi (12 bytes, 1980-01-01 12:01)\n"
+        );
+        assert_eq!(
+            request_as(addr, b"gemini://localhost/changes.gmi\r\n", identity)
+                .await
+                .unwrap(),
+            b"20 text/gemini\r\n# recent changes\n\n\
+            => /index.gmi 1980-01-01 12:01 /index.gmi\n"
+        );
+    }
+    assert_eq!(
+        request_as(addr, b"gemini://localhost/gemlog/\r\n", true)
+            .await
+            .unwrap(),
+        b"20 text/gemini\r\n# gemlog\n\n"
+    );
+}
+
+#[tokio::test]
+async fn client_ca() {
+    let provider = ServerConfig::builder().crypto_provider().clone();
+    let verifier = crate::tls::ca_client_verifier(provider, Path::new(CA_PATH)).unwrap();
+    let addr = serve_zip_on(
+        acceptor_with(verifier),
+        "[::1]:0",
+        Config {
+            authorized: vec![
+                "/gemlog=ce114ddc6436ddf93071da6f8f2a30a7cf246c2e655a17be99d14814d8b38926"
+                    .parse()
+                    .unwrap(),
+                "/gemlog/2026-03-04-second.gmi=0000000000000000000000000000000000000000000000000000000000000000"
+                    .parse()
+                    .unwrap(),
+            ],
+            ..Default::default()
+        },
+    )
+    .await;
+    let first = b"gemini://localhost/gemlog/2026-01-02-first-post.gmi\r\n";
+    let second = b"gemini://localhost/gemlog/2026-03-04-second.gmi\r\n";
+    let signed = connector_as(Some((CLIENT_CERT_PATH, CLIENT_KEY_PATH)));
+    assert_eq!(
+        request_with(addr, first, &signed).await.unwrap(),
+        b"20 text/gemini\r\n# first post\n"
+    );
+    assert_eq!(
+        request_with(addr, second, &signed).await.unwrap(),
+        b"61 certificate not authorized\r\n"
+    );
+    // anonymous clients still get through the handshake to be told what they need
+    assert_eq!(
+        request(addr, first).await.unwrap(),
+        b"60 client certificate required\r\n"
+    );
+    // but self-signed certificates are refused
+    assert!(request_as(addr, first, true).await.is_err());
+}
+
 /// make sure rustls' behavior of not sending `close_notify` when [`TlsStream`] is dropped without
 /// calling shutdown does not change. we need to not send it if we timeout before the client
 /// consumes the whole response, to signify that the response has been truncated
@@ -159,3 +974,602 @@ fn zip_swap_runtime() {
         assert_eq!(out, "hewwo world\n");
     });
 }
+
+#[tokio::test]
+async fn unix_socket() {
+    let zip = ZipFileReader::new(ZIP_PATH).await.unwrap();
+    let srv = Server::load(zip, Config::default()).await.unwrap();
+    let (client, server) = UnixStream::pair().unwrap();
+
+    tokio::spawn(async move {
+        let stream = acceptor().accept(server).await.unwrap();
+        _ = srv.handle_connection(stream).await;
+    });
+
+    let sn = ServerName::from(Ipv6Addr::from_bits(1));
+    let mut stream = connector(false).connect(sn, client).await.unwrap();
+    stream.write_all(b"gemini://localhost/\r\n").await.unwrap();
+    let mut out = Vec::new();
+    copy(&mut stream, &mut out).await.unwrap();
+    assert_eq!(out, b"20 text/gemini\r\nhewwo world\n");
+}
+
+#[tokio::test]
+async fn plaintext() {
+    let zip = ZipFileReader::new(ZIP_PATH).await.unwrap();
+    let srv = Server::load(zip, Config::default()).await.unwrap();
+    let (mut client, server) = UnixStream::pair().unwrap();
+
+    tokio::spawn(async move {
+        _ = srv.handle_connection(server).await;
+    });
+
+    client
+        .write_all(b"gemini://example.com/\r\n")
+        .await
+        .unwrap();
+    let mut out = Vec::new();
+    copy(&mut client, &mut out).await.unwrap();
+    assert_eq!(out, b"20 text/gemini\r\nhewwo world\n");
+}
+
+#[tokio::test]
+async fn outcome() {
+    let zip = ZipFileReader::new(ZIP_PATH).await.unwrap();
+    let srv = Arc::new(Server::load(zip, Config::default()).await.unwrap());
+
+    for (req, status, rejection) in [
+        (&b"gemini://localhost/\r\n"[..], 20, None),
+        (b"gemini://localhost/nope\r\n", 51, None),
+        (b"meow\r\n", 59, Some(Rejection::Malformed)),
+        (b"https://localhost/\r\n", 53, Some(Rejection::Misdirected)),
+        (&[b'a'; 1100], 59, Some(Rejection::TooLong)),
+    ] {
+        let (mut client, server) = UnixStream::pair().unwrap();
+        let handle = tokio::spawn({
+            let srv = srv.clone();
+            async move { srv.handle_connection(server).await }
+        });
+        client.write_all(req).await.unwrap();
+        let mut out = Vec::new();
+        _ = copy(&mut client, &mut out).await;
+        let outcome = handle.await.unwrap();
+        assert_eq!(outcome.status, status);
+        assert_eq!(outcome.bytes, out.len() as u64);
+        assert_eq!(outcome.rejection, rejection);
+        assert_eq!(outcome.url.is_some(), rejection.is_none());
+    }
+}
+
+/// a connection that sends a request and remembers how much each write was, or never finishes
+/// writing anything if it is stalled
+struct Recorder {
+    req: &'static [u8],
+    writes: Vec<usize>,
+    stalled: bool,
+}
+
+impl tokio::io::AsyncRead for Recorder {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        Pin::new(&mut self.req).poll_read(cx, buf)
+    }
+}
+
+impl tokio::io::AsyncWrite for Recorder {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _: &mut std::task::Context<'_>,
+        buf: &[u8],
+    ) -> std::task::Poll<std::io::Result<usize>> {
+        if self.stalled {
+            return std::task::Poll::Pending;
+        }
+        self.writes.push(buf.len());
+        std::task::Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(
+        self: Pin<&mut Self>,
+        _: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        _: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+impl crate::server::Connection for &mut Recorder {}
+
+#[tokio::test]
+async fn single_write() {
+    let zip = ZipFileReader::new(ZIP_PATH).await.unwrap();
+    let srv = Server::load(zip, Config::default()).await.unwrap();
+    let mut conn = Recorder {
+        req: b"gemini://localhost/\r\n",
+        writes: vec![],
+        stalled: false,
+    };
+    let outcome = srv.handle_connection(&mut conn).await;
+    assert_eq!(conn.writes, [outcome.bytes.try_into().unwrap()]);
+}
+
+#[tokio::test]
+async fn header_timeout() {
+    let zip = ZipFileReader::new(ZIP_PATH).await.unwrap();
+    let config = Config {
+        header_timeout: Some(Duration::from_millis(50)),
+        ..Default::default()
+    };
+    let srv = Server::load(zip, config).await.unwrap();
+    let (mut client, server) = UnixStream::pair().unwrap();
+    client.write_all(b"gemini://local").await.unwrap();
+    let outcome = srv.handle_connection(server).await;
+    assert_eq!(outcome.rejection, Some(Rejection::Timeout));
+    let mut out = Vec::new();
+    client.read_to_end(&mut out).await.unwrap();
+    assert_eq!(out, b"40 timed out\r\n");
+}
+
+#[tokio::test]
+async fn idle_timeout() {
+    let zip = ZipFileReader::new(ZIP_PATH).await.unwrap();
+    let config = Config {
+        idle_timeout: Some(Duration::from_millis(50)),
+        ..Default::default()
+    };
+    let srv = Server::load(zip, config).await.unwrap();
+    let mut conn = Recorder {
+        req: b"gemini://localhost/\r\n",
+        writes: vec![],
+        stalled: true,
+    };
+    let outcome = tokio::time::timeout(Duration::from_secs(5), srv.handle_connection(&mut conn))
+        .await
+        .unwrap();
+    assert_eq!(outcome.status, 20);
+    assert_eq!(outcome.bytes, 0);
+}
+
+#[tokio::test]
+async fn unloaded() {
+    let srv = Arc::new(Server::unloaded(Config::default()).unwrap());
+    let addr = serve_tls({
+        let srv = srv.clone();
+        move |s| {
+            let srv = srv.clone();
+            Box::pin(async move {
+                _ = srv.handle_connection(s).await;
+            })
+        }
+    })
+    .await;
+    assert_eq!(
+        request(addr, b"gemini://localhost/\r\n").await.unwrap(),
+        b"41 index building, try again soon\r\n"
+    );
+    assert!(srv.has_authorized());
+
+    let zip = ZipFileReader::new(ZIP_PATH).await.unwrap();
+    srv.swap_zip(zip).await.unwrap();
+    assert_eq!(
+        request(addr, b"gemini://localhost/\r\n").await.unwrap(),
+        b"20 text/gemini\r\nhewwo world\n"
+    );
+    assert!(!srv.has_authorized());
+}
+
+#[tokio::test]
+async fn memory() {
+    let data = tokio::fs::read(ZIP_PATH).await.unwrap();
+    let zip = mem::ZipFileReader::new(data).await.unwrap();
+    let srv = Server::load(zip, Config::default()).await.unwrap();
+    let (mut client, server) = UnixStream::pair().unwrap();
+
+    tokio::spawn(async move {
+        _ = srv.handle_connection(server).await;
+    });
+
+    client.write_all(b"gemini://localhost/\r\n").await.unwrap();
+    let mut out = Vec::new();
+    copy(&mut client, &mut out).await.unwrap();
+    assert_eq!(out, b"20 text/gemini\r\nhewwo world\n");
+}
+
+#[tokio::test]
+async fn fetch() {
+    let addr = serve_zip(Config {
+        gemlog: Some("gemlog".to_string()),
+        ..Default::default()
+    })
+    .await;
+    let url = format!("gemini://[::1]:{}/gemlog", addr.port());
+
+    let connector = crate::fetch::connector(None).unwrap();
+
+    let mut out = Vec::new();
+    let header = crate::fetch::fetch(&connector, &url, false, &mut out)
+        .await
+        .unwrap();
+    assert_eq!(header, format!("31 {url}/"));
+
+    let mut out = Vec::new();
+    let header = crate::fetch::fetch(&connector, &url, true, &mut out)
+        .await
+        .unwrap();
+    assert_eq!(header, "20 text/gemini");
+    assert!(out.starts_with(b"# gemlog\n"));
+
+    // trusted on first use, then refused once the pinned certificate no longer matches
+    let known = std::env::temp_dir().join(format!("redgem-fetch-{}", std::process::id()));
+    _ = std::fs::remove_file(&known);
+    let connector = crate::fetch::connector(Some(&known)).unwrap();
+    let mut out = Vec::new();
+    crate::fetch::fetch(&connector, &url, true, &mut out)
+        .await
+        .unwrap();
+    assert_eq!(
+        std::fs::read_to_string(&known).unwrap(),
+        "::1 bcc54e459fa8b2b51bc880e3405446004ed9e8dbbb1ffbeb56070356223a8eeb\n"
+    );
+    std::fs::write(&known, format!("::1 {}\n", "0".repeat(64))).unwrap();
+    let connector = crate::fetch::connector(Some(&known)).unwrap();
+    assert!(
+        crate::fetch::fetch(&connector, &url, true, &mut out)
+            .await
+            .is_err()
+    );
+    std::fs::remove_file(&known).unwrap();
+}
+
+#[tokio::test]
+async fn check() {
+    let zip = ZipFileReader::new(ZIP_PATH).await.unwrap();
+    let problems = Server::load(zip, Config::default())
+        .await
+        .unwrap()
+        .check()
+        .await;
+    assert!(matches!(problems.as_slice(), [Problem::MissingIndex(dir)] if dir == "/gemlog"));
+
+    let zip = ZipFileReader::new(ZIP_PATH).await.unwrap();
+    let config = Config {
+        gemlog: Some("gemlog".to_string()),
+        ..Default::default()
+    };
+    assert!(
+        Server::load(zip, config)
+            .await
+            .unwrap()
+            .check()
+            .await
+            .is_empty()
+    );
+}
+
+#[tokio::test]
+async fn check_crc() {
+    let mut data = zip_of([
+        (entry("index.gmi"), &b"=> /\n"[..]),
+        (entry("meow.txt"), b"meow meow\n"),
+    ])
+    .await;
+    let at = data.windows(9).position(|w| w == b"meow meow").unwrap();
+    data[at..at + 9].copy_from_slice(b"woem woem");
+    let zip = mem::ZipFileReader::new(data).await.unwrap();
+
+    let srv = Server::load(zip, Config::default()).await.unwrap();
+    let problems = srv.check().await;
+    assert!(matches!(problems.as_slice(), [Problem::BadEntry(path)] if path == "/meow.txt"));
+}
+
+#[tokio::test]
+async fn list() {
+    let zip = ZipFileReader::new(ZIP_PATH).await.unwrap();
+    let list = Server::load(zip, Config::default()).await.unwrap().list();
+    assert_eq!(
+        list,
+        [
+            "/\ttext/gemini\t12\t12\tindex.gmi",
+            "/gemlog/2026-01-02-first-post.gmi\ttext/gemini\t13\t13\t-",
+            "/gemlog/2026-03-04-second.gmi\ttext/gemini\t9\t9\t-",
+            "/index.gmi\ttext/gemini\t12\t12\t-",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn swap_zip() {
+    let zip = ZipFileReader::new(ZIP_PATH).await.unwrap();
+    let srv = Arc::new(Server::load(zip, Config::default()).await.unwrap());
+
+    let mut writer = ZipFileWriter::with_tokio(vec![]);
+    let entry = ZipEntryBuilder::new("index.gmi".into(), Compression::Stored);
+    writer.write_entry_whole(entry, b"meow\n").await.unwrap();
+    let path = std::env::temp_dir().join(format!("redgem-swap-{}.zip", std::process::id()));
+    tokio::fs::write(&path, writer.close().await.unwrap().into_inner())
+        .await
+        .unwrap();
+
+    // a response that has already started keeps reading from the old zip
+    let (mut old, server) = UnixStream::pair().unwrap();
+    old.write_all(b"gemini://localhost/\r\n").await.unwrap();
+    let handle = tokio::spawn({
+        let srv = srv.clone();
+        async move { srv.handle_connection(server).await }
+    });
+    let mut out = vec![0; 16];
+    old.read_exact(&mut out).await.unwrap();
+
+    srv.swap_zip(ZipFileReader::new(&path).await.unwrap())
+        .await
+        .unwrap();
+    tokio::fs::remove_file(&path).await.unwrap();
+    handle.await.unwrap();
+    copy(&mut old, &mut out).await.unwrap();
+    assert_eq!(out, b"20 text/gemini\r\nhewwo world\n");
+
+    assert_eq!(
+        srv.list(),
+        [
+            "/\ttext/gemini\t5\t5\tindex.gmi",
+            "/index.gmi\ttext/gemini\t5\t5\t-"
+        ]
+    );
+}
+
+#[tokio::test]
+async fn export() {
+    let mut writer = ZipFileWriter::with_tokio(vec![]);
+    for (name, data) in [
+        ("index.gmi", &b"hewwo\n"[..]),
+        (".redgem/authorized", b""),
+        ("../escape.gmi", b"nope\n"),
+    ] {
+        let entry = ZipEntryBuilder::new(name.into(), Compression::Stored);
+        writer.write_entry_whole(entry, data).await.unwrap();
+    }
+    let zip = mem::ZipFileReader::new(writer.close().await.unwrap().into_inner())
+        .await
+        .unwrap();
+
+    let dir = std::env::temp_dir().join(format!("redgem-export-{}", std::process::id()));
+    let zip = crate::server::Zip::from(zip);
+    assert_eq!(zip.export(&dir.join("out")).await.unwrap(), (2, 1));
+    assert_eq!(
+        tokio::fs::read(dir.join("out/index.gmi")).await.unwrap(),
+        b"hewwo\n"
+    );
+    assert!(dir.join("out/.redgem/authorized").exists());
+    assert!(!dir.join("escape.gmi").exists());
+    tokio::fs::remove_dir_all(&dir).await.unwrap();
+}
+
+#[tokio::test]
+async fn byte_ranges() {
+    let data: Vec<u8> = (0..=255).collect();
+    #[cfg(feature = "deflate")]
+    let packed = Compression::Deflate;
+    #[cfg(not(feature = "deflate"))]
+    let packed = Compression::Stored;
+    // the extra field makes the data start somewhere other than right after the name
+    let data = zip_of(
+        [
+            ("stored.zip", Compression::Stored, &data[..]),
+            ("packed.zip", packed, &data),
+            ("notes.txt", Compression::Stored, b"hi\n"),
+        ]
+        .map(|(name, compression, data)| {
+            let entry = ZipEntryBuilder::new(name.into(), compression);
+            (entry.unix_permissions(0o100_644), data)
+        }),
+    )
+    .await;
+    let path = std::env::temp_dir().join(format!("redgem-ranges-{}.zip", std::process::id()));
+    tokio::fs::write(&path, &data).await.unwrap();
+
+    let config = Config {
+        byte_ranges: true,
+        ..Default::default()
+    };
+    // seeking works differently for zips on disk and in memory
+    for zip in [
+        crate::server::Zip::from(ZipFileReader::new(&path).await.unwrap()),
+        crate::server::Zip::from(mem::ZipFileReader::new(data).await.unwrap()),
+    ] {
+        let srv = Server::load(zip, config.clone()).await.unwrap();
+        for name in ["stored", "packed"] {
+            for (range, expected) in [
+                ("", &b"\x00\x01"[..]),
+                ("?bytes=0-1", b"\x00\x01"),
+                ("?bytes=250-", b"\xfa\xfb\xfc\xfd\xfe\xff"),
+                ("?bytes=254-1000", b"\xfe\xff"),
+                ("?bytes=100-100", b"\x64"),
+            ] {
+                let out = get(&srv, &format!("gemini://localhost/{name}.zip{range}")).await;
+                let body = out.strip_prefix(b"20 application/zip\r\n").unwrap();
+                if range.is_empty() {
+                    assert_eq!(body.len(), 256);
+                    assert!(body.starts_with(expected));
+                } else {
+                    assert_eq!(body, expected);
+                }
+            }
+        }
+
+        for url in [
+            "gemini://localhost/stored.zip?bytes=256-",
+            "gemini://localhost/notes.txt?bytes=0-1",
+        ] {
+            assert_eq!(get(&srv, url).await, b"59 cannot serve that byte range\r\n");
+        }
+    }
+    tokio::fs::remove_file(&path).await.unwrap();
+}
+
+#[cfg(feature = "daemon")]
+#[test]
+fn reopened_paths() {
+    use argh::FromArgs;
+
+    let mut opt = crate::Opt::from_args(
+        &["redgem"],
+        &[
+            "--chdir",
+            "/",
+            "--zip",
+            "capsule.zip",
+            "--known-hosts",
+            "known_hosts",
+            "--access-log",
+            "access.log",
+            "cert.pem",
+        ],
+    )
+    .unwrap();
+    let paths: Vec<_> = crate::reopened_paths(&mut opt).map(|p| p.clone()).collect();
+    assert_eq!(
+        paths,
+        ["capsule.zip", "known_hosts", "access.log"].map(std::path::PathBuf::from)
+    );
+
+    let mut opt = crate::Opt::from_args(&["redgem"], &["--access-log", "-", "cert.pem"]).unwrap();
+    assert_eq!(crate::reopened_paths(&mut opt).count(), 0);
+}
+
+#[cfg(feature = "recvfd")]
+#[test]
+fn unix_mode_owner() {
+    use crate::{parse_mode, parse_owner};
+
+    assert_eq!(parse_mode("0660"), Ok(0o660));
+    assert_eq!(parse_mode("777"), Ok(0o777));
+    assert!(parse_mode("1777").is_err());
+    assert!(parse_mode("0o660").is_err());
+    assert!(parse_mode("999").is_err());
+
+    assert_eq!(parse_owner("1000:1001"), Ok((Some(1000), Some(1001))));
+    assert_eq!(parse_owner("1000"), Ok((Some(1000), None)));
+    assert_eq!(parse_owner("1000:"), Ok((Some(1000), None)));
+    assert_eq!(parse_owner(":1001"), Ok((None, Some(1001))));
+    assert_eq!(parse_owner("root:0"), Ok((Some(0), Some(0))));
+    assert!(parse_owner("redgem-no-such-user").is_err());
+    assert!(parse_owner(":redgem-no-such-group").is_err());
+    assert!(parse_owner("a\0b").is_err());
+}
+
+#[cfg(target_os = "linux")]
+#[test]
+fn abstract_socket() {
+    use argh::FromArgs;
+    use std::os::{linux::net::SocketAddrExt, unix::net::UnixStream};
+
+    let name = format!("@redgem-test-{}", std::process::id());
+    let listener = crate::bind_unix(name.as_ref()).unwrap();
+    assert!(!std::path::Path::new(&name).exists());
+    let addr = listener.local_addr().unwrap();
+    assert_eq!(addr.as_abstract_name(), Some(&name.as_bytes()[1..]));
+    UnixStream::connect_addr(&addr).unwrap();
+
+    let opt = crate::Opt::from_args(&["redgem"], &["--unix-tls", &name, "cert.pem"]).unwrap();
+    assert_eq!(crate::socket_files(&opt).count(), 0);
+    let opt =
+        crate::Opt::from_args(&["redgem"], &["--unix-tls", "redgem.sock", "cert.pem"]).unwrap();
+    assert_eq!(crate::socket_files(&opt).count(), 1);
+}
+
+#[cfg(feature = "recvfd")]
+#[test]
+fn repeated_unix() {
+    use crate::Bind;
+    use argh::FromArgs;
+    use std::path::PathBuf;
+
+    let opt = crate::Opt::from_args(
+        &["redgem"],
+        &["--unix", "a.sock", "--unix", "b.sock", "cert.pem"],
+    )
+    .unwrap();
+    assert_eq!(
+        crate::binds(&opt),
+        [
+            Bind::Unix(PathBuf::from("a.sock")),
+            Bind::Unix(PathBuf::from("b.sock")),
+        ]
+    );
+}
+
+#[test]
+fn bind_addr() {
+    use argh::FromArgs;
+
+    let opt = crate::Opt::from_args(&["redgem"], &["cert.pem"]).unwrap();
+    assert_eq!(crate::bind_addr(&opt), Some("[::]:1965".parse().unwrap()));
+    let opt =
+        crate::Opt::from_args(&["redgem"], &["--bind", "127.0.0.1:1965", "cert.pem"]).unwrap();
+    assert_eq!(
+        crate::bind_addr(&opt),
+        Some("127.0.0.1:1965".parse().unwrap())
+    );
+
+    #[cfg(unix)]
+    {
+        // unix sockets replace the default, but not an explicit --bind
+        let opt =
+            crate::Opt::from_args(&["redgem"], &["--unix-tls", "c.sock", "cert.pem"]).unwrap();
+        assert_eq!(crate::bind_addr(&opt), None);
+        let opt = crate::Opt::from_args(
+            &["redgem"],
+            &["--unix-tls", "c.sock", "--bind", "[::1]:1965", "cert.pem"],
+        )
+        .unwrap();
+        assert_eq!(crate::bind_addr(&opt), Some("[::1]:1965".parse().unwrap()));
+    }
+    #[cfg(feature = "recvfd")]
+    {
+        let opt = crate::Opt::from_args(&["redgem"], &["--unix", "a.sock", "cert.pem"]).unwrap();
+        assert_eq!(crate::bind_addr(&opt), None);
+    }
+}
+
+#[cfg(feature = "recvfd")]
+#[test]
+fn bind_order() {
+    use crate::Bind;
+    use argh::FromArgs;
+    use std::path::PathBuf;
+
+    let opt = crate::Opt::from_args(
+        &["redgem"],
+        &[
+            "--unix-tls",
+            "c.sock",
+            "--unix",
+            "a.sock",
+            "--bind",
+            "127.0.0.1:1965",
+            "--unix",
+            "b.sock",
+            "cert.pem",
+        ],
+    )
+    .unwrap();
+    // an upgrade hands listeners over by index, so this must not depend on the order of options
+    assert_eq!(
+        crate::binds(&opt),
+        [
+            Bind::Tcp("127.0.0.1:1965".parse().unwrap()),
+            Bind::Unix(PathBuf::from("a.sock")),
+            Bind::Unix(PathBuf::from("b.sock")),
+            Bind::UnixTls(PathBuf::from("c.sock")),
+        ]
+    );
+}
@@ -0,0 +1,350 @@
+//! tls configuration that rustls does not provide out of the box
+
+use crate::{
+    metrics::HandshakeFailure,
+    server::{auth, known_hosts::KnownHosts},
+};
+use std::{path::Path, sync::Arc};
+use tokio_rustls::rustls::{
+    CertificateError, DigitallySignedStruct, DistinguishedName, Error, RootCertStore,
+    SignatureScheme,
+    client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+    crypto::{CryptoProvider, ring, verify_tls12_signature, verify_tls13_signature},
+    pki_types::{CertificateDer, ServerName, UnixTime, pem::PemObject},
+    server::{
+        VerifierBuilderError, WebPkiClientVerifier,
+        danger::{ClientCertVerified, ClientCertVerifier},
+    },
+};
+
+#[derive(Debug, foxerror::FoxError)]
+pub enum ProviderError {
+    /// unknown cipher suite
+    UnknownCipher(String),
+    /// unknown key exchange group
+    UnknownKxGroup(String),
+}
+
+#[derive(Debug, foxerror::FoxError)]
+pub enum ClientCaError {
+    /// could not read client ca
+    #[err(from)]
+    Pem(tokio_rustls::rustls::pki_types::pem::Error),
+    /// could not parse client ca
+    #[err(from)]
+    Parse(Error),
+    /// could not use client ca
+    #[err(from)]
+    Verifier(VerifierBuilderError),
+}
+
+/// a client certificate verifier for closed capsules, that only accepts certificates signed
+/// by a certificate authority from a pem file.
+///
+/// clients without a certificate are still let through, so that they get a 60 instead of a
+/// failed handshake
+pub fn ca_client_verifier(
+    provider: Arc<CryptoProvider>,
+    path: &Path,
+) -> Result<Arc<dyn ClientCertVerifier>, ClientCaError> {
+    let mut roots = RootCertStore::empty();
+    for ca in CertificateDer::pem_file_iter(path)? {
+        roots.add(ca?)?;
+    }
+    Ok(
+        WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+            .allow_unauthenticated()
+            .build()?,
+    )
+}
+
+/// the default crypto provider, restricted to comma separated lists of cipher suites and key
+/// exchange groups when given, and preferring them in the order given
+pub fn restricted_provider(
+    ciphers: Option<&str>,
+    kx_groups: Option<&str>,
+) -> Result<CryptoProvider, ProviderError> {
+    let mut provider = ring::default_provider();
+    if let Some(ciphers) = ciphers {
+        provider.cipher_suites = pick(
+            ciphers,
+            &provider.cipher_suites,
+            |suite| suite.suite().as_str(),
+            ProviderError::UnknownCipher,
+        )?;
+    }
+    if let Some(groups) = kx_groups {
+        provider.kx_groups = pick(
+            groups,
+            &provider.kx_groups,
+            |group| group.name().as_str(),
+            ProviderError::UnknownKxGroup,
+        )?;
+    }
+    Ok(provider)
+}
+
+/// pick items out of a list by their names, ignoring case
+fn pick<T: Copy>(
+    names: &str,
+    from: &[T],
+    name: impl Fn(&T) -> Option<&'static str>,
+    unknown: impl Fn(String) -> ProviderError,
+) -> Result<Vec<T>, ProviderError> {
+    names
+        .split(',')
+        .map(str::trim)
+        .map(|want| {
+            from.iter()
+                .find(|item| name(item).is_some_and(|n| n.eq_ignore_ascii_case(want)))
+                .copied()
+                .ok_or_else(|| unknown(want.to_string()))
+        })
+        .collect()
+}
+
+/// a client certificate verifier that optionally accepts any certificate.
+///
+/// gemini clients use self-signed certificates as identities, so they are checked against
+/// fingerprints later instead of a certificate authority
+#[derive(Debug)]
+pub struct AnyClientCert(Arc<CryptoProvider>);
+
+impl AnyClientCert {
+    pub const fn new(provider: Arc<CryptoProvider>) -> Self {
+        Self(provider)
+    }
+}
+
+impl ClientCertVerifier for AnyClientCert {
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// a server certificate verifier that accepts any certificate.
+///
+/// most gemini servers use self-signed certificates, so clients are expected to trust them on
+/// first use instead
+#[derive(Debug)]
+pub struct AnyServerCert(Arc<CryptoProvider>);
+
+impl AnyServerCert {
+    pub const fn new(provider: Arc<CryptoProvider>) -> Self {
+        Self(provider)
+    }
+}
+
+impl ServerCertVerifier for AnyServerCert {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// a server certificate verifier that only accepts the certificate a host used the first time
+/// we connected to it
+#[derive(Debug)]
+pub struct TofuServerCert(Arc<CryptoProvider>, Arc<KnownHosts>);
+
+impl TofuServerCert {
+    pub const fn new(provider: Arc<CryptoProvider>, known: Arc<KnownHosts>) -> Self {
+        Self(provider, known)
+    }
+}
+
+impl ServerCertVerifier for TofuServerCert {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        if self
+            .1
+            .check(&server_name.to_str(), &auth::fingerprint(end_entity))
+        {
+            Ok(ServerCertVerified::assertion())
+        } else {
+            Err(Error::InvalidCertificate(
+                CertificateError::ApplicationVerificationFailure,
+            ))
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.0.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.signature_verification_algorithms.supported_schemes()
+    }
+}
+
+/// sort a failed handshake by what went wrong
+pub fn handshake_failure(err: &std::io::Error) -> HandshakeFailure {
+    use tokio_rustls::rustls::PeerIncompatible as Incompatible;
+
+    let Some(err) = err.get_ref().and_then(|e| e.downcast_ref::<Error>()) else {
+        return HandshakeFailure::Closed;
+    };
+    match err {
+        Error::InvalidMessage(_)
+        | Error::InappropriateMessage { .. }
+        | Error::InappropriateHandshakeMessage { .. }
+        | Error::DecryptError
+        | Error::PeerMisbehaved(_) => HandshakeFailure::BadRecord,
+        Error::PeerIncompatible(
+            Incompatible::SupportedVersionsExtensionRequired
+            | Incompatible::Tls12NotOffered
+            | Incompatible::Tls12NotOfferedOrEnabled
+            | Incompatible::ServerDoesNotSupportTls12Or13,
+        ) => HandshakeFailure::UnsupportedVersion,
+        Error::PeerIncompatible(_) => HandshakeFailure::Incompatible,
+        Error::AlertReceived(_) => HandshakeFailure::Rejected,
+        _ => HandshakeFailure::Other,
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use super::{ProviderError, restricted_provider};
+
+    #[test]
+    fn restrict() {
+        let provider = restricted_provider(
+            Some("TLS13_CHACHA20_POLY1305_SHA256, tls13_aes_256_gcm_sha384"),
+            Some("x25519"),
+        )
+        .unwrap();
+        let suites: Vec<_> = provider
+            .cipher_suites
+            .iter()
+            .map(|s| s.suite().as_str().unwrap())
+            .collect();
+        assert_eq!(
+            suites,
+            ["TLS13_CHACHA20_POLY1305_SHA256", "TLS13_AES_256_GCM_SHA384"]
+        );
+        assert_eq!(provider.kx_groups.len(), 1);
+
+        assert!(matches!(
+            restricted_provider(Some("TLS_RSA_WITH_NULL_MD5"), None),
+            Err(ProviderError::UnknownCipher(name)) if name == "TLS_RSA_WITH_NULL_MD5"
+        ));
+        assert!(matches!(
+            restricted_provider(None, Some("ffdhe2048")),
+            Err(ProviderError::UnknownKxGroup(_))
+        ));
+    }
+}
@@ -0,0 +1,219 @@
+//! replacing the running binary without refusing connections
+//!
+//! on SIGUSR2 we start the new binary with the same arguments, handing it our listeners. once it
+//! has loaded everything and taken over the listeners, it tells us through a pipe, and we stop
+//! accepting and exit after the connections we already have are done. connections made in the
+//! meantime wait in the listeners' backlogs until the new process accepts them
+
+use std::{
+    io::{Error, Read, Write},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    path::Path,
+    process::Command,
+};
+use tokio_util::sync::CancellationToken;
+
+/// environment variable holding the fds of the listeners being handed over, separated by commas
+const LISTEN_FD: &str = "REDGEM_LISTEN_FD";
+/// environment variable holding the fd of the metrics listener, if there is one
+const METRICS_FD: &str = "REDGEM_METRICS_FD";
+/// environment variable holding the fds of the listeners for each --site, separated by commas
+const SITE_FDS: &str = "REDGEM_SITE_FDS";
+/// environment variable holding the fd of the locked --pidfile, if there is one
+const PIDFILE_FD: &str = "REDGEM_PIDFILE_FD";
+/// environment variable holding the fd of the pipe to write to once we are ready to take over
+const READY_FD: &str = "REDGEM_READY_FD";
+
+/// whether we were started to replace another process
+pub fn is_upgrading() -> bool {
+    std::env::var_os(LISTEN_FD).is_some()
+}
+
+/// take over the listener at an index of the process we are replacing, if there is one
+pub fn inherited_listener(index: usize) -> Option<OwnedFd> {
+    inherited_at(LISTEN_FD, index)
+}
+
+/// take over the metrics listener of the process we are replacing, if there is one
+pub fn inherited_metrics() -> Option<OwnedFd> {
+    inherited(METRICS_FD)
+}
+
+/// take over the listener for the site at an index, if the process we are replacing had one
+pub fn inherited_site(index: usize) -> Option<OwnedFd> {
+    inherited_at(SITE_FDS, index)
+}
+
+/// take over the locked pidfile of the process we are replacing, if it had one
+#[cfg(feature = "daemon")]
+pub fn inherited_pidfile() -> Option<OwnedFd> {
+    inherited(PIDFILE_FD)
+}
+
+/// take the pipe that the process we are replacing waits on, if it is waiting.
+///
+/// this should be done early, so that it does not leak into a process we start ourselves
+pub fn inherited_ready() -> Option<OwnedFd> {
+    inherited(READY_FD)
+}
+
+/// tell the process we are replacing that we have loaded everything and bound our listeners, so
+/// that it can stop accepting
+pub fn ready(fd: OwnedFd) {
+    if let Err(e) = std::fs::File::from(fd).write_all(b"\n") {
+        eprintln!("could not tell the old process we are ready: {e}");
+    }
+}
+
+fn inherited(var: &str) -> Option<OwnedFd> {
+    claim(std::env::var(var).ok()?.parse().ok()?)
+}
+
+fn inherited_at(var: &str, index: usize) -> Option<OwnedFd> {
+    claim(nth_fd(&std::env::var(var).ok()?, index)?)
+}
+
+/// the fd at an index of a comma separated list
+fn nth_fd(fds: &str, index: usize) -> Option<RawFd> {
+    fds.split(',').nth(index)?.parse().ok()
+}
+
+fn claim(fd: RawFd) -> Option<OwnedFd> {
+    // SAFETY: setting flags on an fd that is not open just fails with EBADF
+    if unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } == -1 {
+        return None;
+    }
+    // SAFETY: the old process handed this fd to us, nothing else here knows about it
+    Some(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// watch for SIGUSR2 in a background thread, cancelling `stop` once a new process has taken over
+/// the listeners
+///
+/// this must be called before any other threads are started, so that they inherit SIGUSR2 being
+/// blocked and it only gets delivered to the watching thread
+pub fn watch(
+    listeners: Vec<RawFd>,
+    metrics: Option<RawFd>,
+    sites: Vec<RawFd>,
+    pidfile: Option<RawFd>,
+    stop: CancellationToken,
+) -> std::io::Result<()> {
+    // SAFETY: an empty sigset_t is a valid place for sigemptyset to initialize
+    let mut set = unsafe { std::mem::zeroed::<libc::sigset_t>() };
+    // SAFETY: set is valid for writes, and pthread_sigmask allows a null old set
+    let ret = unsafe {
+        libc::sigemptyset(&raw mut set);
+        libc::sigaddset(&raw mut set, libc::SIGUSR2);
+        libc::pthread_sigmask(libc::SIG_BLOCK, &raw const set, std::ptr::null_mut())
+    };
+    if ret != 0 {
+        return Err(Error::from_raw_os_error(ret));
+    }
+    // the new process resolves our arguments again, before any --chdir
+    let dir = std::env::current_dir()?;
+
+    std::thread::spawn(move || {
+        loop {
+            let mut sig = 0;
+            // SAFETY: set was initialized above and sig is valid for writes
+            if unsafe { libc::sigwait(&raw const set, &raw mut sig) } != 0 {
+                continue;
+            }
+            eprintln!("upgrading");
+            match upgrade(&dir, &listeners, metrics, &sites, pidfile) {
+                Ok(()) => {
+                    eprintln!("new process started, finishing remaining connections");
+                    stop.cancel();
+                    return;
+                }
+                Err(e) => eprintln!("could not upgrade: {e}"),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// dup gives us a copy without close-on-exec, so only that copy leaks into the new process
+fn handover(fd: RawFd) -> std::io::Result<OwnedFd> {
+    // SAFETY: duplicating an fd we own
+    let fd = unsafe { libc::dup(fd) };
+    if fd == -1 {
+        return Err(Error::last_os_error());
+    }
+    // SAFETY: we just created it
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// start a new copy of ourselves with the listeners, waiting until it is ready to take over
+fn upgrade(
+    dir: &Path,
+    listeners: &[RawFd],
+    metrics: Option<RawFd>,
+    sites: &[RawFd],
+    pidfile: Option<RawFd>,
+) -> std::io::Result<()> {
+    let path = crate::path_self().ok_or_else(|| Error::other("could not find path to myself"))?;
+
+    let listeners = listeners
+        .iter()
+        .map(|&fd| handover(fd))
+        .collect::<Result<Vec<_>, _>>()?;
+    let metrics = metrics.map(handover).transpose()?;
+    let sites = sites
+        .iter()
+        .map(|&fd| handover(fd))
+        .collect::<Result<Vec<_>, _>>()?;
+    // sharing the open file keeps it locked, so that nothing can start in between us
+    let pidfile = pidfile.map(handover).transpose()?;
+    let (mut waiting, writer) = std::io::pipe()?;
+    let ready = handover(writer.as_raw_fd())?;
+    drop(writer);
+    let fds: Vec<_> = listeners
+        .iter()
+        .map(|fd| fd.as_raw_fd().to_string())
+        .collect();
+    let mut command = Command::new(path);
+    command
+        .args(std::env::args_os().skip(1))
+        .current_dir(dir)
+        .env(LISTEN_FD, fds.join(","))
+        .env(READY_FD, ready.as_raw_fd().to_string());
+    if let Some(metrics) = &metrics {
+        command.env(METRICS_FD, metrics.as_raw_fd().to_string());
+    }
+    if !sites.is_empty() {
+        let fds: Vec<_> = sites.iter().map(|fd| fd.as_raw_fd().to_string()).collect();
+        command.env(SITE_FDS, fds.join(","));
+    }
+    if let Some(pidfile) = &pidfile {
+        command.env(PIDFILE_FD, pidfile.as_raw_fd().to_string());
+    }
+    let mut child = command.spawn()?;
+    drop((listeners, metrics, sites, pidfile, ready));
+
+    // with every copy of the writing end but the new process' closed, this ends either with it
+    // being ready or with it exiting, including after it daemonizes
+    let mut buf = [0];
+    if waiting.read(&mut buf)? == 1 {
+        return Ok(());
+    }
+    let status = child.wait()?;
+    Err(Error::other(format!(
+        "new process {status} before it was ready"
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::nth_fd;
+
+    #[test]
+    fn fd_index() {
+        assert_eq!(nth_fd("3", 0), Some(3));
+        assert_eq!(nth_fd("3,5,4", 1), Some(5));
+        assert_eq!(nth_fd("3,5,4", 2), Some(4));
+        assert_eq!(nth_fd("3,5,4", 3), None);
+        assert_eq!(nth_fd("", 0), None);
+    }
+}